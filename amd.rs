@@ -0,0 +1,331 @@
+//! AMD GPU family classification and amdgpu vs radeon selection
+//!
+//! The family comes from the PCI device ID. GCN 1.0 (Southern Islands) and GCN 2
+//! (Sea Islands) cards are bound to `radeon` by default although `amdgpu` supports
+//! them too and adds Vulkan through RADV; setup can switch them with kernel
+//! parameters. Cards older than GCN only work with `radeon` and `xf86-video-ati`.
+
+use crate::knowledge::{Recommendation, Selector};
+use crate::packages::PackageDatabase;
+use crate::HardwareComponent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    /// R100 to R500, before unified shaders
+    Rage,
+    /// R600 to Northern Islands
+    TeraScale,
+    SouthernIslands,
+    SeaIslands,
+    /// GCN 3 and 4: Tonga, Fiji, Carrizo and Polaris
+    VolcanicIslands,
+    Vega,
+    Rdna1,
+    Rdna2,
+    Rdna3,
+    Rdna4,
+}
+
+/// Device ID ranges per family, by chip.
+const FAMILIES: &[(u16, u16, Family)] = &[
+    (0x1304, 0x131d, Family::SeaIslands),      // Kaveri
+    (0x1506, 0x1506, Family::Rdna2),           // Mendocino
+    (0x150e, 0x150e, Family::Rdna3),           // Strix Point
+    (0x1586, 0x1586, Family::Rdna3),           // Strix Halo
+    (0x15bf, 0x15bf, Family::Rdna3),           // Phoenix
+    (0x15c8, 0x15c8, Family::Rdna3),           // Phoenix 2
+    (0x15d8, 0x15dd, Family::Vega),            // Raven, Picasso
+    (0x1636, 0x1638, Family::Vega),            // Renoir, Cezanne
+    (0x163f, 0x163f, Family::Rdna2),           // Van Gogh
+    (0x164c, 0x164c, Family::Vega),            // Lucienne
+    (0x164e, 0x164e, Family::Rdna2),           // Raphael
+    (0x1681, 0x1681, Family::Rdna2),           // Rembrandt
+    (0x4100, 0x5fff, Family::Rage),            // R100 to R500
+    (0x6600, 0x663f, Family::SouthernIslands), // Oland
+    (0x6640, 0x665f, Family::SeaIslands),      // Bonaire
+    (0x6660, 0x667f, Family::SouthernIslands), // Hainan
+    (0x66a0, 0x66af, Family::Vega),            // Vega 20
+    (0x6700, 0x677f, Family::TeraScale),       // Cayman, Barts, Turks, Caicos
+    (0x6780, 0x679f, Family::SouthernIslands), // Tahiti
+    (0x67a0, 0x67bf, Family::SeaIslands),      // Hawaii
+    (0x67c0, 0x67ff, Family::VolcanicIslands), // Polaris 10 and 11
+    (0x6800, 0x683f, Family::SouthernIslands), // Pitcairn, Cape Verde
+    (0x6860, 0x687f, Family::Vega),            // Vega 10
+    (0x6880, 0x68ff, Family::TeraScale),       // Evergreen
+    (0x6900, 0x693f, Family::VolcanicIslands), // Topaz, Tonga
+    (0x694c, 0x694f, Family::VolcanicIslands), // Vega M
+    (0x6980, 0x699f, Family::VolcanicIslands), // Polaris 12
+    (0x69a0, 0x69af, Family::Vega),            // Vega 12
+    (0x7100, 0x72ff, Family::Rage),            // R500
+    (0x7300, 0x730f, Family::VolcanicIslands), // Fiji
+    (0x7310, 0x731f, Family::Rdna1),           // Navi 10
+    (0x7340, 0x734f, Family::Rdna1),           // Navi 14
+    (0x7360, 0x736f, Family::Rdna1),           // Navi 12
+    (0x73a0, 0x73ff, Family::Rdna2),           // Navi 21 to 23
+    (0x7420, 0x743f, Family::Rdna2),           // Navi 24
+    (0x7440, 0x749f, Family::Rdna3),           // Navi 31 to 33
+    (0x7550, 0x755f, Family::Rdna4),           // Navi 48
+    (0x7590, 0x759f, Family::Rdna4),           // Navi 44
+    (0x9400, 0x96ff, Family::TeraScale),       // R600, R700, Sumo
+    (0x9710, 0x971f, Family::TeraScale),       // RS880
+    (0x9800, 0x980f, Family::TeraScale),       // Palm
+    (0x9830, 0x983f, Family::SeaIslands),      // Kabini
+    (0x9850, 0x985f, Family::SeaIslands),      // Mullins
+    (0x9870, 0x987f, Family::VolcanicIslands), // Carrizo
+    (0x98e4, 0x98e4, Family::VolcanicIslands), // Stoney
+    (0x9900, 0x99ff, Family::TeraScale),       // Trinity, Richland
+];
+
+impl Family {
+    pub fn from_device_id(device_id: u16) -> Option<Self> {
+        FAMILIES
+            .iter()
+            .find(|(lo, hi, _)| (*lo..=*hi).contains(&device_id))
+            .map(|&(_, _, family)| family)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::Rage => "R100-R500",
+            Family::TeraScale => "TeraScale",
+            Family::SouthernIslands => "Southern Islands (GCN 1.0)",
+            Family::SeaIslands => "Sea Islands (GCN 2)",
+            Family::VolcanicIslands => "Volcanic Islands (GCN 3/4)",
+            Family::Vega => "Vega",
+            Family::Rdna1 => "RDNA 1",
+            Family::Rdna2 => "RDNA 2",
+            Family::Rdna3 => "RDNA 3",
+            Family::Rdna4 => "RDNA 4",
+        }
+    }
+
+    /// Whether `amdgpu` supports the family at all.
+    pub fn is_gcn(self) -> bool {
+        !matches!(self, Family::Rage | Family::TeraScale)
+    }
+
+    /// Kernel parameters that hand the family from `radeon` to `amdgpu`, empty
+    /// for families `amdgpu` drives by default.
+    pub fn amdgpu_params(self) -> Vec<String> {
+        let support = match self {
+            Family::SouthernIslands => "si_support",
+            Family::SeaIslands => "cik_support",
+            _ => return Vec::new(),
+        };
+        vec![
+            format!("radeon.{}=0", support),
+            format!("amdgpu.{}=1", support),
+        ]
+    }
+}
+
+/// Packages and early KMS module for one GPU and why they were chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub family: Family,
+    pub packages: Vec<String>,
+    pub early_module: &'static str,
+    pub reasons: Vec<String>,
+}
+
+/// Chooses the driver stack for the AMD GPU `device_id`, `None` for unknown IDs.
+///
+/// Southern and Sea Islands cards get `amdgpu` only when `amdgpu` is set, i.e.
+/// setup switches them or they already run on it. `libva-mesa-driver` and the
+/// `lib32` packages are only added when the sync databases provide them.
+pub fn select(
+    device_id: u16,
+    amdgpu: bool,
+    packages: Option<&PackageDatabase>,
+) -> Option<Selection> {
+    let family = Family::from_device_id(device_id)?;
+    let available = |name: &str| packages.is_some_and(|db| db.is_available(name));
+
+    let mut reasons = Vec::new();
+    let (selected, early_module) = if !family.is_gcn() {
+        reasons.push(format!(
+            "AMD {} GPU predates GCN, only radeon and xf86-video-ati support it and there is no Vulkan",
+            family.name()
+        ));
+        (vec!["mesa", "xf86-video-ati"], "radeon")
+    } else if !family.amdgpu_params().is_empty() && !amdgpu {
+        reasons.push(format!(
+            "AMD {} GPU runs on radeon without Vulkan, setup --amdgpu switches it to amdgpu",
+            family.name()
+        ));
+        (vec!["mesa", "xf86-video-ati"], "radeon")
+    } else {
+        reasons.push(format!(
+            "AMD {} GPU is supported by amdgpu and gets Vulkan through RADV",
+            family.name()
+        ));
+        let mut selected = vec!["mesa", "vulkan-radeon"];
+        // Newer mesa packages include the VA-API driver
+        if packages.is_none() || available("libva-mesa-driver") {
+            selected.push("libva-mesa-driver");
+        }
+        (selected, "amdgpu")
+    };
+
+    let lib32: Vec<String> = selected
+        .iter()
+        .filter(|name| **name != "xf86-video-ati")
+        .map(|name| format!("lib32-{}", name))
+        .collect();
+    let mut selected: Vec<String> = selected.into_iter().map(str::to_string).collect();
+    if lib32.iter().all(|name| available(name)) {
+        reasons.push(format!(
+            "{} add the 32-bit drivers for Steam and Wine",
+            lib32.join(", ")
+        ));
+        selected.extend(lib32);
+    } else {
+        reasons.push("32-bit drivers are skipped because multilib is not enabled".to_string());
+    }
+
+    Some(Selection {
+        family,
+        packages: selected,
+        early_module,
+        reasons,
+    })
+}
+
+/// Replaces the packages of recommendations from rules with `"select": "amd"` by
+/// the driver stack chosen for their GPU.
+///
+/// With `switch` set, Southern and Sea Islands cards get the `amdgpu` stack; cards
+/// already bound to `amdgpu` always do.
+pub fn apply(
+    recommendations: &mut [Recommendation],
+    components: &[HardwareComponent],
+    switch: bool,
+    packages: Option<&PackageDatabase>,
+) {
+    for recommendation in recommendations
+        .iter_mut()
+        .filter(|r| r.select == Some(Selector::Amd))
+    {
+        let Some(component) = recommendation
+            .components
+            .iter()
+            .map(|&i| &components[i])
+            .find(|c| c.pci.is_some())
+        else {
+            continue;
+        };
+        let device_id = component.pci.as_ref().map_or(0, |device| device.device_id);
+        let amdgpu = switch || component.driver.as_deref() == Some("amdgpu");
+        let Some(selection) = select(device_id, amdgpu, packages) else {
+            continue;
+        };
+        recommendation.packages = selection.packages;
+        recommendation.early_modules = vec![selection.early_module.to_string()];
+        recommendation.reason = selection.reasons.join("; ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_device_ids() {
+        let cases = [
+            (0x5964, Family::Rage),            // Radeon 9200
+            (0x9440, Family::TeraScale),       // Radeon HD 4870
+            (0x6739, Family::TeraScale),       // Radeon HD 6850
+            (0x6818, Family::SouthernIslands), // Radeon HD 7870
+            (0x6798, Family::SouthernIslands), // Radeon HD 7970
+            (0x67b1, Family::SeaIslands),      // Radeon R9 290
+            (0x67df, Family::VolcanicIslands), // Radeon RX 480
+            (0x687f, Family::Vega),            // Radeon RX Vega 64
+            (0x731f, Family::Rdna1),           // Radeon RX 5700 XT
+            (0x73bf, Family::Rdna2),           // Radeon RX 6800
+            (0x744c, Family::Rdna3),           // Radeon RX 7900 XTX
+            (0x7550, Family::Rdna4),           // Radeon RX 9070 XT
+        ];
+        for (device_id, family) in cases {
+            assert_eq!(
+                Family::from_device_id(device_id),
+                Some(family),
+                "{:04x}",
+                device_id
+            );
+        }
+        assert_eq!(Family::from_device_id(0x0001), None);
+    }
+
+    #[test]
+    fn switch_parameters_per_family() {
+        assert_eq!(
+            Family::SouthernIslands.amdgpu_params(),
+            vec!["radeon.si_support=0", "amdgpu.si_support=1"]
+        );
+        assert_eq!(
+            Family::SeaIslands.amdgpu_params(),
+            vec!["radeon.cik_support=0", "amdgpu.cik_support=1"]
+        );
+        assert!(Family::Rdna2.amdgpu_params().is_empty());
+    }
+
+    #[test]
+    fn southern_islands_needs_the_switch_for_vulkan() {
+        let multilib =
+            PackageDatabase::parse("", "mesa\nvulkan-radeon\nlib32-mesa\nlib32-vulkan-radeon\n");
+
+        let radeon = select(0x6818, false, Some(&multilib)).unwrap();
+        assert_eq!(
+            radeon.packages,
+            vec!["mesa", "xf86-video-ati", "lib32-mesa"]
+        );
+        assert_eq!(radeon.early_module, "radeon");
+
+        let amdgpu = select(0x6818, true, Some(&multilib)).unwrap();
+        assert_eq!(
+            amdgpu.packages,
+            vec!["mesa", "vulkan-radeon", "lib32-mesa", "lib32-vulkan-radeon"]
+        );
+        assert_eq!(amdgpu.early_module, "amdgpu");
+    }
+
+    #[test]
+    fn pre_gcn_gets_radeon_only() {
+        let terascale = select(0x9440, true, None).unwrap();
+        assert_eq!(terascale.packages, vec!["mesa", "xf86-video-ati"]);
+        assert_eq!(
+            terascale.reasons,
+            vec![
+                "AMD TeraScale GPU predates GCN, only radeon and xf86-video-ati support it and there is no Vulkan",
+                "32-bit drivers are skipped because multilib is not enabled",
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_selector_recommendations() {
+        let gpu = HardwareComponent {
+            driver: Some("amdgpu".to_string()),
+            kernel_modules: vec!["amdgpu".to_string()],
+            pci: Some(crate::pci::PciDevice {
+                vendor_id: 0x1002,
+                device_id: 0x73bf,
+                class: 0x03,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut kb = crate::knowledge::KnowledgeBase::default();
+        kb.add_rules(include_str!("data/rules.json"), "bundled rules")
+            .unwrap();
+        let mut recommendations = kb.evaluate(std::slice::from_ref(&gpu));
+        let db = PackageDatabase::parse("", "mesa\nvulkan-radeon\nlibva-mesa-driver\n");
+
+        apply(&mut recommendations, &[gpu], false, Some(&db));
+        assert_eq!(
+            recommendations[0].packages,
+            vec!["mesa", "vulkan-radeon", "libva-mesa-driver"]
+        );
+        assert_eq!(recommendations[0].early_modules, vec!["amdgpu"]);
+    }
+}
@@ -0,0 +1,446 @@
+//! Building drivers that only the AUR packages
+//!
+//! AUR packages are never built as root: setup runs paru, yay or pikaur as the
+//! user who started it, with pacman escalated through the tool setup itself was
+//! started with. Without a helper it clones the package's git repository and runs
+//! makepkg as that user, installs the build dependencies from the repositories
+//! itself, then installs the built packages with pacman. Setup only plans AUR
+//! packages with `--allow-aur`.
+
+use crate::command::CommandLine;
+use crate::privilege::Escalation;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+pub const AUR_URL: &str = "https://aur.archlinux.org";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Helper {
+    Paru,
+    Yay,
+    Pikaur,
+}
+
+/// Helpers tried in order.
+const HELPERS: [Helper; 3] = [Helper::Paru, Helper::Yay, Helper::Pikaur];
+
+impl Helper {
+    pub fn program(self) -> &'static str {
+        match self {
+            Helper::Paru => "paru",
+            Helper::Yay => "yay",
+            Helper::Pikaur => "pikaur",
+        }
+    }
+}
+
+/// First AUR helper installed on the system at `root`.
+pub fn detect_helper(root: &Path) -> Option<Helper> {
+    HELPERS
+        .into_iter()
+        .find(|helper| root.join("usr/bin").join(helper.program()).exists())
+}
+
+/// An unprivileged user from `/etc/passwd`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub home: String,
+}
+
+/// The user who started setup.
+///
+/// sudo, doas and pkexec keep the login UID of the session. Outside a login
+/// session, as under run0, the variables the tools set name the user:
+/// `SUDO_UID` or `SUDO_USER` (sudo and run0), `DOAS_USER` and `PKEXEC_UID`.
+/// `None` when only root started setup.
+pub async fn invoking_user(root: &Path) -> Option<User> {
+    let passwd = fs::read_to_string(root.join("etc/passwd")).await.ok()?;
+    let loginuid = fs::read_to_string(root.join("proc/self/loginuid"))
+        .await
+        .unwrap_or_default();
+    let environ = fs::read_to_string(root.join("proc/self/environ"))
+        .await
+        .unwrap_or_default();
+    let var = |name: &str| {
+        environ
+            .split('\0')
+            .find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+    };
+
+    let users: Vec<(User, &str)> = passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            match fields[..] {
+                [name, _, uid, _, _, home, ..] => Some((
+                    User {
+                        name: name.to_string(),
+                        home: home.to_string(),
+                    },
+                    uid,
+                )),
+                _ => None,
+            }
+        })
+        .collect();
+    let by_uid = |uid: &str| users.iter().find(|(_, id)| *id == uid.trim());
+    let by_name = |name: &str| users.iter().find(|(user, _)| user.name == name);
+
+    // 4294967295 is the unset login UID
+    let candidates = [
+        by_uid(&loginuid).filter(|_| loginuid.trim() != "4294967295"),
+        var("SUDO_UID").and_then(by_uid),
+        var("SUDO_USER").and_then(by_name),
+        var("DOAS_USER").and_then(by_name),
+        var("PKEXEC_UID").and_then(by_uid),
+    ];
+    for (user, uid) in candidates.into_iter().flatten() {
+        if *uid != "0" {
+            return Some(user.clone());
+        }
+    }
+    None
+}
+
+/// An AUR package in the plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AurTarget {
+    pub name: String,
+    /// AUR repository the package is built from, differs for split packages
+    pub pkgbase: String,
+    pub reason: String,
+}
+
+impl AurTarget {
+    pub fn pkgbuild_url(&self) -> String {
+        format!("{}/cgit/aur.git/tree/PKGBUILD?h={}", AUR_URL, self.pkgbase)
+    }
+}
+
+/// Who builds the plan's AUR packages and with what.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Builder {
+    pub user: User,
+    /// `None` builds with makepkg directly
+    #[serde(default)]
+    pub helper: Option<Helper>,
+    /// Tool the helper runs pacman through
+    #[serde(default)]
+    pub escalation: Option<Escalation>,
+}
+
+impl Builder {
+    /// Runs `argv` as the unprivileged user, from and with the user's home so
+    /// git and makepkg do not look at root's.
+    fn as_user<'a>(&'a self, argv: impl IntoIterator<Item = &'a str>) -> CommandLine {
+        let home = self.user.home.as_str();
+        let mut line = vec![
+            "runuser".to_string(),
+            "-u".to_string(),
+            self.user.name.clone(),
+            "--".to_string(),
+            "env".to_string(),
+            "-C".to_string(),
+            home.to_string(),
+            format!("HOME={}", home),
+            format!("XDG_CACHE_HOME={}/.cache", home),
+        ];
+        line.extend(argv.into_iter().map(str::to_string));
+        CommandLine::new(line)
+    }
+
+    /// Program that builds the packages.
+    pub fn tool(&self) -> &'static str {
+        self.helper.map_or("makepkg", Helper::program)
+    }
+
+    /// Builds and installs `names` with the helper. pikaur reads its escalation
+    /// tool from `pikaur.conf`, paru and yay are passed it.
+    pub fn helper_command(&self, names: &[&str]) -> Option<CommandLine> {
+        let helper = self.helper?;
+        let sudo = self.escalation?.program()?;
+        let mut argv = vec![helper.program(), "-S", "--needed", "--noconfirm"];
+        if helper != Helper::Pikaur {
+            argv.extend(["--sudo", sudo]);
+        }
+        argv.extend(names);
+        Some(self.as_user(argv))
+    }
+
+    /// Directory `pkgbase` is cloned to and built in, owned by the user so no
+    /// one else can swap the PKGBUILD before root installs the result.
+    pub fn build_dir(&self, pkgbase: &str) -> String {
+        format!("{}/.cache/ardenthat/aur/{}", self.user.home, pkgbase)
+    }
+
+    /// Clones `pkgbase` into a fresh build directory.
+    pub fn clone_commands(&self, pkgbase: &str) -> Vec<CommandLine> {
+        let dir = self.build_dir(pkgbase);
+        let url = format!("{}/{}.git", AUR_URL, pkgbase);
+        vec![
+            self.as_user(["rm", "-rf", dir.as_str()]),
+            self.as_user(["git", "clone", "--depth", "1", url.as_str(), dir.as_str()]),
+        ]
+    }
+
+    /// Prints the `.SRCINFO` of the cloned `pkgbase`, for [`build_dependencies`].
+    pub fn srcinfo_command(&self, pkgbase: &str) -> CommandLine {
+        let dir = self.build_dir(pkgbase);
+        self.as_user(["makepkg", "-D", dir.as_str(), "--printsrcinfo"])
+            .capture()
+    }
+
+    /// Builds the cloned `pkgbase`, whose dependencies root installed beforehand.
+    pub fn build_command(&self, pkgbase: &str) -> CommandLine {
+        let dir = self.build_dir(pkgbase);
+        self.as_user(["makepkg", "-D", dir.as_str(), "--noconfirm"])
+    }
+
+    /// Lists the package files makepkg built for `pkgbase`.
+    pub fn packagelist_command(&self, pkgbase: &str) -> CommandLine {
+        let dir = self.build_dir(pkgbase);
+        self.as_user(["makepkg", "-D", dir.as_str(), "--packagelist"])
+            .capture()
+    }
+}
+
+/// Run, build and check dependencies from `makepkg --printsrcinfo` output, for
+/// the architecture setup runs on. Packages built from the same `.SRCINFO` are
+/// left out, so split packages may depend on each other.
+pub fn build_dependencies(srcinfo: &str) -> Vec<String> {
+    let fields: Vec<(&str, &str)> = srcinfo
+        .lines()
+        .filter_map(|line| line.trim().split_once(" = "))
+        .collect();
+    let built: Vec<&str> = fields
+        .iter()
+        .filter(|&&(key, _)| key == "pkgname")
+        .map(|&(_, value)| value)
+        .collect();
+
+    let mut dependencies: Vec<String> = Vec::new();
+    for (key, value) in fields {
+        let kind = key
+            .strip_suffix(std::env::consts::ARCH)
+            .and_then(|k| k.strip_suffix('_'))
+            .unwrap_or(key);
+        if !matches!(kind, "depends" | "makedepends" | "checkdepends") {
+            continue;
+        }
+        if !built.contains(&dependency_name(value)) && !dependencies.iter().any(|d| d == value) {
+            dependencies.push(value.to_string());
+        }
+    }
+    dependencies
+}
+
+/// Package name of a dependency such as `libglvnd>=1.7`.
+pub fn dependency_name(dependency: &str) -> &str {
+    dependency
+        .split(['<', '>', '='])
+        .next()
+        .unwrap_or(dependency)
+}
+
+/// Package files of `makepkg --packagelist` output that contain one of `names`.
+///
+/// File names are `<name>-<pkgver>-<pkgrel>-<arch>.pkg.tar.<ext>`.
+pub fn built_packages(packagelist: &str, names: &[&str]) -> Vec<String> {
+    packagelist
+        .lines()
+        .filter(|path| {
+            let file = path.rsplit('/').next().unwrap_or(path);
+            let mut fields: Vec<&str> = file.split('-').collect();
+            fields.truncate(fields.len().saturating_sub(3));
+            names.contains(&fields.join("-").as_str())
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn alex() -> Option<User> {
+        Some(User {
+            name: "alex".to_string(),
+            home: "/home/alex".to_string(),
+        })
+    }
+
+    /// A root shell outside any login session, as run0 and `machinectl shell` start.
+    fn passwd_root(name: &str) -> TempTree {
+        let root = TempTree::new(name);
+        root.write(
+            "etc/passwd",
+            "root:x:0:0::/root:/bin/bash\nalex:x:1000:1000::/home/alex:/bin/zsh\n",
+        );
+        root.write("proc/self/loginuid", "4294967295");
+        root
+    }
+
+    #[tokio::test]
+    async fn finds_the_login_user() {
+        let root = passwd_root("aur-user");
+        assert_eq!(invoking_user(root.path()).await, None);
+        root.write("proc/self/loginuid", "1000");
+        assert_eq!(invoking_user(root.path()).await, alex());
+        root.write("proc/self/loginuid", "0");
+        assert_eq!(invoking_user(root.path()).await, None);
+    }
+
+    #[tokio::test]
+    async fn finds_the_user_under_sudo_and_run0() {
+        let root = passwd_root("aur-user-sudo");
+        root.write("proc/self/environ", "HOME=/root\0SUDO_USER=alex\0");
+        assert_eq!(invoking_user(root.path()).await, alex());
+        root.write(
+            "proc/self/environ",
+            "HOME=/root\0SUDO_UID=1000\0SUDO_GID=1000\0",
+        );
+        assert_eq!(invoking_user(root.path()).await, alex());
+    }
+
+    #[tokio::test]
+    async fn finds_the_user_under_doas() {
+        let root = passwd_root("aur-user-doas");
+        root.write("proc/self/environ", "DOAS_USER=alex\0HOME=/root\0");
+        assert_eq!(invoking_user(root.path()).await, alex());
+    }
+
+    #[tokio::test]
+    async fn finds_the_user_under_pkexec() {
+        let root = passwd_root("aur-user-pkexec");
+        root.write("proc/self/environ", "PKEXEC_UID=1000\0");
+        assert_eq!(invoking_user(root.path()).await, alex());
+        root.write("proc/self/environ", "PKEXEC_UID=0\0");
+        assert_eq!(invoking_user(root.path()).await, None);
+    }
+
+    #[test]
+    fn builds_as_the_user() {
+        let builder = Builder {
+            user: User {
+                name: "alex".to_string(),
+                home: "/home/alex".to_string(),
+            },
+            helper: None,
+            escalation: None,
+        };
+        let commands: Vec<String> = builder
+            .clone_commands("nvidia-470xx-utils")
+            .iter()
+            .chain([&builder.build_command("nvidia-470xx-utils")])
+            .map(ToString::to_string)
+            .collect();
+        let user = "runuser -u alex -- env -C /home/alex HOME=/home/alex \
+                    XDG_CACHE_HOME=/home/alex/.cache";
+        assert_eq!(
+            commands,
+            vec![
+                format!("{user} rm -rf /home/alex/.cache/ardenthat/aur/nvidia-470xx-utils"),
+                format!(
+                    "{user} git clone --depth 1 https://aur.archlinux.org/nvidia-470xx-utils.git \
+                     /home/alex/.cache/ardenthat/aur/nvidia-470xx-utils"
+                ),
+                format!(
+                    "{user} makepkg -D /home/alex/.cache/ardenthat/aur/nvidia-470xx-utils \
+                     --noconfirm"
+                ),
+            ]
+        );
+
+        assert_eq!(builder.helper_command(&["rtl8821au-dkms-git"]), None);
+
+        let target = AurTarget {
+            name: "nvidia-470xx-dkms".to_string(),
+            pkgbase: "nvidia-470xx-utils".to_string(),
+            reason: "GPU driver".to_string(),
+        };
+        assert_eq!(
+            target.pkgbuild_url(),
+            "https://aur.archlinux.org/cgit/aur.git/tree/PKGBUILD?h=nvidia-470xx-utils"
+        );
+    }
+
+    #[test]
+    fn helpers_escalate_through_the_setup_tool() {
+        let root = TempTree::new("aur-helper");
+        assert_eq!(detect_helper(root.path()), None);
+        root.write("usr/bin/yay", "");
+        root.write("usr/bin/pikaur", "");
+        assert_eq!(detect_helper(root.path()), Some(Helper::Yay));
+
+        let mut builder = Builder {
+            user: User {
+                name: "alex".to_string(),
+                home: "/home/alex".to_string(),
+            },
+            helper: Some(Helper::Yay),
+            escalation: Some(Escalation::Doas),
+        };
+        assert_eq!(builder.tool(), "yay");
+        assert_eq!(
+            builder
+                .helper_command(&["rtl8821au-dkms-git"])
+                .unwrap()
+                .to_string(),
+            "runuser -u alex -- env -C /home/alex HOME=/home/alex \
+             XDG_CACHE_HOME=/home/alex/.cache yay -S --needed --noconfirm --sudo doas \
+             rtl8821au-dkms-git"
+        );
+        builder.helper = Some(Helper::Pikaur);
+        assert!(builder
+            .helper_command(&["rtl8821au-dkms-git"])
+            .unwrap()
+            .to_string()
+            .ends_with(" pikaur -S --needed --noconfirm rtl8821au-dkms-git"));
+        builder.escalation = None;
+        assert_eq!(builder.helper_command(&["rtl8821au-dkms-git"]), None);
+    }
+
+    #[test]
+    fn reads_build_dependencies_from_srcinfo() {
+        let srcinfo = format!(
+            "pkgbase = nvidia-470xx-utils
+\tpkgver = 470.256.02
+\tmakedepends = git
+\tmakedepends_aarch64 = arm-only
+\tdepends_{} = libglvnd>=1.7
+\tdepends = dkms
+
+pkgname = nvidia-470xx-utils
+\tdepends = xorg-server
+
+pkgname = nvidia-470xx-dkms
+\tdepends = dkms
+\tdepends = nvidia-470xx-utils=470.256.02
+",
+            std::env::consts::ARCH
+        );
+        assert_eq!(
+            build_dependencies(&srcinfo),
+            vec!["git", "libglvnd>=1.7", "dkms", "xorg-server"]
+        );
+        assert_eq!(dependency_name("libglvnd>=1.7"), "libglvnd");
+    }
+
+    #[test]
+    fn picks_requested_split_packages() {
+        let list = "/tmp/b/nvidia-470xx-utils-470.256.02-8-x86_64.pkg.tar.zst\n\
+                    /tmp/b/opencl-nvidia-470xx-470.256.02-8-x86_64.pkg.tar.zst\n\
+                    /tmp/b/nvidia-470xx-dkms-470.256.02-8-x86_64.pkg.tar.zst\n";
+        assert_eq!(
+            built_packages(list, &["nvidia-470xx-dkms", "nvidia-470xx-utils"]),
+            vec![
+                "/tmp/b/nvidia-470xx-utils-470.256.02-8-x86_64.pkg.tar.zst",
+                "/tmp/b/nvidia-470xx-dkms-470.256.02-8-x86_64.pkg.tar.zst",
+            ]
+        );
+    }
+}
@@ -0,0 +1,434 @@
+//! Bootloader detection and kernel command line editing
+//!
+//! Parameters are added to the configuration each bootloader reads the command
+//! line from: `/etc/default/grub` (then grub-mkconfig), systemd-boot entries,
+//! `limine.conf`, `refind_linux.conf` or `/etc/kernel/cmdline` for unified kernel
+//! images. A parameter that is already set is left alone, and one set to a
+//! different value is reported instead of overridden.
+
+use crate::command::CommandLine;
+use std::path::Path;
+use tokio::fs;
+
+/// Directories that may hold systemd-boot entries, by ESP mount point.
+pub const LOADER_ENTRY_DIRS: [&str; 3] = [
+    "/boot/loader/entries",
+    "/efi/loader/entries",
+    "/boot/efi/loader/entries",
+];
+pub const GRUB_CFG: &str = "/boot/grub/grub.cfg";
+pub const GRUB_DEFAULTS: &str = "/etc/default/grub";
+/// Command line baked into unified kernel images by mkinitcpio and kernel-install
+pub const UKI_CMDLINE: &str = "/etc/kernel/cmdline";
+pub const REFIND_LINUX_CONF: &str = "/boot/refind_linux.conf";
+const LIMINE_CONFS: [&str; 6] = [
+    "/boot/limine.conf",
+    "/boot/limine/limine.conf",
+    "/boot/EFI/limine/limine.conf",
+    "/efi/limine.conf",
+    "/efi/limine/limine.conf",
+    "/efi/EFI/limine/limine.conf",
+];
+/// Set by systemd-boot when it started the running system
+const LOADER_INFO: &str =
+    "/sys/firmware/efi/efivars/LoaderInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootloader {
+    Grub,
+    SystemdBoot,
+    Limine,
+    Refind,
+    Uki,
+}
+
+/// The bootloader in use and the files holding its kernel command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Detected {
+    pub bootloader: Bootloader,
+    pub paths: Vec<String>,
+}
+
+impl Bootloader {
+    pub fn name(self) -> &'static str {
+        match self {
+            Bootloader::Grub => "GRUB",
+            Bootloader::SystemdBoot => "systemd-boot",
+            Bootloader::Limine => "Limine",
+            Bootloader::Refind => "rEFInd",
+            Bootloader::Uki => "unified kernel images",
+        }
+    }
+
+    /// Command that turns the edited configuration into what the bootloader reads.
+    ///
+    /// Unified kernel images are rebuilt with the initramfs instead.
+    pub fn regenerate_command(self) -> Option<CommandLine> {
+        match self {
+            Bootloader::Grub => Some(CommandLine::new(["grub-mkconfig", "-o", GRUB_CFG])),
+            _ => None,
+        }
+    }
+
+    /// Adds `params` to a configuration file of this bootloader, returning
+    /// `Ok(None)` when all are already set.
+    ///
+    /// Fails with the existing parameters that set one of `params` to another value.
+    pub fn add_params(
+        self,
+        config: &str,
+        params: &[String],
+    ) -> Result<Option<String>, Vec<String>> {
+        match self {
+            Bootloader::Grub => edit_grub_defaults(config, params),
+            Bootloader::SystemdBoot => edit_loader_entry(config, params),
+            Bootloader::Limine => edit_lines(config, params, limine_cmdline),
+            Bootloader::Refind => edit_lines(config, params, refind_options),
+            Bootloader::Uki => edit_lines(config, params, |line| {
+                let start = line.len() - line.trim_start().len();
+                (!line.trim().is_empty() && !line.trim_start().starts_with('#'))
+                    .then(|| (start, line.trim_end().len()))
+            })
+            .map(|edited| match (edited, config.trim().is_empty()) {
+                (None, true) => Some(format!("{}\n", params.join(" "))),
+                (edited, _) => edited,
+            }),
+        }
+    }
+}
+
+/// Finds the bootloader of the system at `root`.
+///
+/// An `/etc/kernel/cmdline` means unified kernel images carry the command line
+/// whatever loads them. Otherwise systemd-boot's `LoaderInfo` variable wins, then
+/// Limine, rEFInd and GRUB configurations, then plain systemd-boot entries.
+pub async fn detect(root: &Path) -> Option<Detected> {
+    let exists = |path: &str| root.join(path.trim_start_matches('/')).exists();
+    let detected = |bootloader, paths: Vec<String>| Detected { bootloader, paths };
+
+    if exists(UKI_CMDLINE) {
+        return Some(detected(Bootloader::Uki, vec![UKI_CMDLINE.to_string()]));
+    }
+    let entries = loader_entries(root).await;
+    if exists(LOADER_INFO) && !entries.is_empty() {
+        return Some(detected(Bootloader::SystemdBoot, entries));
+    }
+    if let Some(conf) = LIMINE_CONFS.iter().find(|path| exists(path)) {
+        return Some(detected(Bootloader::Limine, vec![conf.to_string()]));
+    }
+    if exists(REFIND_LINUX_CONF) {
+        return Some(detected(
+            Bootloader::Refind,
+            vec![REFIND_LINUX_CONF.to_string()],
+        ));
+    }
+    if exists(GRUB_DEFAULTS) {
+        return Some(detected(Bootloader::Grub, vec![GRUB_DEFAULTS.to_string()]));
+    }
+    (!entries.is_empty()).then(|| detected(Bootloader::SystemdBoot, entries))
+}
+
+/// Paths of every systemd-boot entry, sorted.
+pub async fn loader_entries(root: &Path) -> Vec<String> {
+    let mut paths = Vec::new();
+    for dir in LOADER_ENTRY_DIRS {
+        let Ok(mut entries) = fs::read_dir(root.join(dir.trim_start_matches('/'))).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".conf") {
+                paths.push(format!("{}/{}", dir, name));
+            }
+        }
+    }
+    paths.sort();
+    paths
+}
+
+/// Name of a parameter for comparison; the kernel treats `-` and `_` alike.
+fn param_key(param: &str) -> String {
+    param.split('=').next().unwrap_or(param).replace('-', "_")
+}
+
+fn param_value(param: &str) -> Option<&str> {
+    param.split_once('=').map(|(_, value)| value)
+}
+
+/// `cmdline` with the missing `params` appended, `None` if nothing is missing.
+fn add_to_cmdline(cmdline: &str, params: &[String]) -> Result<Option<String>, Vec<String>> {
+    let existing: Vec<&str> = cmdline.split_whitespace().collect();
+    let mut clashes = Vec::new();
+    let mut missing = Vec::new();
+    for param in params {
+        let key = param_key(param);
+        match existing.iter().rev().find(|p| param_key(p) == key) {
+            Some(set) if param_value(set) == param_value(param) => {}
+            Some(set) => clashes.push(set.to_string()),
+            None if !missing.contains(&param.as_str()) => missing.push(param.as_str()),
+            None => {}
+        }
+    }
+    if !clashes.is_empty() {
+        return Err(clashes);
+    }
+    if missing.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        existing
+            .into_iter()
+            .chain(missing)
+            .collect::<Vec<_>>()
+            .join(" "),
+    ))
+}
+
+/// Edits every line for which `locate` returns the byte range of its command line.
+fn edit_lines(
+    config: &str,
+    params: &[String],
+    locate: impl Fn(&str) -> Option<(usize, usize)>,
+) -> Result<Option<String>, Vec<String>> {
+    let mut changed = false;
+    let mut lines = Vec::new();
+    for line in config.lines() {
+        let edited = match locate(line) {
+            Some((start, end)) => add_to_cmdline(&line[start..end], params)?
+                .map(|cmdline| format!("{}{}{}", &line[..start], cmdline, &line[end..])),
+            None => None,
+        };
+        changed |= edited.is_some();
+        lines.push(edited.unwrap_or_else(|| line.to_string()));
+    }
+    Ok(changed.then(|| lines.join("\n") + "\n"))
+}
+
+/// `cmdline:` (or the older `kernel_cmdline:`) of a limine.conf entry.
+fn limine_cmdline(line: &str) -> Option<(usize, usize)> {
+    let trimmed = line.trim_start();
+    let (key, value) = trimmed.split_once(':')?;
+    if !matches!(
+        key.trim().to_ascii_lowercase().as_str(),
+        "cmdline" | "kernel_cmdline"
+    ) {
+        return None;
+    }
+    let start = line.len() - value.len();
+    let start = start + (value.len() - value.trim_start().len());
+    Some((start, line.trim_end().len().max(start)))
+}
+
+/// Second quoted field of a refind_linux.conf line: `"title" "options"`.
+fn refind_options(line: &str) -> Option<(usize, usize)> {
+    if line.trim_start().starts_with('#') {
+        return None;
+    }
+    let quotes: Vec<usize> = line.match_indices('"').map(|(i, _)| i).collect();
+    match quotes[..] {
+        [_, _, open, close, ..] => Some((open + 1, close)),
+        _ => None,
+    }
+}
+
+/// Adds to `GRUB_CMDLINE_LINUX_DEFAULT`, honoring what `GRUB_CMDLINE_LINUX` sets.
+fn edit_grub_defaults(config: &str, params: &[String]) -> Result<Option<String>, Vec<String>> {
+    let value = |line: &str, key: &str| {
+        let rest = line.trim_start().strip_prefix(key)?.strip_prefix('=')?;
+        Some(
+            rest.trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string(),
+        )
+    };
+    let always: String = config
+        .lines()
+        .filter_map(|line| value(line, "GRUB_CMDLINE_LINUX"))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut found = false;
+    let mut changed = false;
+    let mut lines = Vec::new();
+    for line in config.lines() {
+        if let Some(default) = value(line, "GRUB_CMDLINE_LINUX_DEFAULT") {
+            found = true;
+            let combined = format!("{} {}", always, default);
+            if add_to_cmdline(&combined, params)?.is_some() {
+                let missing = missing_params(&combined, params);
+                let cmdline = add_to_cmdline(&default, &missing)?.unwrap_or(default);
+                lines.push(format!("GRUB_CMDLINE_LINUX_DEFAULT=\"{}\"", cmdline));
+                changed = true;
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+    if !found {
+        add_to_cmdline(&always, params)?;
+        let missing = missing_params(&always, params);
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        lines.push(format!(
+            "GRUB_CMDLINE_LINUX_DEFAULT=\"{}\"",
+            missing.join(" ")
+        ));
+        changed = true;
+    }
+    Ok(changed.then(|| lines.join("\n") + "\n"))
+}
+
+fn missing_params(cmdline: &str, params: &[String]) -> Vec<String> {
+    params
+        .iter()
+        .filter(|param| {
+            !cmdline
+                .split_whitespace()
+                .any(|p| param_key(p) == param_key(param))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Adds to the last `options` line of a systemd-boot entry, or adds one.
+fn edit_loader_entry(entry: &str, params: &[String]) -> Result<Option<String>, Vec<String>> {
+    fn options(line: &str) -> Option<&str> {
+        let rest = line.trim_start().strip_prefix("options")?;
+        rest.starts_with(char::is_whitespace).then_some(rest.trim())
+    }
+    let all: Vec<&str> = entry.lines().filter_map(options).collect();
+    if add_to_cmdline(&all.join(" "), params)?.is_none() {
+        return Ok(None);
+    }
+    let missing = missing_params(&all.join(" "), params);
+
+    let mut lines: Vec<String> = entry.lines().map(str::to_string).collect();
+    match lines.iter().rposition(|line| options(line).is_some()) {
+        Some(index) => {
+            let current = options(&lines[index]).unwrap_or_default().to_string();
+            let cmdline = add_to_cmdline(&current, &missing)?.unwrap_or(current);
+            lines[index] = format!("options {}", cmdline);
+        }
+        None => lines.push(format!("options {}", missing.join(" "))),
+    }
+    Ok(Some(lines.join("\n") + "\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn params(params: &[&str]) -> Vec<String> {
+        params.iter().map(|p| p.to_string()).collect()
+    }
+
+    const SI: [&str; 2] = ["radeon.si_support=0", "amdgpu.si_support=1"];
+
+    #[test]
+    fn grub_defaults_gain_parameters_once() {
+        let config = "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet\"\nGRUB_CMDLINE_LINUX=\"\"\n";
+        let edited = Bootloader::Grub
+            .add_params(config, &params(&SI))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            edited,
+            "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet radeon.si_support=0 amdgpu.si_support=1\"\nGRUB_CMDLINE_LINUX=\"\"\n"
+        );
+        assert_eq!(Bootloader::Grub.add_params(&edited, &params(&SI)), Ok(None));
+
+        // Already set for every boot in GRUB_CMDLINE_LINUX
+        let always =
+            "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet\"\nGRUB_CMDLINE_LINUX=\"radeon.si-support=0\"\n";
+        assert_eq!(
+            Bootloader::Grub.add_params(always, &params(&SI)).unwrap().unwrap(),
+            "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet amdgpu.si_support=1\"\nGRUB_CMDLINE_LINUX=\"radeon.si-support=0\"\n"
+        );
+    }
+
+    #[test]
+    fn different_values_are_not_overridden() {
+        let config = "GRUB_CMDLINE_LINUX_DEFAULT='quiet amdgpu.si_support=0'\n";
+        assert_eq!(
+            Bootloader::Grub.add_params(config, &params(&SI)),
+            Err(vec!["amdgpu.si_support=0".to_string()])
+        );
+    }
+
+    #[test]
+    fn loader_entries_extend_options() {
+        let entry = "title Arch Linux\nlinux /vmlinuz-linux\ninitrd /initramfs-linux.img\noptions root=UUID=1 rw\n";
+        assert_eq!(
+            Bootloader::SystemdBoot
+                .add_params(entry, &params(&["i915.enable_guc=3"]))
+                .unwrap()
+                .unwrap(),
+            "title Arch Linux\nlinux /vmlinuz-linux\ninitrd /initramfs-linux.img\noptions root=UUID=1 rw i915.enable_guc=3\n"
+        );
+        assert_eq!(
+            Bootloader::SystemdBoot
+                .add_params("linux /vmlinuz-linux\n", &params(&["quiet"]))
+                .unwrap()
+                .unwrap(),
+            "linux /vmlinuz-linux\noptions quiet\n"
+        );
+    }
+
+    #[test]
+    fn limine_refind_and_uki_command_lines() {
+        let limine = "timeout: 3\n\n/Arch Linux\n    protocol: linux\n    kernel_path: boot():/vmlinuz-linux\n    cmdline: root=UUID=1 rw\n";
+        assert_eq!(
+            Bootloader::Limine
+                .add_params(limine, &params(&["mem_sleep_default=deep"]))
+                .unwrap()
+                .unwrap(),
+            limine.replace("rw\n", "rw mem_sleep_default=deep\n")
+        );
+
+        let refind = "\"Boot with standard options\"  \"root=UUID=1 rw quiet\"\n\"Boot to single-user mode\"   \"root=UUID=1 rw single\"\n";
+        assert_eq!(
+            Bootloader::Refind
+                .add_params(refind, &params(&["quiet"]))
+                .unwrap()
+                .unwrap(),
+            refind.replace("rw single", "rw single quiet")
+        );
+
+        assert_eq!(
+            Bootloader::Uki
+                .add_params("root=UUID=1 rw\n", &params(&SI))
+                .unwrap()
+                .unwrap(),
+            "root=UUID=1 rw radeon.si_support=0 amdgpu.si_support=1\n"
+        );
+        assert_eq!(
+            Bootloader::Uki.add_params("", &params(&["quiet"])),
+            Ok(Some("quiet\n".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn detects_by_configuration() {
+        let root = TempTree::new("bootloader-detect");
+        root.write("etc/default/grub", "GRUB_CMDLINE_LINUX_DEFAULT=\"quiet\"\n");
+        root.write("boot/loader/entries/arch.conf", "options rw\n");
+        let detected = detect(root.path()).await.unwrap();
+        assert_eq!(detected.bootloader, Bootloader::Grub);
+
+        root.write(
+            "sys/firmware/efi/efivars/LoaderInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f",
+            "",
+        );
+        let detected = detect(root.path()).await.unwrap();
+        assert_eq!(detected.bootloader, Bootloader::SystemdBoot);
+        assert_eq!(detected.paths, vec!["/boot/loader/entries/arch.conf"]);
+
+        root.write("etc/kernel/cmdline", "rw\n");
+        assert_eq!(
+            detect(root.path()).await.unwrap().bootloader,
+            Bootloader::Uki
+        );
+    }
+}
@@ -0,0 +1,276 @@
+//! External command execution behind a trait so setup can be tested without a live system
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// A command line to run, with optional input and output capture.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandLine {
+    pub argv: Vec<String>,
+    /// Written to the command's stdin, which is closed afterwards
+    pub stdin: Option<String>,
+    /// Capture stdout and stderr instead of passing them through to the terminal
+    pub capture: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandLine {
+    pub fn new<I, S>(argv: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            argv: argv.into_iter().map(Into::into).collect(),
+            stdin: None,
+            capture: false,
+        }
+    }
+
+    pub fn stdin(mut self, input: impl Into<String>) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    pub fn capture(mut self) -> Self {
+        self.capture = true;
+        self
+    }
+
+    pub fn program(&self) -> &str {
+        self.argv.first().map(String::as_str).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.argv.join(" "))
+    }
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+pub trait CommandRunner {
+    /// Runs `command` and waits for it; a non-zero exit is not an error.
+    ///
+    /// Fails only when the program cannot be started.
+    fn run(&self, command: &CommandLine) -> Result<CommandOutput>;
+}
+
+/// Runs commands on the host with `std::process::Command`.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, command: &CommandLine) -> Result<CommandOutput> {
+        let mut process = Command::new(command.program());
+        process.args(&command.argv[1..]);
+        if command.capture {
+            process.stdout(Stdio::piped()).stderr(Stdio::piped());
+        } else if command.stdin.is_some() {
+            // Input is usually piped into tee, which would echo it back
+            process.stdout(Stdio::null());
+        }
+        if command.stdin.is_some() {
+            process.stdin(Stdio::piped());
+        }
+
+        let mut child = process
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", command.program()))?;
+        if let (Some(input), Some(mut stdin)) = (&command.stdin, child.stdin.take()) {
+            stdin.write_all(input.as_bytes())?;
+        }
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to wait for {}", command.program()))?;
+
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockRunner;
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use std::cell::RefCell;
+
+    enum Reply {
+        Exit(i32, String),
+        /// The program is not installed
+        Missing,
+    }
+
+    struct Script {
+        argv: Vec<String>,
+        /// Match any command starting with `argv`
+        prefix: bool,
+        reply: Reply,
+        used: bool,
+    }
+
+    /// Records every command and replays scripted results.
+    ///
+    /// Unscripted commands succeed with empty output. A command scripted more
+    /// than once gets its replies in order, the last one repeating.
+    #[derive(Default)]
+    pub struct MockRunner {
+        scripts: RefCell<Vec<Script>>,
+        log: RefCell<Vec<CommandLine>>,
+    }
+
+    impl MockRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn reply(self, argv: &[&str], code: i32, stdout: &str) -> Self {
+            self.script(argv, false, Reply::Exit(code, stdout.to_string()))
+        }
+
+        /// Like `reply`, for any command whose argv starts with `prefix`.
+        pub fn reply_prefix(self, prefix: &[&str], code: i32, stdout: &str) -> Self {
+            self.script(prefix, true, Reply::Exit(code, stdout.to_string()))
+        }
+
+        /// Makes `program` fail to start, whatever its arguments.
+        pub fn missing(self, program: &str) -> Self {
+            self.script(&[program], true, Reply::Missing)
+        }
+
+        fn script(self, argv: &[&str], prefix: bool, reply: Reply) -> Self {
+            self.scripts.borrow_mut().push(Script {
+                argv: argv.iter().map(|a| a.to_string()).collect(),
+                prefix,
+                reply,
+                used: false,
+            });
+            self
+        }
+
+        pub fn commands(&self) -> Vec<CommandLine> {
+            self.log.borrow().clone()
+        }
+
+        /// Every recorded command as `$ argv`, followed by its input as `> line`.
+        pub fn transcript(&self) -> String {
+            let mut text = String::new();
+            for command in self.log.borrow().iter() {
+                text.push_str(&format!("$ {}\n", command));
+                for line in command.stdin.iter().flat_map(|input| input.lines()) {
+                    text.push_str(&format!("> {}\n", line));
+                }
+            }
+            text
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, command: &CommandLine) -> Result<CommandOutput> {
+            self.log.borrow_mut().push(command.clone());
+
+            let mut scripts = self.scripts.borrow_mut();
+            let matches = |script: &Script| {
+                if script.prefix {
+                    command.argv.starts_with(&script.argv)
+                } else {
+                    script.argv == command.argv
+                }
+            };
+            let index = scripts
+                .iter()
+                .position(|s| !s.used && matches(s))
+                .or_else(|| scripts.iter().rposition(matches));
+
+            match index.map(|i| &mut scripts[i]) {
+                Some(script) => {
+                    script.used = true;
+                    match &script.reply {
+                        Reply::Exit(code, stdout) => Ok(CommandOutput {
+                            code: Some(*code),
+                            stdout: stdout.clone(),
+                            ..Default::default()
+                        }),
+                        Reply::Missing => {
+                            anyhow::bail!("Failed to execute {}", command.program())
+                        }
+                    }
+                }
+                None => Ok(CommandOutput {
+                    code: Some(0),
+                    ..Default::default()
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_replays_scripted_replies_in_order() {
+        let runner = MockRunner::new()
+            .reply(&["pacman", "-Q", "linux"], 0, "linux 6.11.5.arch1-1\n")
+            .reply(&["pacman", "-Q", "linux"], 1, "")
+            .missing("lspci");
+
+        let query = CommandLine::new(["pacman", "-Q", "linux"]).capture();
+        assert_eq!(runner.run(&query).unwrap().stdout, "linux 6.11.5.arch1-1\n");
+        assert!(!runner.run(&query).unwrap().success());
+        assert!(!runner.run(&query).unwrap().success());
+        assert!(runner.run(&CommandLine::new(["lspci", "-k"])).is_err());
+        assert!(runner
+            .run(&CommandLine::new(["modprobe", "i915"]))
+            .unwrap()
+            .success());
+        assert_eq!(runner.commands().len(), 5);
+    }
+
+    #[test]
+    fn transcript_includes_input() {
+        let runner = MockRunner::new();
+        runner
+            .run(&CommandLine::new(["tee", "/etc/modules-load.d/x.conf"]).stdin("a\nb\n"))
+            .unwrap();
+        assert_eq!(
+            runner.transcript(),
+            "$ tee /etc/modules-load.d/x.conf\n> a\n> b\n"
+        );
+    }
+
+    #[test]
+    fn system_runner_captures_output_and_exit_code() {
+        let output = SystemRunner
+            .run(
+                &CommandLine::new(["sh", "-c", "cat; exit 3"])
+                    .stdin("hello")
+                    .capture(),
+            )
+            .unwrap();
+        assert_eq!(output.stdout, "hello");
+        assert_eq!(output.code, Some(3));
+        assert!(SystemRunner
+            .run(&CommandLine::new(["/nonexistent/ardenthat-cmd"]))
+            .is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! System-wide settings from `/etc/ardenthat/config.json`
+
+use crate::privilege::Escalation;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+
+const CONFIG_FILE: &str = "etc/ardenthat/config.json";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Privilege escalation tool, auto-detected when unset
+    pub escalation: Option<Escalation>,
+    /// Refuse to run setup unless a pre-setup snapshot was taken
+    #[serde(default)]
+    pub require_snapshot: bool,
+}
+
+impl Config {
+    /// Loads the configuration below `root`; a missing file means defaults.
+    pub async fn load(root: &Path) -> Result<Self> {
+        let path = root.join(CONFIG_FILE);
+        match fs::read_to_string(&path).await {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("Invalid configuration in {}", path.display())),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    #[tokio::test]
+    async fn loads_escalation_setting() {
+        let root = TempTree::new("config");
+        assert!(Config::load(root.path())
+            .await
+            .unwrap()
+            .escalation
+            .is_none());
+
+        root.write(CONFIG_FILE, r#"{ "escalation": "doas" }"#);
+        let config = Config::load(root.path()).await.unwrap();
+        assert_eq!(config.escalation, Some(Escalation::Doas));
+        assert!(!config.require_snapshot);
+
+        root.write(CONFIG_FILE, r#"{ "require_snapshot": true }"#);
+        assert!(Config::load(root.path()).await.unwrap().require_snapshot);
+
+        root.write(CONFIG_FILE, r#"{ "escalate": "doas" }"#);
+        assert!(Config::load(root.path()).await.is_err());
+    }
+}
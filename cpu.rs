@@ -0,0 +1,299 @@
+//! CPU description parsed from `/proc/cpuinfo` (x86 and aarch64 layouts)
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CpuInfo {
+    /// Raw vendor string, e.g. `GenuineIntel`, `AuthenticAMD` or `ARM`
+    pub vendor_id: String,
+    pub model_name: String,
+    pub family: Option<u32>,
+    pub model: Option<u32>,
+    pub stepping: Option<u32>,
+    pub sockets: u32,
+    pub cores: u32,
+    pub threads: u32,
+    pub flags: BTreeSet<String>,
+    /// Running microcode revision as reported by the kernel, e.g. `0x830107a`
+    pub microcode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hybrid: Option<HybridTopology>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct HybridTopology {
+    pub performance_cores: u32,
+    pub efficiency_cores: u32,
+}
+
+impl CpuInfo {
+    /// Short vendor name for display, e.g. `Intel` for `GenuineIntel`.
+    pub fn vendor_name(&self) -> &str {
+        match self.vendor_id.as_str() {
+            "GenuineIntel" => "Intel",
+            "AuthenticAMD" => "AMD",
+            "HygonGenuine" => "Hygon",
+            "CentaurHauls" => "Centaur",
+            other => other,
+        }
+    }
+}
+
+/// Collapses the per-processor blocks of `/proc/cpuinfo` into a single description.
+pub fn parse_cpuinfo(info: &str) -> Result<CpuInfo> {
+    let blocks: Vec<BTreeMap<&str, &str>> = info
+        .split("\n\n")
+        .map(|block| {
+            block
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .collect::<BTreeMap<_, _>>()
+        })
+        .filter(|block| block.contains_key("processor"))
+        .collect();
+
+    let first = match blocks.first() {
+        Some(first) => first,
+        None => anyhow::bail!("No processor entries found in cpuinfo"),
+    };
+
+    if first.contains_key("CPU implementer") {
+        Ok(parse_arm(&blocks))
+    } else {
+        Ok(parse_x86(&blocks))
+    }
+}
+
+fn parse_x86(blocks: &[BTreeMap<&str, &str>]) -> CpuInfo {
+    let first = &blocks[0];
+
+    // Count logical processors per physical core to derive cores and hybrid layout
+    let mut threads_per_core: BTreeMap<(&str, &str), u32> = BTreeMap::new();
+    for block in blocks {
+        let key = match (block.get("physical id"), block.get("core id")) {
+            (Some(package), Some(core)) => (*package, *core),
+            // Some hypervisors hide topology, treat every processor as its own core
+            _ => ("0", block.get("processor").copied().unwrap_or("")),
+        };
+        *threads_per_core.entry(key).or_default() += 1;
+    }
+    let sockets = threads_per_core
+        .keys()
+        .map(|(package, _)| *package)
+        .collect::<BTreeSet<_>>()
+        .len() as u32;
+
+    let flags: BTreeSet<String> = first
+        .get("flags")
+        .map(|flags| flags.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+
+    // P-cores run two SMT threads, E-cores only one; without SMT the split is unknowable
+    let hybrid = if flags.contains("hybrid_cpu") {
+        let performance = threads_per_core.values().filter(|&&t| t > 1).count() as u32;
+        let efficiency = threads_per_core.values().filter(|&&t| t == 1).count() as u32;
+        (performance > 0 && efficiency > 0).then_some(HybridTopology {
+            performance_cores: performance,
+            efficiency_cores: efficiency,
+        })
+    } else {
+        None
+    };
+
+    CpuInfo {
+        vendor_id: first.get("vendor_id").unwrap_or(&"Unknown").to_string(),
+        model_name: first.get("model name").unwrap_or(&"Unknown").to_string(),
+        family: first.get("cpu family").and_then(|v| v.parse().ok()),
+        model: first.get("model").and_then(|v| v.parse().ok()),
+        stepping: first.get("stepping").and_then(|v| v.parse().ok()),
+        sockets: sockets.max(1),
+        cores: threads_per_core.len() as u32,
+        threads: blocks.len() as u32,
+        flags,
+        microcode: first.get("microcode").map(|v| v.to_string()),
+        hybrid,
+    }
+}
+
+fn parse_arm(blocks: &[BTreeMap<&str, &str>]) -> CpuInfo {
+    let first = &blocks[0];
+    let implementer = first
+        .get("CPU implementer")
+        .and_then(|v| parse_hex(v))
+        .unwrap_or_default();
+
+    // big.LITTLE designs list a different CPU part per cluster
+    let mut parts: BTreeMap<u32, u32> = BTreeMap::new();
+    for block in blocks {
+        if let Some(part) = block.get("CPU part").and_then(|v| parse_hex(v)) {
+            *parts.entry(part).or_default() += 1;
+        }
+    }
+
+    let efficiency: u32 = parts
+        .iter()
+        .filter(|(part, _)| implementer == 0x41 && is_arm_little_core(**part))
+        .map(|(_, count)| count)
+        .sum();
+    let performance = blocks.len() as u32 - efficiency;
+    let hybrid = (performance > 0 && efficiency > 0).then_some(HybridTopology {
+        performance_cores: performance,
+        efficiency_cores: efficiency,
+    });
+
+    let model_name = parts
+        .keys()
+        .rev()
+        .map(|&part| match arm_part_name(implementer, part) {
+            Some(name) => name.to_string(),
+            None => format!("CPU part {:#x}", part),
+        })
+        .collect::<Vec<_>>()
+        .join(" + ");
+
+    CpuInfo {
+        vendor_id: arm_implementer_name(implementer).to_string(),
+        model_name,
+        family: first.get("CPU architecture").and_then(|v| v.parse().ok()),
+        model: first.get("CPU part").and_then(|v| parse_hex(v)),
+        stepping: first.get("CPU revision").and_then(|v| v.parse().ok()),
+        sockets: 1,
+        // aarch64 cores do not use SMT in practice, every processor is a core
+        cores: blocks.len() as u32,
+        threads: blocks.len() as u32,
+        flags: first
+            .get("Features")
+            .map(|flags| flags.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default(),
+        microcode: None,
+        hybrid,
+    }
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+fn arm_implementer_name(implementer: u32) -> &'static str {
+    match implementer {
+        0x41 => "ARM",
+        0x42 => "Broadcom",
+        0x48 => "HiSilicon",
+        0x4e => "NVIDIA",
+        0x51 => "Qualcomm",
+        0x61 => "Apple",
+        0xc0 => "Ampere",
+        _ => "Unknown",
+    }
+}
+
+fn arm_part_name(implementer: u32, part: u32) -> Option<&'static str> {
+    if implementer != 0x41 {
+        return None;
+    }
+    Some(match part {
+        0xd03 => "Cortex-A53",
+        0xd04 => "Cortex-A35",
+        0xd05 => "Cortex-A55",
+        0xd07 => "Cortex-A57",
+        0xd08 => "Cortex-A72",
+        0xd09 => "Cortex-A73",
+        0xd0a => "Cortex-A75",
+        0xd0b => "Cortex-A76",
+        0xd0c => "Neoverse-N1",
+        0xd0d => "Cortex-A77",
+        0xd40 => "Neoverse-V1",
+        0xd41 => "Cortex-A78",
+        0xd44 => "Cortex-X1",
+        0xd46 => "Cortex-A510",
+        0xd47 => "Cortex-A710",
+        0xd48 => "Cortex-X2",
+        0xd49 => "Neoverse-N2",
+        _ => return None,
+    })
+}
+
+fn is_arm_little_core(part: u32) -> bool {
+    matches!(part, 0xd03 | 0xd04 | 0xd05 | 0xd46)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collapses_threadripper_blocks() {
+        let cpu = parse_cpuinfo(include_str!("fixtures/cpuinfo/threadripper-3970x.txt")).unwrap();
+        assert_eq!(cpu.vendor_id, "AuthenticAMD");
+        assert_eq!(cpu.vendor_name(), "AMD");
+        assert_eq!(
+            cpu.model_name,
+            "AMD Ryzen Threadripper 3970X 32-Core Processor"
+        );
+        assert_eq!(
+            (cpu.family, cpu.model, cpu.stepping),
+            (Some(23), Some(49), Some(0))
+        );
+        assert_eq!((cpu.sockets, cpu.cores, cpu.threads), (1, 32, 64));
+        assert_eq!(cpu.microcode.as_deref(), Some("0x830107a"));
+        assert!(cpu.flags.contains("svm"));
+        assert!(cpu.flags.contains("avx2"));
+        assert!(cpu.hybrid.is_none());
+    }
+
+    #[test]
+    fn detects_intel_hybrid_topology() {
+        let cpu = parse_cpuinfo(include_str!("fixtures/cpuinfo/alderlake-i7-12700h.txt")).unwrap();
+        assert_eq!(cpu.vendor_name(), "Intel");
+        assert_eq!(cpu.model_name, "12th Gen Intel(R) Core(TM) i7-12700H");
+        assert_eq!(
+            (cpu.family, cpu.model, cpu.stepping),
+            (Some(6), Some(154), Some(3))
+        );
+        assert_eq!((cpu.cores, cpu.threads), (14, 20));
+        assert_eq!(cpu.microcode.as_deref(), Some("0x432"));
+        assert_eq!(
+            cpu.hybrid,
+            Some(HybridTopology {
+                performance_cores: 6,
+                efficiency_cores: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_aarch64_layout() {
+        let cpu = parse_cpuinfo(include_str!("fixtures/cpuinfo/rpi4-cortex-a72.txt")).unwrap();
+        assert_eq!(cpu.vendor_id, "ARM");
+        assert_eq!(cpu.model_name, "Cortex-A72");
+        assert_eq!(
+            (cpu.family, cpu.model, cpu.stepping),
+            (Some(8), Some(0xd08), Some(0))
+        );
+        assert_eq!((cpu.cores, cpu.threads), (4, 4));
+        assert!(cpu.flags.contains("asimd"));
+        assert!(cpu.microcode.is_none());
+        assert!(cpu.hybrid.is_none());
+    }
+
+    #[test]
+    fn detects_big_little_clusters() {
+        let cpu = parse_cpuinfo(include_str!("fixtures/cpuinfo/rk3588-big-little.txt")).unwrap();
+        assert_eq!(cpu.model_name, "Cortex-A76 + Cortex-A55");
+        assert_eq!(
+            cpu.hybrid,
+            Some(HybridTopology {
+                performance_cores: 4,
+                efficiency_cores: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_cpuinfo() {
+        assert!(parse_cpuinfo("").is_err());
+    }
+}
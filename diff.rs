@@ -0,0 +1,170 @@
+//! Unified diffs of configuration files for setup plans
+
+/// Lines of unchanged context around each hunk, as in `diff -u`.
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Unified diff turning `old` into `new` for the file at `path`.
+///
+/// A missing `old` file is diffed against `/dev/null`. Returns an empty string
+/// when the contents are equal.
+pub fn unified(path: &str, old: Option<&str>, new: &str) -> String {
+    let old_lines: Vec<&str> = old.unwrap_or_default().lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let lines = diff_lines(&old_lines, &new_lines);
+    if lines.iter().all(|l| matches!(l, Line::Same(_))) {
+        return String::new();
+    }
+
+    let mut out = format!(
+        "--- {}\n+++ {}\n",
+        if old.is_some() { path } else { "/dev/null" },
+        path
+    );
+    for (start, end) in hunks(&lines) {
+        // 1-based line numbers of the hunk start in each file
+        let old_start = 1 + lines[..start]
+            .iter()
+            .filter(|l| !matches!(l, Line::Added(_)))
+            .count();
+        let new_start = 1 + lines[..start]
+            .iter()
+            .filter(|l| !matches!(l, Line::Removed(_)))
+            .count();
+        let hunk = &lines[start..end];
+        let old_len = hunk.iter().filter(|l| !matches!(l, Line::Added(_))).count();
+        let new_len = hunk
+            .iter()
+            .filter(|l| !matches!(l, Line::Removed(_)))
+            .count();
+
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_len),
+            range(new_start, new_len)
+        ));
+        for line in hunk {
+            let (marker, text) = match line {
+                Line::Same(text) => (' ', text),
+                Line::Removed(text) => ('-', text),
+                Line::Added(text) => ('+', text),
+            };
+            out.push(marker);
+            out.push_str(text);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// `start,len` as in diff hunk headers; an empty range starts before its position.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start - 1),
+        1 => start.to_string(),
+        _ => format!("{},{}", start, len),
+    }
+}
+
+/// Line-by-line edit script from the longest common subsequence.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    // lcs[i][j]: common lines of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Index ranges of the hunks: changes with their context, merged when they touch.
+fn hunks(lines: &[Line<'_>]) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, _) in lines
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| !matches!(l, Line::Same(_)))
+    {
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + 1 + CONTEXT).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_a_changed_line_with_context() {
+        let old = "# mkinitcpio\nMODULES=()\nBINARIES=()\nFILES=()\nHOOKS=(base udev)\nCOMPRESSION=zstd\n";
+        let new = old.replace("MODULES=()", "MODULES=(i915)");
+        assert_eq!(
+            unified("/etc/mkinitcpio.conf", Some(old), &new),
+            "--- /etc/mkinitcpio.conf\n\
+             +++ /etc/mkinitcpio.conf\n\
+             @@ -1,5 +1,5 @@\n \
+             # mkinitcpio\n\
+             -MODULES=()\n\
+             +MODULES=(i915)\n \
+             BINARIES=()\n \
+             FILES=()\n \
+             HOOKS=(base udev)\n"
+        );
+    }
+
+    #[test]
+    fn new_files_are_diffed_against_dev_null() {
+        assert_eq!(
+            unified("/etc/modules-load.d/ardenthat.conf", None, "iwlwifi\n"),
+            "--- /dev/null\n+++ /etc/modules-load.d/ardenthat.conf\n@@ -0,0 +1 @@\n+iwlwifi\n"
+        );
+    }
+
+    #[test]
+    fn separate_changes_get_separate_hunks() {
+        let old: String = (1..=12).map(|n| format!("{}\n", n)).collect();
+        let new: String = (1..=12)
+            .filter(|&n| n != 11)
+            .map(|n| match n {
+                2 => "two\n".to_string(),
+                n => format!("{}\n", n),
+            })
+            .collect();
+        let diff = unified("/f", Some(&old), &new);
+        assert_eq!(diff.matches("@@ -").count(), 2);
+        assert!(diff.contains("@@ -8,5 +8,4 @@\n 8\n 9\n 10\n-11\n 12\n"));
+        assert_eq!(unified("/f", Some(&old), &old), "");
+    }
+}
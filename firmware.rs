@@ -0,0 +1,445 @@
+//! Missing firmware detection from modinfo and the kernel log
+//!
+//! Every bound driver declares the blobs it may request in its `firmware:`
+//! modinfo entries, and the kernel logs each request that fails. Blobs missing
+//! from `/lib/firmware` are mapped to the package that ships them using the
+//! bundled `data/firmware.json` table.
+
+use crate::command::CommandLine;
+use crate::modalias::fnmatch;
+use crate::packages::PackageDatabase;
+use crate::{DriverStatus, HardwareComponent, Host};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+
+const FIRMWARE_VERSION: u32 = 1;
+const BUNDLED_FIRMWARE: &str = include_str!("data/firmware.json");
+
+/// Directories the kernel loads firmware from, in lookup order.
+const FIRMWARE_DIRS: [&str; 2] = ["/lib/firmware/updates", "/lib/firmware"];
+/// Compressed variants the kernel decompresses on load.
+const COMPRESSED: [&str; 3] = ["", ".zst", ".xz"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FirmwareFile {
+    version: u32,
+    packages: Vec<PackageEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackageEntry {
+    package: String,
+    #[serde(default)]
+    aur: bool,
+    /// Path prefixes below `/lib/firmware`, e.g. `amdgpu/` or `iwlwifi-`
+    blobs: Vec<String>,
+}
+
+/// Package that ships a firmware blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwarePackage {
+    pub name: String,
+    /// Only in the AUR, so setup builds it only with `--allow-aur`
+    pub aur: bool,
+}
+
+/// A firmware blob a bound driver needs but `/lib/firmware` does not have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingFirmware {
+    pub blob: String,
+    /// Driver that declared or requested the blob, when known
+    pub module: Option<String>,
+    pub package: Option<FirmwarePackage>,
+}
+
+/// Blob path prefixes and the packages that ship them.
+#[derive(Debug, Default)]
+pub struct FirmwareIndex {
+    prefixes: Vec<(String, FirmwarePackage)>,
+}
+
+impl FirmwareIndex {
+    pub fn bundled() -> Result<Self> {
+        Self::parse(BUNDLED_FIRMWARE)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: FirmwareFile =
+            serde_json::from_str(text).context("Invalid firmware package table")?;
+        if file.version != FIRMWARE_VERSION {
+            anyhow::bail!(
+                "Unsupported firmware table version {} (expected {})",
+                file.version,
+                FIRMWARE_VERSION
+            );
+        }
+
+        let mut prefixes = Vec::new();
+        for entry in file.packages {
+            let package = FirmwarePackage {
+                name: entry.package,
+                aur: entry.aur,
+            };
+            for blob in entry.blobs {
+                prefixes.push((blob, package.clone()));
+            }
+        }
+        Ok(Self { prefixes })
+    }
+
+    /// Package shipping `blob`, by longest matching prefix.
+    pub fn package_for(&self, blob: &str) -> Option<&FirmwarePackage> {
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| blob.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, package)| package)
+    }
+}
+
+/// Blobs the kernel log reports as failed requests, with the logging driver.
+///
+/// Handles `Direct firmware load for <blob> failed with error -2` and
+/// `firmware: failed to load <blob> (-2)`, with or without dmesg timestamps.
+pub fn failed_loads(log: &str) -> Vec<(Option<String>, String)> {
+    let mut failed: Vec<(Option<String>, String)> = Vec::new();
+    for line in log.lines() {
+        let line = match line.trim_start().strip_prefix('[') {
+            Some(rest) => rest
+                .split_once(']')
+                .map_or(rest, |(_, text)| text)
+                .trim_start(),
+            None => line.trim_start(),
+        };
+        let blob = if let Some((_, rest)) = line.split_once("Direct firmware load for ") {
+            rest.split_whitespace().next()
+        } else if let Some((_, rest)) = line.split_once("firmware: failed to load ") {
+            rest.split_whitespace().next()
+        } else {
+            continue;
+        };
+        let Some(blob) = blob else {
+            continue;
+        };
+        // "iwlwifi 0000:00:14.3: ..." names the driver before the device
+        let module = line
+            .split_once(": ")
+            .and_then(|(source, _)| source.split_once(' '))
+            .map(|(driver, _)| driver.to_string());
+        let entry = (module, blob.to_string());
+        if !failed.iter().any(|(_, b)| *b == entry.1) {
+            failed.push(entry);
+        }
+    }
+    failed
+}
+
+/// Whether `blob` or a compressed variant exists below one of the firmware
+/// directories of `root`. Glob patterns from modinfo match any file.
+pub async fn is_present(root: &Path, blob: &str) -> bool {
+    for dir in FIRMWARE_DIRS {
+        let dir = root.join(dir.trim_start_matches('/'));
+        if blob.contains(['*', '?', '[']) {
+            let (parent, pattern) = blob.rsplit_once('/').unwrap_or(("", blob));
+            let Ok(mut entries) = fs::read_dir(dir.join(parent)).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let name = COMPRESSED
+                    .iter()
+                    .find_map(|ext| name.strip_suffix(ext).filter(|_| !ext.is_empty()))
+                    .unwrap_or(&name);
+                if fnmatch(pattern.as_bytes(), name.as_bytes()) {
+                    return true;
+                }
+            }
+        } else if COMPRESSED
+            .iter()
+            .any(|ext| dir.join(format!("{}{}", blob, ext)).exists())
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Firmware `module` declares in its `firmware:` modinfo entries.
+fn module_firmware(host: &Host<'_>, module: &str) -> Vec<String> {
+    match host.run(CommandLine::new(["modinfo", "-F", "firmware", module]).capture()) {
+        Ok(output) if output.success() => output
+            .stdout
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Kernel messages of the current boot, from the journal or else the ring buffer.
+fn kernel_log(host: &Host<'_>) -> String {
+    let commands = [
+        vec!["journalctl", "-k", "-b", "-o", "cat", "--no-pager"],
+        vec!["dmesg"],
+    ];
+    for argv in commands {
+        if let Ok(output) = host.run(CommandLine::new(argv).capture()) {
+            if output.success() {
+                return output.stdout;
+            }
+        }
+    }
+    String::new()
+}
+
+/// Finds firmware the bound drivers of `components` are missing.
+///
+/// Declared blobs only count when a repository package that is not installed
+/// ships them; drivers list firmware for every device they support and several
+/// API versions, most of which no package provides. Blobs of AUR packages count
+/// only as failed requests from the kernel log, since setup cannot tell whether
+/// an AUR package is installed. Failed requests count unless their package is
+/// already installed.
+pub async fn find_missing(
+    host: &Host<'_>,
+    components: &[HardwareComponent],
+    packages: Option<&PackageDatabase>,
+) -> Vec<MissingFirmware> {
+    let Ok(index) = FirmwareIndex::bundled() else {
+        return Vec::new();
+    };
+    let installed = |package: &FirmwarePackage| {
+        !package.aur && packages.is_some_and(|db| db.is_installed(&package.name))
+    };
+
+    let mut drivers: Vec<&str> = components
+        .iter()
+        .filter(|c| c.cpu.is_none())
+        .filter_map(|c| c.driver.as_deref())
+        .collect();
+    drivers.sort();
+    drivers.dedup();
+
+    let mut missing: Vec<MissingFirmware> = Vec::new();
+    for (module, blob) in failed_loads(&kernel_log(host)) {
+        let package = index.package_for(&blob).cloned();
+        if package.as_ref().is_some_and(installed) || is_present(&host.root, &blob).await {
+            continue;
+        }
+        missing.push(MissingFirmware {
+            blob,
+            module,
+            package,
+        });
+    }
+    for driver in drivers {
+        for blob in module_firmware(host, driver) {
+            let Some(package) = index.package_for(&blob) else {
+                continue;
+            };
+            if package.aur
+                || installed(package)
+                || missing.iter().any(|m| m.blob == blob)
+                || is_present(&host.root, &blob).await
+            {
+                continue;
+            }
+            missing.push(MissingFirmware {
+                blob,
+                module: Some(driver.to_string()),
+                package: Some(package.clone()),
+            });
+        }
+    }
+    missing
+}
+
+/// Marks components whose bound driver misses firmware as `FirmwareMissing`.
+pub fn mark_components(components: &mut [HardwareComponent], missing: &[MissingFirmware]) {
+    for component in components.iter_mut().filter(|c| c.cpu.is_none()) {
+        let Some(driver) = component.driver.clone() else {
+            continue;
+        };
+        let blobs: Vec<&MissingFirmware> = missing
+            .iter()
+            .filter(|m| m.module.as_deref() == Some(driver.as_str()))
+            .collect();
+        let Some(first) = blobs.first() else {
+            continue;
+        };
+
+        let files = match blobs.len() {
+            1 => format!("firmware {} is", first.blob),
+            n => format!("{} firmware files are", n),
+        };
+        let mut shipped_by: Vec<&str> = blobs
+            .iter()
+            .filter_map(|m| m.package.as_ref())
+            .map(|p| p.name.as_str())
+            .collect();
+        shipped_by.sort();
+        shipped_by.dedup();
+        let package = if shipped_by.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", shipped_by.join(", "))
+        };
+        component.status = DriverStatus::FirmwareMissing;
+        component.status_reason = format!("{} is bound but {} missing{}", driver, files, package);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MockRunner;
+    use crate::testutil::TempTree;
+
+    #[test]
+    fn maps_blobs_by_longest_prefix() {
+        let index = FirmwareIndex::bundled().unwrap();
+        let package = |blob| index.package_for(blob).map(|p| p.name.as_str());
+        assert_eq!(
+            package("amdgpu/navi10_gpu_info.bin"),
+            Some("linux-firmware-amdgpu")
+        );
+        assert_eq!(
+            package("intel/ibt-0040-0041.sfi"),
+            Some("linux-firmware-intel")
+        );
+        assert_eq!(package("intel/sof/sof-tgl.ri"), Some("sof-firmware"));
+        assert_eq!(
+            package("iwlwifi-so-a0-gf-a0-89.ucode"),
+            Some("linux-firmware-intel")
+        );
+        assert_eq!(package("unknown.bin"), None);
+        assert!(index.package_for("renesas_usb_fw.mem").unwrap().aur);
+    }
+
+    #[test]
+    fn parses_failed_requests() {
+        let log = "[    2.112] iwlwifi 0000:00:14.3: Direct firmware load for iwlwifi-so-a0-gf-a0-89.ucode failed with error -2\n\
+                   [    2.113] iwlwifi 0000:00:14.3: Direct firmware load for iwlwifi-so-a0-gf-a0-89.ucode failed with error -2\n\
+                   bluetooth hci0: firmware: failed to load intel/ibt-0040-0041.sfi (-2)\n\
+                   usb 1-2: new high-speed USB device number 3\n";
+        assert_eq!(
+            failed_loads(log),
+            vec![
+                (
+                    Some("iwlwifi".to_string()),
+                    "iwlwifi-so-a0-gf-a0-89.ucode".to_string()
+                ),
+                (
+                    Some("bluetooth".to_string()),
+                    "intel/ibt-0040-0041.sfi".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_compressed_and_globbed_blobs() {
+        let root = TempTree::new("firmware-present");
+        root.write("lib/firmware/amdgpu/navi10_sos.bin.zst", "");
+        root.write("lib/firmware/updates/rtw89/rtw8852b_fw-1.bin.xz", "");
+        assert!(is_present(root.path(), "amdgpu/navi10_sos.bin").await);
+        assert!(is_present(root.path(), "rtw89/rtw8852b_fw-1.bin").await);
+        assert!(is_present(root.path(), "rtw89/rtw8852b_fw*.bin").await);
+        assert!(!is_present(root.path(), "amdgpu/navi10_ta.bin").await);
+    }
+
+    #[tokio::test]
+    async fn declared_blobs_count_only_when_their_package_is_missing() {
+        let root = TempTree::new("firmware-missing");
+        let runner = MockRunner::new().reply(
+            &["modinfo", "-F", "firmware", "amdgpu"],
+            0,
+            "amdgpu/navi10_sos.bin\namdgpu/navi10_ta.bin\nvendor/unknown.bin\n",
+        );
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+        let gpu = HardwareComponent {
+            driver: Some("amdgpu".to_string()),
+            ..Default::default()
+        };
+
+        let db = PackageDatabase::parse("", "linux-firmware-amdgpu\n");
+        let mut components = vec![gpu];
+        let missing = find_missing(&host, &components, Some(&db)).await;
+        let blobs: Vec<&str> = missing.iter().map(|m| m.blob.as_str()).collect();
+        assert_eq!(blobs, vec!["amdgpu/navi10_sos.bin", "amdgpu/navi10_ta.bin"]);
+        mark_components(&mut components, &missing);
+        assert_eq!(
+            components[0].status_reason,
+            "amdgpu is bound but 2 firmware files are missing (linux-firmware-amdgpu)"
+        );
+
+        let installed = PackageDatabase::parse("linux-firmware-amdgpu 20250808-1\n", "");
+        assert!(find_missing(&host, &components, Some(&installed))
+            .await
+            .is_empty());
+    }
+
+    #[test]
+    fn names_each_firmware_package_once() {
+        let blob = |blob: &str, package: &str| MissingFirmware {
+            blob: blob.to_string(),
+            module: Some("iwlwifi".to_string()),
+            package: Some(FirmwarePackage {
+                name: package.to_string(),
+                aur: false,
+            }),
+        };
+        let missing = [
+            blob("iwlwifi-8265-36.ucode", "linux-firmware-intel"),
+            blob("regulatory.db", "wireless-regdb"),
+            blob("iwl-dbg-cfg.ini", "linux-firmware-intel"),
+        ];
+        let mut components = vec![HardwareComponent {
+            driver: Some("iwlwifi".to_string()),
+            ..Default::default()
+        }];
+        mark_components(&mut components, &missing);
+        assert_eq!(
+            components[0].status_reason,
+            "iwlwifi is bound but 3 firmware files are missing \
+             (linux-firmware-intel, wireless-regdb)"
+        );
+    }
+
+    #[tokio::test]
+    async fn aur_firmware_counts_only_when_loading_it_failed() {
+        let root = TempTree::new("firmware-aur");
+        let journal = ["journalctl", "-k", "-b", "-o", "cat", "--no-pager"];
+        let runner = MockRunner::new()
+            .reply(&["modinfo", "-F", "firmware", "ast"], 0, "ast_dp501_fw.bin\n")
+            .reply(&journal, 0, "")
+            .reply(
+                &journal,
+                0,
+                "ast 0000:02:00.0: Direct firmware load for ast_dp501_fw.bin failed with error -2\n",
+            );
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+        let bmc = vec![HardwareComponent {
+            driver: Some("ast".to_string()),
+            ..Default::default()
+        }];
+        assert!(find_missing(&host, &bmc, None).await.is_empty());
+
+        let missing = find_missing(&host, &bmc, None).await;
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].blob, "ast_dp501_fw.bin");
+        assert_eq!(missing[0].package.as_ref().unwrap().name, "ast-firmware");
+    }
+}
@@ -0,0 +1,428 @@
+//! Hybrid graphics (PRIME / Optimus): which GPU drives the panel and how the
+//! discrete GPU is used
+//!
+//! GPUs are described from sysfs: `boot_vga` marks the firmware's primary
+//! adapter, `/sys/class/drm/cardN-<connector>` lists the outputs wired to each
+//! card, and the device's `power/` attributes show runtime PM and its D-state.
+
+use crate::HardwareComponent;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+/// Connector types of a built-in laptop panel.
+const INTERNAL_CONNECTORS: &[&str] = &["eDP", "LVDS", "DSI"];
+
+pub const REMOVE_DGPU_RULES: &str = "/etc/udev/rules.d/00-ardenthat-remove-dgpu.rules";
+pub const NVIDIA_PM_RULES: &str = "/etc/udev/rules.d/80-ardenthat-nvidia-pm.rules";
+pub const NVIDIA_PM_CONF: &str = "/etc/modprobe.d/ardenthat-nvidia-pm.conf";
+pub const PRIMARY_GPU_CONF: &str = "/etc/X11/xorg.conf.d/10-ardenthat-primary-gpu.conf";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GpuKind {
+    Integrated,
+    Discrete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Connector {
+    /// e.g. `eDP-1` or `HDMI-A-1`
+    pub name: String,
+    pub connected: bool,
+}
+
+/// Display-controller details from sysfs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpuInfo {
+    pub kind: GpuKind,
+    /// The firmware initialized this GPU as the primary display adapter
+    pub boot_vga: bool,
+    pub connectors: Vec<Connector>,
+    /// `power/runtime_status`, e.g. `active` or `suspended`
+    pub runtime_status: Option<String>,
+    /// `power/control`: `auto` allows runtime suspend, `on` keeps the device powered
+    pub power_control: Option<String>,
+    /// `power_state`, e.g. `D0` or `D3cold`
+    pub power_state: Option<String>,
+}
+
+impl GpuInfo {
+    /// Built-in panel connector of this GPU, preferring a connected one.
+    fn panel(&self) -> Option<&Connector> {
+        let internal = |c: &&Connector| {
+            INTERNAL_CONNECTORS
+                .iter()
+                .any(|kind| c.name.starts_with(&format!("{}-", kind)))
+        };
+        self.connectors
+            .iter()
+            .filter(internal)
+            .find(|c| c.connected)
+            .or_else(|| self.connectors.iter().find(internal))
+    }
+
+    /// Runtime D3 summary such as `D3cold, runtime suspended, runtime PM enabled`.
+    pub fn power_summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(state) = &self.power_state {
+            parts.push(state.clone());
+        }
+        if let Some(status) = &self.runtime_status {
+            parts.push(format!("runtime {}", status));
+        }
+        match self.power_control.as_deref() {
+            Some("auto") => parts.push("runtime PM enabled".to_string()),
+            Some(_) => parts.push("runtime PM disabled, the GPU never suspends".to_string()),
+            None => {}
+        }
+        if parts.is_empty() {
+            "unknown".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+/// How setup configures a system with an integrated and a discrete GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Power off the discrete GPU and use only the integrated one
+    #[value(name = "integrated-only")]
+    IntegratedOnly,
+    /// Integrated GPU by default, programs opt into the dGPU with prime-run or DRI_PRIME=1
+    #[value(name = "offload")]
+    Offload,
+    /// Render everything on the discrete GPU
+    #[value(name = "dgpu-always")]
+    DgpuAlways,
+}
+
+/// An integrated plus a discrete GPU, as indices into the component list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Topology {
+    pub integrated: usize,
+    pub discrete: usize,
+    /// GPU with the built-in panel, falling back to the boot VGA device
+    pub panel: Option<usize>,
+}
+
+/// Fills in `gpu` for every display controller from the sysfs tree at `sys`.
+///
+/// Without sysfs only the integrated/discrete classification is known.
+pub async fn inspect(sys: &Path, components: &mut [HardwareComponent]) {
+    let cards = drm_cards(sys).await;
+    let intel_present = components
+        .iter()
+        .filter_map(|c| c.pci.as_ref())
+        .any(|d| d.class == 0x03 && d.vendor_id == 0x8086);
+
+    for component in components.iter_mut() {
+        let Some(device) = component.pci.as_ref().filter(|d| d.class == 0x03) else {
+            continue;
+        };
+        let address = sysfs_address(&device.slot);
+        let path = sys.join("bus/pci/devices").join(&address);
+        let boot_vga = read_attr(&path, "boot_vga").await.as_deref() == Some("1");
+        let connectors = cards
+            .iter()
+            .filter(|(card_address, _)| *card_address == address)
+            .flat_map(|(_, connectors)| connectors.iter().cloned())
+            .collect();
+
+        component.gpu = Some(GpuInfo {
+            kind: classify(device.vendor_id, device.device_id, boot_vga, intel_present),
+            boot_vga,
+            connectors,
+            runtime_status: read_attr(&path, "power/runtime_status").await,
+            power_control: read_attr(&path, "power/control").await,
+            power_state: read_attr(&path, "power_state").await,
+        });
+    }
+}
+
+/// NVIDIA and Intel Arc cards are discrete, other Intel GPUs integrated. An AMD
+/// GPU counts as the APU's integrated one when it is the boot VGA device and
+/// there is no Intel GPU.
+fn classify(vendor_id: u16, device_id: u16, boot_vga: bool, intel_present: bool) -> GpuKind {
+    let discrete = match vendor_id {
+        0x8086 => matches!(device_id, 0x5690..=0x56ff | 0xe200..=0xe2ff),
+        0x1002 => !boot_vga || intel_present,
+        _ => true,
+    };
+    if discrete {
+        GpuKind::Discrete
+    } else {
+        GpuKind::Integrated
+    }
+}
+
+/// The integrated/discrete pair of a hybrid system, `None` for single-GPU machines.
+pub fn topology(components: &[HardwareComponent]) -> Option<Topology> {
+    let find = |kind: GpuKind| {
+        components
+            .iter()
+            .position(|c| c.gpu.as_ref().is_some_and(|g| g.kind == kind))
+    };
+    let integrated = find(GpuKind::Integrated)?;
+    let discrete = find(GpuKind::Discrete)?;
+
+    let gpu = |index: usize| components[index].gpu.as_ref();
+    let with_panel = [integrated, discrete]
+        .into_iter()
+        .find(|&i| gpu(i).is_some_and(|g| g.panel().is_some_and(|c| c.connected)))
+        .or_else(|| {
+            [integrated, discrete]
+                .into_iter()
+                .find(|&i| gpu(i).is_some_and(|g| g.panel().is_some()))
+        })
+        .or_else(|| {
+            [integrated, discrete]
+                .into_iter()
+                .find(|&i| gpu(i).is_some_and(|g| g.boot_vga))
+        });
+
+    Some(Topology {
+        integrated,
+        discrete,
+        panel: with_panel,
+    })
+}
+
+impl Topology {
+    /// Lines for the `detect` output.
+    pub fn describe(&self, components: &[HardwareComponent]) -> Vec<String> {
+        let name = |index: usize| {
+            let component = &components[index];
+            let slot = component
+                .pci
+                .as_ref()
+                .map(|d| d.slot.as_str())
+                .unwrap_or_default();
+            format!("{} {} ({})", component.vendor, component.model, slot)
+        };
+        let panel = match self.panel {
+            Some(index) => {
+                let connector = components[index]
+                    .gpu
+                    .as_ref()
+                    .and_then(GpuInfo::panel)
+                    .map(|c| format!(" via {}", c.name))
+                    .unwrap_or_default();
+                format!("Panel driven by: {}{}", name(index), connector)
+            }
+            None => "Panel driven by: unknown".to_string(),
+        };
+        let power = components[self.discrete]
+            .gpu
+            .as_ref()
+            .map(GpuInfo::power_summary)
+            .unwrap_or_default();
+
+        vec![
+            format!("Integrated GPU: {}", name(self.integrated)),
+            format!("Discrete GPU: {}", name(self.discrete)),
+            panel,
+            format!("Discrete GPU power: {}", power),
+        ]
+    }
+}
+
+/// udev rule that removes the discrete GPU from the PCI bus so it stays powered off.
+pub fn remove_dgpu_rules(vendor_id: u16, device_id: u16) -> String {
+    format!(
+        "# Written by ardenthat: integrated-only graphics, remove the discrete GPU\n\
+         ACTION==\"add\", SUBSYSTEM==\"pci\", ATTR{{vendor}}==\"0x{:04x}\", ATTR{{device}}==\"0x{:04x}\", ATTR{{power/control}}=\"auto\", ATTR{{remove}}=\"1\"\n",
+        vendor_id, device_id
+    )
+}
+
+/// udev rules enabling runtime D3 for NVIDIA display controllers while the driver is bound.
+pub fn nvidia_pm_rules() -> &'static str {
+    "# Written by ardenthat: runtime D3 power management for PRIME offload\n\
+     ACTION==\"bind\", SUBSYSTEM==\"pci\", ATTR{vendor}==\"0x10de\", ATTR{class}==\"0x030000\", TEST==\"power/control\", ATTR{power/control}=\"auto\"\n\
+     ACTION==\"bind\", SUBSYSTEM==\"pci\", ATTR{vendor}==\"0x10de\", ATTR{class}==\"0x030200\", TEST==\"power/control\", ATTR{power/control}=\"auto\"\n\
+     ACTION==\"unbind\", SUBSYSTEM==\"pci\", ATTR{vendor}==\"0x10de\", ATTR{class}==\"0x030000\", TEST==\"power/control\", ATTR{power/control}=\"on\"\n\
+     ACTION==\"unbind\", SUBSYSTEM==\"pci\", ATTR{vendor}==\"0x10de\", ATTR{class}==\"0x030200\", TEST==\"power/control\", ATTR{power/control}=\"on\"\n"
+}
+
+/// Fine-grained dynamic power management of the NVIDIA driver (Turing and newer).
+pub fn nvidia_pm_options() -> &'static str {
+    "# Written by ardenthat: let the NVIDIA driver power down the GPU when idle\n\
+     options nvidia \"NVreg_DynamicPowerManagement=0x02\"\n"
+}
+
+/// Xorg configuration making the discrete GPU's driver the primary GPU.
+pub fn primary_gpu_conf(xorg_driver: &str, drm_driver: &str) -> String {
+    let module_path = if xorg_driver == "nvidia" {
+        "    ModulePath \"/usr/lib/nvidia/xorg\"\n    ModulePath \"/usr/lib/xorg/modules\"\n"
+    } else {
+        ""
+    };
+    format!(
+        "# Written by ardenthat: render everything on the discrete GPU\n\
+         Section \"OutputClass\"\n    \
+         Identifier \"ardenthat-primary-gpu\"\n    \
+         MatchDriver \"{}\"\n    \
+         Driver \"{}\"\n    \
+         Option \"PrimaryGPU\" \"yes\"\n\
+         {}EndSection\n",
+        drm_driver, xorg_driver, module_path
+    )
+}
+
+/// `0000:01:00.0` for an lspci slot such as `01:00.0`.
+fn sysfs_address(slot: &str) -> String {
+    if slot.matches(':').count() == 1 {
+        format!("0000:{}", slot)
+    } else {
+        slot.to_string()
+    }
+}
+
+/// DRM cards with the PCI address of their device and their connectors.
+async fn drm_cards(sys: &Path) -> Vec<(String, Vec<Connector>)> {
+    let dir = sys.join("class/drm");
+    let mut names = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(&dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+
+    let mut cards = Vec::new();
+    for card in names
+        .iter()
+        .filter(|n| n.starts_with("card") && !n.contains('-'))
+    {
+        let Ok(target) = fs::read_link(dir.join(card).join("device")).await else {
+            continue;
+        };
+        let Some(address) = target.file_name().map(|n| n.to_string_lossy().into_owned()) else {
+            continue;
+        };
+
+        let prefix = format!("{}-", card);
+        let mut connectors = Vec::new();
+        for name in names.iter().filter(|n| n.starts_with(&prefix)) {
+            let status = read_attr(&dir.join(name), "status").await;
+            connectors.push(Connector {
+                name: name[prefix.len()..].to_string(),
+                connected: status.as_deref() == Some("connected"),
+            });
+        }
+        cards.push((address, connectors));
+    }
+    cards
+}
+
+async fn read_attr(path: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(path.join(attr))
+        .await
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn gpu(slot: &str, vendor_id: u16, device_id: u16) -> HardwareComponent {
+        HardwareComponent {
+            vendor: format!("{:04x}", vendor_id),
+            model: format!("{:04x}", device_id),
+            pci: Some(crate::pci::PciDevice {
+                slot: slot.to_string(),
+                vendor_id,
+                device_id,
+                class: 0x03,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// An Optimus laptop: Intel UHD 620 drives eDP-1, a suspended MX150 has no outputs.
+    fn optimus_sysfs(name: &str) -> TempTree {
+        let sys = TempTree::new(name);
+        for (card, address) in [("card0", "0000:00:02.0"), ("card1", "0000:01:00.0")] {
+            sys.symlink(
+                &format!("class/drm/{}/device", card),
+                &format!("devices/pci0000:00/{}", address),
+            );
+        }
+        sys.write("class/drm/card0-eDP-1/status", "connected\n");
+        sys.write("class/drm/card0-HDMI-A-1/status", "disconnected\n");
+        sys.write("bus/pci/devices/0000:00:02.0/boot_vga", "1\n");
+        sys.write("bus/pci/devices/0000:01:00.0/boot_vga", "0\n");
+        sys.write(
+            "bus/pci/devices/0000:01:00.0/power/runtime_status",
+            "suspended\n",
+        );
+        sys.write("bus/pci/devices/0000:01:00.0/power/control", "auto\n");
+        sys.write("bus/pci/devices/0000:01:00.0/power_state", "D3cold\n");
+        sys
+    }
+
+    #[tokio::test]
+    async fn finds_the_panel_gpu_and_dgpu_power_state() {
+        let sys = optimus_sysfs("hybrid-optimus");
+        let mut components = vec![
+            gpu("00:02.0", 0x8086, 0x5917),
+            gpu("01:00.0", 0x10de, 0x1d12),
+        ];
+        inspect(sys.path(), &mut components).await;
+
+        let intel = components[0].gpu.as_ref().unwrap();
+        assert_eq!(intel.kind, GpuKind::Integrated);
+        assert!(intel.boot_vga);
+        assert_eq!(intel.connectors.len(), 2);
+
+        let topology = topology(&components).unwrap();
+        assert_eq!(
+            topology,
+            Topology {
+                integrated: 0,
+                discrete: 1,
+                panel: Some(0),
+            }
+        );
+        assert_eq!(
+            topology.describe(&components),
+            vec![
+                "Integrated GPU: 8086 5917 (00:02.0)",
+                "Discrete GPU: 10de 1d12 (01:00.0)",
+                "Panel driven by: 8086 5917 (00:02.0) via eDP-1",
+                "Discrete GPU power: D3cold, runtime suspended, runtime PM enabled",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn single_gpu_is_not_hybrid() {
+        let mut components = vec![gpu("00:02.0", 0x8086, 0x5917)];
+        inspect(Path::new("/nonexistent"), &mut components).await;
+        assert!(components[0].gpu.is_some());
+        assert_eq!(topology(&components), None);
+    }
+
+    #[test]
+    fn classifies_amd_apus_by_boot_vga() {
+        assert_eq!(classify(0x1002, 0x1638, true, false), GpuKind::Integrated);
+        assert_eq!(classify(0x1002, 0x73df, false, false), GpuKind::Discrete);
+        assert_eq!(classify(0x1002, 0x73df, true, true), GpuKind::Discrete);
+        assert_eq!(classify(0x8086, 0x56a0, false, true), GpuKind::Discrete);
+    }
+
+    #[test]
+    fn profile_files() {
+        assert!(remove_dgpu_rules(0x10de, 0x1d12)
+            .contains("ATTR{vendor}==\"0x10de\", ATTR{device}==\"0x1d12\""));
+        assert!(primary_gpu_conf("nvidia", "nvidia-drm")
+            .contains("    MatchDriver \"nvidia-drm\"\n    Driver \"nvidia\"\n"));
+        assert!(!primary_gpu_conf("amdgpu", "amdgpu").contains("ModulePath"));
+    }
+}
@@ -0,0 +1,269 @@
+//! Vendor, device and class name lookups from hwdata's `pci.ids` and `usb.ids`
+
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+
+const HWDATA_DIR: &str = "usr/share/hwdata";
+
+// Compact fallback for systems without the hwdata package
+const EMBEDDED_PCI_IDS: &str = include_str!("data/pci.ids");
+const EMBEDDED_USB_IDS: &str = include_str!("data/usb.ids");
+
+#[derive(Debug, Default)]
+pub struct IdDatabase {
+    vendors: HashMap<u16, VendorEntry>,
+    classes: HashMap<u8, ClassEntry>,
+}
+
+#[derive(Debug, Default)]
+struct VendorEntry {
+    name: String,
+    devices: HashMap<u16, DeviceEntry>,
+}
+
+#[derive(Debug, Default)]
+struct DeviceEntry {
+    name: String,
+    subsystems: HashMap<(u16, u16), String>,
+}
+
+#[derive(Debug, Default)]
+struct ClassEntry {
+    name: String,
+    subclasses: HashMap<u8, String>,
+}
+
+enum Section {
+    Vendors,
+    Classes,
+    Other,
+}
+
+impl IdDatabase {
+    /// Parses the shared `pci.ids`/`usb.ids` format, skipping sections it does not use.
+    pub fn parse(text: &str) -> Self {
+        let mut db = IdDatabase::default();
+        let mut section = Section::Vendors;
+        let mut vendor: Option<u16> = None;
+        let mut device: Option<u16> = None;
+        let mut class: Option<u8> = None;
+
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let depth = line.chars().take_while(|&c| c == '\t').count();
+            let line = &line[depth..];
+
+            if depth == 0 {
+                vendor = None;
+                device = None;
+                class = None;
+
+                if let Some(rest) = line.strip_prefix("C ") {
+                    section = Section::Classes;
+                    if let Some((id, name)) = split_entry(rest) {
+                        if let Ok(id) = u8::from_str_radix(id, 16) {
+                            db.classes.insert(
+                                id,
+                                ClassEntry {
+                                    name,
+                                    ..Default::default()
+                                },
+                            );
+                            class = Some(id);
+                        }
+                    }
+                    continue;
+                }
+
+                match split_entry(line).and_then(|(id, name)| Some((parse_u16(id)?, name))) {
+                    Some((id, name)) if id_len(line) == 4 => {
+                        section = Section::Vendors;
+                        db.vendors.insert(
+                            id,
+                            VendorEntry {
+                                name,
+                                ..Default::default()
+                            },
+                        );
+                        vendor = Some(id);
+                    }
+                    // usb.ids has further sections (AT, HID, R, ...) we do not need
+                    _ => section = Section::Other,
+                }
+                continue;
+            }
+
+            match section {
+                Section::Vendors => {
+                    let vendor = match vendor.and_then(|v| db.vendors.get_mut(&v)) {
+                        Some(vendor) => vendor,
+                        None => continue,
+                    };
+                    if depth == 1 {
+                        if let Some((id, name)) = split_entry(line) {
+                            if let Some(id) = parse_u16(id) {
+                                vendor.devices.insert(
+                                    id,
+                                    DeviceEntry {
+                                        name,
+                                        ..Default::default()
+                                    },
+                                );
+                                device = Some(id);
+                            }
+                        }
+                    } else if let Some(entry) = device.and_then(|d| vendor.devices.get_mut(&d)) {
+                        // Subsystem lines: `\t\t<subvendor> <subdevice>  <name>`
+                        if let Some((ids, name)) = split_entry(line) {
+                            if let Some((sv, sd)) = ids.split_once(' ') {
+                                if let (Some(sv), Some(sd)) = (parse_u16(sv), parse_u16(sd)) {
+                                    entry.subsystems.insert((sv, sd), name);
+                                }
+                            }
+                        }
+                    }
+                }
+                Section::Classes if depth == 1 => {
+                    if let Some(entry) = class.and_then(|c| db.classes.get_mut(&c)) {
+                        if let Some((id, name)) = split_entry(line) {
+                            if let Ok(id) = u8::from_str_radix(id, 16) {
+                                entry.subclasses.insert(id, name);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        db
+    }
+
+    pub fn vendor(&self, vendor: u16) -> Option<&str> {
+        self.vendors.get(&vendor).map(|v| v.name.as_str())
+    }
+
+    pub fn device(&self, vendor: u16, device: u16) -> Option<&str> {
+        self.vendors
+            .get(&vendor)?
+            .devices
+            .get(&device)
+            .map(|d| d.name.as_str())
+    }
+
+    pub fn subsystem(
+        &self,
+        vendor: u16,
+        device: u16,
+        subsystem_vendor: u16,
+        subsystem_device: u16,
+    ) -> Option<&str> {
+        self.vendors
+            .get(&vendor)?
+            .devices
+            .get(&device)?
+            .subsystems
+            .get(&(subsystem_vendor, subsystem_device))
+            .map(|s| s.as_str())
+    }
+
+    /// Subclass name, falling back to the class name when the subclass is not listed.
+    pub fn subclass(&self, class: u8, subclass: u8) -> Option<&str> {
+        let entry = self.classes.get(&class)?;
+        Some(
+            entry
+                .subclasses
+                .get(&subclass)
+                .map(|s| s.as_str())
+                .unwrap_or(&entry.name),
+        )
+    }
+}
+
+/// Name lookups for both buses, shared by detection, display and reports.
+#[derive(Debug, Default)]
+pub struct IdResolver {
+    pub pci: IdDatabase,
+    pub usb: IdDatabase,
+}
+
+impl IdResolver {
+    /// Loads the system hwdata files, using the embedded database for any that are missing.
+    pub async fn load(root: &Path) -> Self {
+        Self::load_from(&root.join(HWDATA_DIR)).await
+    }
+
+    pub async fn load_from(dir: &Path) -> Self {
+        let pci = fs::read_to_string(dir.join("pci.ids")).await;
+        let usb = fs::read_to_string(dir.join("usb.ids")).await;
+
+        Self {
+            pci: IdDatabase::parse(pci.as_deref().unwrap_or(EMBEDDED_PCI_IDS)),
+            usb: IdDatabase::parse(usb.as_deref().unwrap_or(EMBEDDED_USB_IDS)),
+        }
+    }
+}
+
+/// Splits `"8086  Intel Corporation"` into the ID part and the name.
+fn split_entry(line: &str) -> Option<(&str, String)> {
+    let (id, name) = line.split_once("  ")?;
+    Some((id.trim(), name.trim().to_string()))
+}
+
+fn id_len(line: &str) -> usize {
+    line.split_whitespace().next().map(str::len).unwrap_or(0)
+}
+
+fn parse_u16(value: &str) -> Option<u16> {
+    u16::from_str_radix(value, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_embedded_pci_names() {
+        let db = IdDatabase::parse(EMBEDDED_PCI_IDS);
+        assert_eq!(db.vendor(0x8086), Some("Intel Corporation"));
+        assert_eq!(db.device(0x10de, 0x2204), Some("GA102 [GeForce RTX 3090]"));
+        assert_eq!(
+            db.subsystem(0x8086, 0x5917, 0x17aa, 0x225d),
+            Some("ThinkPad T480")
+        );
+        assert_eq!(db.subsystem(0x8086, 0x5917, 0x17aa, 0x0000), None);
+        assert_eq!(db.subclass(0x03, 0x00), Some("VGA compatible controller"));
+        assert_eq!(db.subclass(0x0d, 0x40), Some("Wireless controller"));
+        assert_eq!(db.vendor(0xdead), None);
+    }
+
+    #[test]
+    fn resolves_embedded_usb_names() {
+        let db = IdDatabase::parse(EMBEDDED_USB_IDS);
+        assert_eq!(db.vendor(0x8087), Some("Intel Corp."));
+        assert_eq!(db.device(0x1d6b, 0x0003), Some("3.0 root hub"));
+        assert_eq!(db.subclass(0x0e, 0x01), Some("Video Control"));
+        assert_eq!(db.subclass(0x09, 0x00), Some("Unused"));
+    }
+
+    #[test]
+    fn skips_unused_usb_sections() {
+        let db = IdDatabase::parse(
+            "1234  Acme\n\t0001  Widget\nAT 0001  Standard AC-3\n\t0002  Not a device\nHID 00  Undefined\n",
+        );
+        assert_eq!(db.device(0x1234, 0x0001), Some("Widget"));
+        assert_eq!(db.device(0x1234, 0x0002), None);
+        assert_eq!(db.vendors.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_embedded_database() {
+        let resolver = IdResolver::load_from(Path::new("/nonexistent/hwdata")).await;
+        assert_eq!(resolver.pci.vendor(0x10de), Some("NVIDIA Corporation"));
+        assert_eq!(resolver.usb.vendor(0x06cb), Some("Synaptics, Inc."));
+    }
+}
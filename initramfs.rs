@@ -0,0 +1,426 @@
+//! Initramfs generator detection, early module configuration and regeneration
+//!
+//! Supports mkinitcpio (the Arch default), dracut and booster. All paths are
+//! resolved below a configurable root so detection can run against fixtures.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generator {
+    Mkinitcpio,
+    Dracut,
+    Booster,
+}
+
+/// An installed kernel, identified by its `/usr/lib/modules/<version>/pkgbase`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kernel {
+    pub pkgbase: String,
+    pub version: String,
+}
+
+impl Generator {
+    pub fn name(self) -> &'static str {
+        match self {
+            Generator::Mkinitcpio => "mkinitcpio",
+            Generator::Dracut => "dracut",
+            Generator::Booster => "booster",
+        }
+    }
+
+    /// Configuration file that lists modules to force into the image.
+    pub fn config_path(self) -> &'static str {
+        match self {
+            Generator::Mkinitcpio => "/etc/mkinitcpio.conf",
+            Generator::Dracut => "/etc/dracut.conf.d/ardenthat.conf",
+            Generator::Booster => "/etc/booster.yaml",
+        }
+    }
+
+    /// Adds `modules` to the generator configuration, returning `None` if nothing changes.
+    pub fn add_modules(self, config: &str, modules: &[String]) -> Option<String> {
+        match self {
+            Generator::Mkinitcpio => add_mkinitcpio_modules(config, modules),
+            Generator::Dracut => add_dracut_drivers(config, modules),
+            Generator::Booster => add_booster_modules(config, modules),
+        }
+    }
+
+    /// Commands that rebuild the images for every installed kernel.
+    pub fn regenerate_commands(self, kernels: &[Kernel]) -> Vec<Vec<String>> {
+        match self {
+            // -P walks every preset in /etc/mkinitcpio.d, one per kernel package
+            Generator::Mkinitcpio => vec![vec!["mkinitcpio".to_string(), "-P".to_string()]],
+            Generator::Dracut => kernels
+                .iter()
+                .map(|kernel| {
+                    vec![
+                        "dracut".to_string(),
+                        "--force".to_string(),
+                        format!("/boot/initramfs-{}.img", kernel.pkgbase),
+                        kernel.version.clone(),
+                    ]
+                })
+                .collect(),
+            Generator::Booster => kernels
+                .iter()
+                .map(|kernel| {
+                    vec![
+                        "booster".to_string(),
+                        "build".to_string(),
+                        "--force".to_string(),
+                        "--kernel-version".to_string(),
+                        kernel.version.clone(),
+                        format!("/boot/booster-{}.img", kernel.pkgbase),
+                    ]
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Picks the installed generator, preferring one with its own configuration
+/// when several are installed side by side.
+pub async fn detect_generator(root: &Path) -> Option<Generator> {
+    let installed = |name: &str| root.join("usr/bin").join(name).exists();
+    let has_mkinitcpio = installed("mkinitcpio");
+    let has_dracut = installed("dracut");
+    let has_booster = installed("booster");
+
+    if has_booster && root.join("etc/booster.yaml").exists() {
+        return Some(Generator::Booster);
+    }
+    if has_dracut && dir_has_entries(&root.join("etc/dracut.conf.d")).await {
+        return Some(Generator::Dracut);
+    }
+    if has_mkinitcpio {
+        return Some(Generator::Mkinitcpio);
+    }
+    if has_dracut {
+        return Some(Generator::Dracut);
+    }
+    has_booster.then_some(Generator::Booster)
+}
+
+/// Lists installed kernels from `<root>/usr/lib/modules/*/pkgbase`.
+pub async fn installed_kernels(root: &Path) -> Result<Vec<Kernel>> {
+    let dir = root.join("usr/lib/modules");
+    let mut entries = fs::read_dir(&dir)
+        .await
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+
+    let mut kernels = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        // Leftover directories of removed kernels have no pkgbase
+        if let Ok(pkgbase) = fs::read_to_string(entry.path().join("pkgbase")).await {
+            kernels.push(Kernel {
+                pkgbase: pkgbase.trim().to_string(),
+                version: entry.file_name().to_string_lossy().into_owned(),
+            });
+        }
+    }
+    kernels.sort_by(|a, b| a.pkgbase.cmp(&b.pkgbase));
+    Ok(kernels)
+}
+
+/// Path of the backup written before a configuration file is first modified.
+pub fn backup_path(path: &str) -> String {
+    format!("{}.ardenthat.bak", path)
+}
+
+async fn dir_has_entries(dir: &Path) -> bool {
+    match fs::read_dir(dir).await {
+        Ok(mut entries) => matches!(entries.next_entry().await, Ok(Some(_))),
+        Err(_) => false,
+    }
+}
+
+fn missing_modules<'a>(present: &[&str], modules: &'a [String]) -> Vec<&'a str> {
+    let mut missing: Vec<&str> = Vec::new();
+    for module in modules {
+        if !present.contains(&module.as_str()) && !missing.contains(&module.as_str()) {
+            missing.push(module);
+        }
+    }
+    missing
+}
+
+/// Extends the `MODULES=(...)` array in mkinitcpio.conf.
+fn add_mkinitcpio_modules(config: &str, modules: &[String]) -> Option<String> {
+    let mut found = false;
+    let mut changed = false;
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in config.lines() {
+        let trimmed = line.trim_start();
+        if !found && trimmed.starts_with("MODULES=(") {
+            found = true;
+            let inner = trimmed
+                .trim_start_matches("MODULES=(")
+                .split(')')
+                .next()
+                .unwrap_or("");
+            let present: Vec<&str> = inner.split_whitespace().collect();
+            let missing = missing_modules(&present, modules);
+            if !missing.is_empty() {
+                changed = true;
+                let all: Vec<&str> = present.iter().copied().chain(missing).collect();
+                lines.push(format!("MODULES=({})", all.join(" ")));
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+
+    if !found {
+        lines.push(format!("MODULES=({})", modules.join(" ")));
+        changed = true;
+    }
+    changed.then(|| lines.join("\n") + "\n")
+}
+
+/// Inserts the `microcode` hook after `autodetect` in mkinitcpio's `HOOKS=(...)`,
+/// so later hooks only see the host CPU's updates. Returns `None` if the hook is
+/// already there or there is no `HOOKS` array to extend.
+pub fn add_microcode_hook(config: &str) -> Option<String> {
+    let mut changed = false;
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in config.lines() {
+        if let (false, Some(mut hooks)) = (changed, hooks_array(line)) {
+            if hooks.contains(&"microcode") {
+                return None;
+            }
+            let at = ["autodetect", "systemd", "udev", "base"]
+                .iter()
+                .find_map(|after| hooks.iter().position(|h| h == after))
+                .map_or(0, |i| i + 1);
+            hooks.insert(at, "microcode");
+            lines.push(format!("HOOKS=({})", hooks.join(" ")));
+            changed = true;
+            continue;
+        }
+        lines.push(line.to_string());
+    }
+    changed.then(|| lines.join("\n") + "\n")
+}
+
+/// Hooks of the first `HOOKS=(...)` array in mkinitcpio.conf.
+pub fn mkinitcpio_hooks(config: &str) -> Vec<&str> {
+    config.lines().find_map(hooks_array).unwrap_or_default()
+}
+
+fn hooks_array(line: &str) -> Option<Vec<&str>> {
+    let inner = line.trim_start().strip_prefix("HOOKS=(")?;
+    Some(
+        inner
+            .split(')')
+            .next()
+            .unwrap_or("")
+            .split_whitespace()
+            .collect(),
+    )
+}
+
+/// Maintains an `add_drivers+=" ... "` line in ardenthat's dracut drop-in.
+fn add_dracut_drivers(config: &str, modules: &[String]) -> Option<String> {
+    let present: Vec<&str> = config
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("add_drivers+="))
+        .flat_map(|value| value.trim_matches('"').split_whitespace())
+        .collect();
+    let missing = missing_modules(&present, modules);
+    if missing.is_empty() {
+        return None;
+    }
+
+    let mut contents = config.to_string();
+    if contents.is_empty() {
+        contents.push_str("# Early modules added by ardenthat\n");
+    } else if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&format!("add_drivers+=\" {} \"\n", missing.join(" ")));
+    Some(contents)
+}
+
+/// Extends the comma separated `modules_force_load` key in booster.yaml.
+fn add_booster_modules(config: &str, modules: &[String]) -> Option<String> {
+    let mut found = false;
+    let mut changed = false;
+    let mut lines: Vec<String> = Vec::new();
+
+    for line in config.lines() {
+        if let Some(value) = line.strip_prefix("modules_force_load:") {
+            found = true;
+            let present: Vec<&str> = value
+                .split(',')
+                .map(str::trim)
+                .filter(|m| !m.is_empty())
+                .collect();
+            let missing = missing_modules(&present, modules);
+            if !missing.is_empty() {
+                changed = true;
+                let all: Vec<&str> = present.iter().copied().chain(missing).collect();
+                lines.push(format!("modules_force_load: {}", all.join(",")));
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+
+    if !found {
+        lines.push(format!("modules_force_load: {}", modules.join(",")));
+        changed = true;
+    }
+    changed.then(|| lines.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn modules(names: &[&str]) -> Vec<String> {
+        names.iter().map(|m| m.to_string()).collect()
+    }
+
+    fn kernel(pkgbase: &str, version: &str) -> Kernel {
+        Kernel {
+            pkgbase: pkgbase.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    #[test]
+    fn extends_mkinitcpio_modules_array() {
+        let config = "# vim:set ft=sh\nMODULES=(btrfs)\nBINARIES=()\nHOOKS=(base udev)\n";
+        let updated = Generator::Mkinitcpio
+            .add_modules(config, &modules(&["nvidia", "nvidia_drm", "btrfs"]))
+            .unwrap();
+        assert_eq!(
+            updated,
+            "# vim:set ft=sh\nMODULES=(btrfs nvidia nvidia_drm)\nBINARIES=()\nHOOKS=(base udev)\n"
+        );
+        assert_eq!(
+            Generator::Mkinitcpio.add_modules(&updated, &modules(&["nvidia"])),
+            None
+        );
+
+        // Commented examples are left alone
+        let updated = Generator::Mkinitcpio
+            .add_modules("#MODULES=(piix)\nMODULES=()\n", &modules(&["i915"]))
+            .unwrap();
+        assert_eq!(updated, "#MODULES=(piix)\nMODULES=(i915)\n");
+    }
+
+    #[test]
+    fn inserts_microcode_hook_after_autodetect() {
+        let config = "MODULES=()\nHOOKS=(base udev autodetect modconf block filesystems fsck)\n";
+        assert_eq!(
+            add_microcode_hook(config).unwrap(),
+            "MODULES=()\nHOOKS=(base udev autodetect microcode modconf block filesystems fsck)\n"
+        );
+        assert_eq!(
+            add_microcode_hook("HOOKS=(base systemd microcode autodetect)\n"),
+            None
+        );
+        assert_eq!(add_microcode_hook("MODULES=()\n"), None);
+    }
+
+    #[test]
+    fn appends_dracut_drivers() {
+        let created = Generator::Dracut
+            .add_modules("", &modules(&["amdgpu"]))
+            .unwrap();
+        assert_eq!(
+            created,
+            "# Early modules added by ardenthat\nadd_drivers+=\" amdgpu \"\n"
+        );
+        assert_eq!(
+            Generator::Dracut.add_modules(&created, &modules(&["amdgpu"])),
+            None
+        );
+        assert!(Generator::Dracut
+            .add_modules(&created, &modules(&["amdgpu", "i915"]))
+            .unwrap()
+            .ends_with("add_drivers+=\" amdgpu \"\nadd_drivers+=\" i915 \"\n"));
+    }
+
+    #[test]
+    fn extends_booster_force_load() {
+        let config = "compression: zstd\nmodules_force_load: btrfs\n";
+        let updated = Generator::Booster
+            .add_modules(config, &modules(&["nvidia", "nvidia_drm"]))
+            .unwrap();
+        assert_eq!(
+            updated,
+            "compression: zstd\nmodules_force_load: btrfs,nvidia,nvidia_drm\n"
+        );
+
+        let added = Generator::Booster
+            .add_modules("compression: zstd\n", &modules(&["i915"]))
+            .unwrap();
+        assert_eq!(added, "compression: zstd\nmodules_force_load: i915\n");
+    }
+
+    #[test]
+    fn regenerates_every_kernel() {
+        let kernels = [
+            kernel("linux", "6.11.5-arch1-1"),
+            kernel("linux-lts", "6.6.58-1-lts"),
+        ];
+        assert_eq!(
+            Generator::Mkinitcpio.regenerate_commands(&kernels),
+            vec![vec!["mkinitcpio", "-P"]]
+        );
+        assert_eq!(
+            Generator::Dracut.regenerate_commands(&kernels)[1],
+            vec![
+                "dracut",
+                "--force",
+                "/boot/initramfs-linux-lts.img",
+                "6.6.58-1-lts"
+            ]
+        );
+        assert_eq!(
+            Generator::Booster.regenerate_commands(&kernels)[0],
+            vec![
+                "booster",
+                "build",
+                "--force",
+                "--kernel-version",
+                "6.11.5-arch1-1",
+                "/boot/booster-linux.img"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn detects_generator_and_kernels() {
+        let root = TempTree::new("initramfs-root");
+        root.write("usr/bin/mkinitcpio", "");
+        root.write("usr/bin/dracut", "");
+        root.write("usr/lib/modules/6.11.5-arch1-1/pkgbase", "linux\n");
+        root.write("usr/lib/modules/6.6.58-1-lts/pkgbase", "linux-lts\n");
+        root.write("usr/lib/modules/6.10.0-arch1-1/modules.dep", "");
+
+        assert_eq!(
+            detect_generator(root.path()).await,
+            Some(Generator::Mkinitcpio)
+        );
+        root.write("etc/dracut.conf.d/10-custom.conf", "hostonly=\"yes\"\n");
+        assert_eq!(detect_generator(root.path()).await, Some(Generator::Dracut));
+
+        let kernels = installed_kernels(root.path()).await.unwrap();
+        assert_eq!(
+            kernels,
+            vec![
+                kernel("linux", "6.11.5-arch1-1"),
+                kernel("linux-lts", "6.6.58-1-lts")
+            ]
+        );
+    }
+}
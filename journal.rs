@@ -0,0 +1,489 @@
+//! Rollback journal of setup transactions under `/var/lib/ardenthat/transactions`
+//!
+//! Each transaction is a JSON file named after its sequence number. Steps are
+//! recorded before they run, so an interrupted setup can still be undone.
+
+use crate::command::CommandLine;
+use crate::packages::PackageDatabase;
+use crate::snapshot::Snapshot;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+const JOURNAL_DIR: &str = "var/lib/ardenthat/transactions";
+const PACKAGE_CACHE: &str = "var/cache/pacman/pkg";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageChange {
+    pub name: String,
+    /// Installed version before the transaction, `None` if it was not installed
+    pub previous_version: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileRecord {
+    pub path: String,
+    /// Contents before the transaction, `None` if setup created the file
+    pub previous: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Transaction {
+    pub id: u32,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// Filesystem snapshot taken before any change
+    #[serde(default)]
+    pub snapshot: Option<Snapshot>,
+    #[serde(default)]
+    pub installed: Vec<PackageChange>,
+    #[serde(default)]
+    pub removed: Vec<PackageChange>,
+    #[serde(default)]
+    pub files: Vec<FileRecord>,
+    #[serde(default)]
+    pub blacklisted: Vec<String>,
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// Whether the initramfs was regenerated
+    #[serde(default)]
+    pub initramfs: bool,
+    /// Commands that regenerated bootloader configuration, rerun after the
+    /// files they read are restored
+    #[serde(default)]
+    pub bootloader: Vec<CommandLine>,
+    #[serde(default)]
+    pub undone: bool,
+}
+
+pub struct Journal {
+    dir: PathBuf,
+}
+
+impl Journal {
+    /// Journal of the system mounted at `root`.
+    pub fn new(root: &Path) -> Self {
+        Self {
+            dir: root.join(JOURNAL_DIR),
+        }
+    }
+
+    /// All recorded transactions, oldest first.
+    pub async fn list(&self) -> Result<Vec<Transaction>> {
+        let mut transactions = Vec::new();
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(_) => return Ok(transactions),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let text = fs::read_to_string(&path)
+                    .await
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let transaction: Transaction = serde_json::from_str(&text)
+                    .with_context(|| format!("Invalid journal entry {}", path.display()))?;
+                transactions.push(transaction);
+            }
+        }
+        transactions.sort_by_key(|t| t.id);
+        Ok(transactions)
+    }
+
+    /// Starts a new transaction numbered after the last recorded one.
+    pub async fn begin(&self) -> Result<Transaction> {
+        let id = self.list().await?.last().map(|t| t.id + 1).unwrap_or(1);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Ok(Transaction {
+            id,
+            timestamp,
+            ..Default::default()
+        })
+    }
+
+    pub async fn save(&self, transaction: &Transaction) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let path = self.path(transaction.id);
+        let text = serde_json::to_string_pretty(transaction)?;
+        fs::write(&path, text)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn path(&self, id: u32) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+impl Transaction {
+    pub fn is_empty(&self) -> bool {
+        self.installed.is_empty()
+            && self.removed.is_empty()
+            && self.files.is_empty()
+            && self.blacklisted.is_empty()
+            && self.services.is_empty()
+            && !self.initramfs
+            && self.bootloader.is_empty()
+    }
+
+    /// Package files of the removed packages' previous versions that are still in
+    /// pacman's cache, by package name.
+    pub async fn cached_packages(&self, root: &Path) -> BTreeMap<String, String> {
+        let mut cached = BTreeMap::new();
+        for package in &self.removed {
+            let Some(version) = &package.previous_version else {
+                continue;
+            };
+            if let Some(path) = cached_package(root, &package.name, version).await {
+                cached.insert(package.name.clone(), path);
+            }
+        }
+        cached
+    }
+
+    /// How undo reinstalls each removed package, given [`Self::cached_packages`].
+    pub fn restore_summary(&self, cached: &BTreeMap<String, String>) -> Vec<String> {
+        self.removed
+            .iter()
+            .map(
+                |package| match (&package.previous_version, cached.get(&package.name)) {
+                    (Some(version), Some(_)) => {
+                        format!(
+                            "{} {} is reinstalled from the package cache",
+                            package.name, version
+                        )
+                    }
+                    (Some(version), None) => format!(
+                        "{} {} is not in the package cache, the repository version is installed",
+                        package.name, version
+                    ),
+                    (None, _) => format!("{} is installed from the repositories", package.name),
+                },
+            )
+            .collect()
+    }
+
+    /// Commands that revert this transaction, in order: files, services, packages,
+    /// then `regenerate` if the initramfs was rebuilt and the bootloader
+    /// configuration.
+    ///
+    /// Packages that are no longer installed are not removed again. Removed
+    /// packages come back from the `cached` files of their previous versions, or
+    /// from the repositories without one.
+    pub fn undo_commands(
+        &self,
+        packages: Option<&PackageDatabase>,
+        cached: &BTreeMap<String, String>,
+        regenerate: Vec<Vec<String>>,
+    ) -> Vec<CommandLine> {
+        let mut commands = Vec::new();
+
+        for file in self.files.iter().rev() {
+            commands.push(match &file.previous {
+                Some(contents) => {
+                    CommandLine::new(["tee", file.path.as_str()]).stdin(contents.as_str())
+                }
+                None => CommandLine::new(["rm", "-f", file.path.as_str()]),
+            });
+        }
+
+        if !self.services.is_empty() {
+            let mut argv = vec!["systemctl", "disable"];
+            argv.extend(self.services.iter().map(String::as_str));
+            commands.push(CommandLine::new(argv));
+        }
+
+        let installed: Vec<&str> = self
+            .installed
+            .iter()
+            .filter(|p| p.previous_version.is_none())
+            .map(|p| p.name.as_str())
+            .filter(|name| packages.is_none_or(|db| db.is_installed(name)))
+            .collect();
+        if !installed.is_empty() {
+            let mut argv = vec!["pacman", "-Rns", "--noconfirm"];
+            argv.extend(installed);
+            commands.push(CommandLine::new(argv));
+        }
+
+        let (restored, reinstalled): (Vec<&PackageChange>, Vec<&PackageChange>) = self
+            .removed
+            .iter()
+            .partition(|p| cached.contains_key(&p.name));
+        if !restored.is_empty() {
+            let mut argv = vec!["pacman", "-U", "--noconfirm"];
+            argv.extend(restored.iter().map(|p| cached[&p.name].as_str()));
+            commands.push(CommandLine::new(argv));
+        }
+        if !reinstalled.is_empty() {
+            let mut argv = vec!["pacman", "-S", "--needed", "--noconfirm"];
+            argv.extend(reinstalled.iter().map(|p| p.name.as_str()));
+            commands.push(CommandLine::new(argv));
+        }
+
+        if self.initramfs {
+            commands.extend(regenerate.into_iter().map(CommandLine::new));
+        }
+        commands.extend(self.bootloader.iter().cloned());
+        commands
+    }
+
+    /// One-line description for `undo --list`.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.installed.is_empty() {
+            let names: Vec<&str> = self.installed.iter().map(|p| p.name.as_str()).collect();
+            parts.push(format!("installed {}", names.join(" ")));
+        }
+        if !self.removed.is_empty() {
+            let names: Vec<&str> = self.removed.iter().map(|p| p.name.as_str()).collect();
+            parts.push(format!("removed {}", names.join(" ")));
+        }
+        if !self.files.is_empty() {
+            let paths: Vec<&str> = self.files.iter().map(|f| f.path.as_str()).collect();
+            parts.push(format!("wrote {}", paths.join(" ")));
+        }
+        if !self.blacklisted.is_empty() {
+            parts.push(format!("blacklisted {}", self.blacklisted.join(" ")));
+        }
+        if !self.services.is_empty() {
+            parts.push(format!("enabled {}", self.services.join(" ")));
+        }
+        for command in &self.bootloader {
+            parts.push(format!("ran {}", command));
+        }
+        if let Some(snapshot) = &self.snapshot {
+            parts.push(format!(
+                "{} snapshot {}",
+                snapshot.backend.name(),
+                snapshot.id
+            ));
+        }
+
+        format!(
+            "{:>4}  {}  {}{}",
+            self.id,
+            format_timestamp(self.timestamp),
+            parts.join("; "),
+            if self.undone { " (undone)" } else { "" }
+        )
+    }
+
+    /// Whether both transactions changed the same file or package.
+    pub fn overlaps(&self, other: &Transaction) -> bool {
+        self.files
+            .iter()
+            .any(|f| other.files.iter().any(|o| o.path == f.path))
+            || self.installed.iter().chain(&self.removed).any(|p| {
+                other
+                    .installed
+                    .iter()
+                    .chain(&other.removed)
+                    .any(|o| o.name == p.name)
+            })
+    }
+}
+
+/// Absolute path of the cached package file of `name` at `version`, signatures
+/// aside. File names are `<name>-<pkgver>-<pkgrel>-<arch>.pkg.tar.<ext>`.
+async fn cached_package(root: &Path, name: &str, version: &str) -> Option<String> {
+    let prefix = format!("{}-{}-", name, version);
+    let mut entries = fs::read_dir(root.join(PACKAGE_CACHE)).await.ok()?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let file = entry.file_name().to_string_lossy().into_owned();
+        let Some(rest) = file.strip_prefix(&prefix) else {
+            continue;
+        };
+        if !rest.contains('-') && rest.contains(".pkg.tar.") && !rest.ends_with(".sig") {
+            return Some(format!("/{}/{}", PACKAGE_CACHE, file));
+        }
+    }
+    None
+}
+
+/// `YYYY-MM-DD HH:MM UTC` for a Unix timestamp.
+fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86_400) as i64;
+    let seconds = timestamp % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn transaction() -> Transaction {
+        Transaction {
+            id: 1,
+            timestamp: 1_760_451_012,
+            installed: vec![
+                PackageChange {
+                    name: "nvidia-open".to_string(),
+                    previous_version: None,
+                },
+                PackageChange {
+                    name: "nvidia-utils".to_string(),
+                    previous_version: None,
+                },
+            ],
+            files: vec![
+                FileRecord {
+                    path: "/etc/mkinitcpio.conf".to_string(),
+                    previous: Some("MODULES=()\n".to_string()),
+                },
+                FileRecord {
+                    path: "/etc/modprobe.d/ardenthat-blacklist.conf".to_string(),
+                    previous: None,
+                },
+            ],
+            blacklisted: vec!["nouveau".to_string()],
+            services: vec!["nvidia-persistenced".to_string()],
+            initramfs: true,
+            bootloader: vec![CommandLine::new([
+                "grub-mkconfig",
+                "-o",
+                "/boot/grub/grub.cfg",
+            ])],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn undo_reverts_files_then_packages_then_initramfs() {
+        let db = PackageDatabase::parse("nvidia-open 575.64-1\n", "");
+        let commands: Vec<String> = transaction()
+            .undo_commands(
+                Some(&db),
+                &BTreeMap::new(),
+                vec![vec!["mkinitcpio".to_string(), "-P".to_string()]],
+            )
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "rm -f /etc/modprobe.d/ardenthat-blacklist.conf",
+                "tee /etc/mkinitcpio.conf",
+                "systemctl disable nvidia-persistenced",
+                "pacman -Rns --noconfirm nvidia-open",
+                "mkinitcpio -P",
+                "grub-mkconfig -o /boot/grub/grub.cfg",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_packages_come_back_at_their_previous_version() {
+        let root = TempTree::new("journal-restore");
+        let cache = "var/cache/pacman/pkg";
+        root.write(
+            &format!("{}/nvidia-575.64.05-3-x86_64.pkg.tar.zst", cache),
+            "",
+        );
+        root.write(
+            &format!("{}/nvidia-575.64.05-3-x86_64.pkg.tar.zst.sig", cache),
+            "",
+        );
+        root.write(
+            &format!("{}/nvidia-utils-575.64.05-3-x86_64.pkg.tar.zst", cache),
+            "",
+        );
+        let transaction = Transaction {
+            removed: vec![
+                PackageChange {
+                    name: "nvidia".to_string(),
+                    previous_version: Some("575.64.05-3".to_string()),
+                },
+                PackageChange {
+                    name: "xf86-video-nouveau".to_string(),
+                    previous_version: Some("1.0.18-1".to_string()),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let cached = transaction.cached_packages(root.path()).await;
+        let commands: Vec<String> = transaction
+            .undo_commands(None, &cached, Vec::new())
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "pacman -U --noconfirm /var/cache/pacman/pkg/nvidia-575.64.05-3-x86_64.pkg.tar.zst",
+                "pacman -S --needed --noconfirm xf86-video-nouveau",
+            ]
+        );
+        assert_eq!(
+            transaction.restore_summary(&cached),
+            vec![
+                "nvidia 575.64.05-3 is reinstalled from the package cache",
+                "xf86-video-nouveau 1.0.18-1 is not in the package cache, \
+                 the repository version is installed",
+            ]
+        );
+    }
+
+    #[test]
+    fn summarizes_transactions() {
+        assert_eq!(
+            transaction().summary(),
+            "   1  2025-10-14 14:10 UTC  installed nvidia-open nvidia-utils; \
+             wrote /etc/mkinitcpio.conf /etc/modprobe.d/ardenthat-blacklist.conf; \
+             blacklisted nouveau; enabled nvidia-persistenced; ran grub-mkconfig -o /boot/grub/grub.cfg"
+        );
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00 UTC");
+    }
+
+    #[tokio::test]
+    async fn numbers_and_reloads_transactions() {
+        let root = TempTree::new("journal");
+        let journal = Journal::new(root.path());
+        assert!(journal.list().await.unwrap().is_empty());
+
+        let first = journal.begin().await.unwrap();
+        assert_eq!(first.id, 1);
+        journal.save(&transaction()).await.unwrap();
+
+        let second = journal.begin().await.unwrap();
+        assert_eq!(second.id, 2);
+        journal.save(&second).await.unwrap();
+
+        let listed = journal.list().await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].files, transaction().files);
+        assert!(listed[0].overlaps(&transaction()));
+        assert!(!listed[1].overlaps(&listed[0]));
+    }
+}
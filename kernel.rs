@@ -0,0 +1,304 @@
+//! Installed kernel variants and the kernel module packages that fit them
+//!
+//! Out-of-tree modules are packaged either prebuilt for one kernel (`nvidia-open`
+//! for `linux`, `nvidia-open-lts` for `linux-lts`) or as a `-dkms` package that
+//! builds them for every kernel whose `-headers` package is installed.
+
+use crate::initramfs::Kernel;
+use crate::packages::PackageDatabase;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Stock,
+    Lts,
+    Zen,
+    Hardened,
+    Rt,
+    /// Any other `linux-*` package, usually from the AUR or built locally
+    Custom,
+}
+
+impl Variant {
+    pub fn of(pkgbase: &str) -> Self {
+        match pkgbase {
+            "linux" => Variant::Stock,
+            "linux-lts" => Variant::Lts,
+            "linux-zen" => Variant::Zen,
+            "linux-hardened" => Variant::Hardened,
+            "linux-rt" | "linux-rt-lts" => Variant::Rt,
+            _ => Variant::Custom,
+        }
+    }
+}
+
+/// Package with the headers DKMS needs to build modules for `kernel`.
+pub fn headers(kernel: &Kernel) -> String {
+    format!("{}-headers", kernel.pkgbase)
+}
+
+pub fn is_dkms(package: &str) -> bool {
+    package.ends_with("-dkms") || package.contains("-dkms-")
+}
+
+/// A DKMS module package and the packages with the same modules prebuilt for
+/// one kernel each.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ModulePackages {
+    pub dkms: &'static str,
+    prebuilt: &'static [(&'static str, &'static str)],
+}
+
+const MODULE_PACKAGES: [ModulePackages; 7] = [
+    ModulePackages {
+        dkms: "nvidia-open-dkms",
+        prebuilt: &[("linux", "nvidia-open"), ("linux-lts", "nvidia-open-lts")],
+    },
+    ModulePackages {
+        dkms: "nvidia-dkms",
+        prebuilt: &[("linux", "nvidia"), ("linux-lts", "nvidia-lts")],
+    },
+    ModulePackages {
+        dkms: "broadcom-wl-dkms",
+        prebuilt: &[("linux", "broadcom-wl")],
+    },
+    ModulePackages {
+        dkms: "virtualbox-host-dkms",
+        prebuilt: &[("linux", "virtualbox-host-modules-arch")],
+    },
+    ModulePackages {
+        dkms: "r8168-dkms",
+        prebuilt: &[("linux", "r8168"), ("linux-lts", "r8168-lts")],
+    },
+    ModulePackages {
+        dkms: "acpi_call-dkms",
+        prebuilt: &[("linux", "acpi_call"), ("linux-lts", "acpi_call-lts")],
+    },
+    ModulePackages {
+        dkms: "tp_smapi-dkms",
+        prebuilt: &[("linux", "tp_smapi"), ("linux-lts", "tp_smapi-lts")],
+    },
+];
+
+/// Kernel module packages chosen for the installed kernels and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Choice {
+    pub packages: Vec<String>,
+    pub reason: String,
+}
+
+impl ModulePackages {
+    /// Family of `package`, by its DKMS or any prebuilt name.
+    pub fn find(package: &str) -> Option<&'static ModulePackages> {
+        MODULE_PACKAGES
+            .iter()
+            .find(|family| family.contains(package))
+    }
+
+    pub fn contains(&self, package: &str) -> bool {
+        self.dkms == package || self.prebuilt.iter().any(|&(_, p)| p == package)
+    }
+
+    /// Prebuilt packages when every installed kernel has one, else the DKMS package.
+    ///
+    /// Prebuilt modules for kernels other than `linux` are dropped from the
+    /// repositories more often, so they count only when the sync databases have them.
+    pub fn choose(&self, kernels: &[Kernel], packages: Option<&PackageDatabase>) -> Choice {
+        if kernels.is_empty() {
+            return Choice {
+                packages: vec![self.dkms.to_string()],
+                reason: "no installed kernel was found, so the modules are built with DKMS"
+                    .to_string(),
+            };
+        }
+
+        let mut prebuilt: Vec<&str> = Vec::new();
+        let mut uncovered: Vec<&str> = Vec::new();
+        for kernel in kernels {
+            let package = self
+                .prebuilt
+                .iter()
+                .find(|&&(pkgbase, _)| pkgbase == kernel.pkgbase)
+                .map(|&(_, package)| package);
+            match package {
+                Some(package)
+                    if kernel.pkgbase == "linux"
+                        || packages.is_some_and(|db| db.is_available(package)) =>
+                {
+                    prebuilt.push(package)
+                }
+                _ => uncovered.push(&kernel.pkgbase),
+            }
+        }
+
+        if !uncovered.is_empty() {
+            return Choice {
+                packages: vec![self.dkms.to_string()],
+                reason: format!(
+                    "{} {} no prebuilt modules, so they are built with DKMS",
+                    uncovered.join(", "),
+                    if uncovered.len() == 1 { "has" } else { "have" }
+                ),
+            };
+        }
+        let reason = match prebuilt[..] {
+            [package] if kernels[0].pkgbase == "linux" => format!(
+                "only the linux kernel is installed, so the prebuilt {} modules fit",
+                package
+            ),
+            _ => format!(
+                "every installed kernel has prebuilt modules: {}",
+                prebuilt.join(", ")
+            ),
+        };
+        Choice {
+            packages: prebuilt.into_iter().map(str::to_string).collect(),
+            reason,
+        }
+    }
+}
+
+/// One line of `dkms status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkmsEntry {
+    /// `name/version`
+    pub module: String,
+    /// Kernel release the module was built for, `None` while only added
+    pub kernel: Option<String>,
+    pub state: String,
+}
+
+/// Parses `dkms status`, accepting the `name, version, ...` form of dkms 2 too.
+pub fn parse_dkms_status(output: &str) -> Vec<DkmsEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (left, state) = line.rsplit_once(": ")?;
+            let fields: Vec<&str> = left.split(", ").map(str::trim).collect();
+            let (module, rest) = match fields[..] {
+                [module, ref rest @ ..] if module.contains('/') => (module.to_string(), rest),
+                [name, version, ref rest @ ..] => (format!("{}/{}", name, version), rest),
+                _ => return None,
+            };
+            Some(DkmsEntry {
+                module,
+                kernel: rest.first().map(|kernel| kernel.to_string()),
+                state: state.split_whitespace().next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// DKMS modules not installed for one of `kernels`, as `(module, kernel release)`.
+pub fn missing_builds(entries: &[DkmsEntry], kernels: &[Kernel]) -> Vec<(String, String)> {
+    let mut modules: Vec<&str> = Vec::new();
+    for entry in entries {
+        if !modules.contains(&entry.module.as_str()) {
+            modules.push(&entry.module);
+        }
+    }
+    let mut missing = Vec::new();
+    for module in modules {
+        for kernel in kernels {
+            let built = entries.iter().any(|e| {
+                e.module == module
+                    && e.kernel.as_deref() == Some(kernel.version.as_str())
+                    && e.state == "installed"
+            });
+            if !built {
+                missing.push((module.to_string(), kernel.version.clone()));
+            }
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernels(pkgbases: &[&str]) -> Vec<Kernel> {
+        pkgbases
+            .iter()
+            .map(|pkgbase| Kernel {
+                pkgbase: pkgbase.to_string(),
+                version: format!("6.12.1-{}", pkgbase),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classifies_variants() {
+        assert_eq!(Variant::of("linux"), Variant::Stock);
+        assert_eq!(Variant::of("linux-hardened"), Variant::Hardened);
+        assert_eq!(Variant::of("linux-cachyos"), Variant::Custom);
+        assert_eq!(headers(&kernels(&["linux-zen"])[0]), "linux-zen-headers");
+        assert!(is_dkms("rtl8821au-dkms-git"));
+        assert!(!is_dkms("nvidia-open"));
+    }
+
+    #[test]
+    fn prebuilt_only_when_every_kernel_has_one() {
+        let family = ModulePackages::find("broadcom-wl").unwrap();
+        assert_eq!(family.dkms, "broadcom-wl-dkms");
+
+        let stock = family.choose(&kernels(&["linux"]), None);
+        assert_eq!(stock.packages, vec!["broadcom-wl"]);
+
+        let zen = family.choose(&kernels(&["linux", "linux-zen"]), None);
+        assert_eq!(zen.packages, vec!["broadcom-wl-dkms"]);
+        assert_eq!(
+            zen.reason,
+            "linux-zen has no prebuilt modules, so they are built with DKMS"
+        );
+
+        let nvidia = ModulePackages::find("nvidia-open-dkms").unwrap();
+        let lts = kernels(&["linux", "linux-lts"]);
+        assert_eq!(nvidia.choose(&lts, None).packages, vec!["nvidia-open-dkms"]);
+        let db = PackageDatabase::parse("", "nvidia-open\nnvidia-open-lts\n");
+        let both = nvidia.choose(&lts, Some(&db));
+        assert_eq!(both.packages, vec!["nvidia-open", "nvidia-open-lts"]);
+        assert_eq!(
+            both.reason,
+            "every installed kernel has prebuilt modules: nvidia-open, nvidia-open-lts"
+        );
+    }
+
+    #[test]
+    fn finds_modules_missing_for_a_kernel() {
+        let status = "\
+nvidia/575.64.05, 6.12.1-linux, x86_64: installed
+nvidia/575.64.05, 6.12.1-linux-lts, x86_64: installed (WARNING! Diff between built and installed module!)
+broadcom-wl, 6.30.223.271, 6.12.1-linux, x86_64: installed
+v4l2loopback/0.13.2: added
+";
+        let entries = parse_dkms_status(status);
+        assert_eq!(
+            entries[2],
+            DkmsEntry {
+                module: "broadcom-wl/6.30.223.271".to_string(),
+                kernel: Some("6.12.1-linux".to_string()),
+                state: "installed".to_string(),
+            }
+        );
+        assert_eq!(entries[3].kernel, None);
+
+        let missing = missing_builds(&entries, &kernels(&["linux", "linux-lts"]));
+        assert_eq!(
+            missing,
+            vec![
+                (
+                    "broadcom-wl/6.30.223.271".to_string(),
+                    "6.12.1-linux-lts".to_string()
+                ),
+                (
+                    "v4l2loopback/0.13.2".to_string(),
+                    "6.12.1-linux".to_string()
+                ),
+                (
+                    "v4l2loopback/0.13.2".to_string(),
+                    "6.12.1-linux-lts".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! Kernel module resolution against `modules.dep` and `modules.builtin`
+
+use crate::modalias::{KernelModules, ModuleAliases};
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+use tokio::fs;
+
+pub const MODULES_LOAD_CONF: &str = "/etc/modules-load.d/ardenthat.conf";
+pub const BLACKLIST_CONF: &str = "/etc/modprobe.d/ardenthat-blacklist.conf";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleState {
+    Builtin,
+    Loaded,
+    /// Shipped as a loadable module but not currently loaded
+    Available,
+    Missing,
+}
+
+#[derive(Debug, Default)]
+pub struct ModuleIndex {
+    loadable: BTreeSet<String>,
+    builtin: BTreeSet<String>,
+    loaded: BTreeSet<String>,
+    aliases: ModuleAliases,
+}
+
+impl ModuleIndex {
+    /// Index for the running kernel, including the currently loaded modules.
+    pub async fn load_running(root: &Path) -> Result<Self> {
+        let modules = KernelModules::running(root).await?;
+        let proc_modules = fs::read_to_string(root.join("proc/modules"))
+            .await
+            .context("Failed to read /proc/modules")?;
+        Self::load(&modules, &proc_modules).await
+    }
+
+    pub async fn load(modules: &KernelModules, proc_modules: &str) -> Result<Self> {
+        let dep = read(modules.dir(), "modules.dep").await?;
+        let builtin = read(modules.dir(), "modules.builtin")
+            .await
+            .unwrap_or_default();
+        let aliases = modules.load_aliases().await?;
+        Ok(Self::parse(&dep, &builtin, aliases, proc_modules))
+    }
+
+    pub fn parse(dep: &str, builtin: &str, aliases: ModuleAliases, proc_modules: &str) -> Self {
+        let loadable = dep
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(path, _)| module_name_from_path(path))
+            .collect();
+        let builtin = builtin
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(module_name_from_path)
+            .collect();
+        let loaded = proc_modules
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(normalize)
+            .collect();
+
+        Self {
+            loadable,
+            builtin,
+            loaded,
+            aliases,
+        }
+    }
+
+    /// Canonical module name for `name`, which may use dashes or be an alias.
+    pub fn resolve(&self, name: &str) -> Option<String> {
+        let normalized = normalize(name);
+        if self.loadable.contains(&normalized) || self.builtin.contains(&normalized) {
+            return Some(normalized);
+        }
+        self.aliases
+            .lookup(name)
+            .into_iter()
+            .next()
+            .map(|m| normalize(&m))
+    }
+
+    pub fn state(&self, name: &str) -> ModuleState {
+        match self.resolve(name) {
+            Some(module) if self.builtin.contains(&module) => ModuleState::Builtin,
+            Some(module) if self.loaded.contains(&module) => ModuleState::Loaded,
+            Some(_) => ModuleState::Available,
+            None => ModuleState::Missing,
+        }
+    }
+}
+
+/// Module names treat `-` and `_` as equivalent, the kernel reports underscores.
+pub fn normalize(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// `kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst` -> `iwlwifi`
+fn module_name_from_path(path: &str) -> String {
+    let file = path.trim().rsplit('/').next().unwrap_or(path);
+    let stem = file.split(".ko").next().unwrap_or(file);
+    normalize(stem)
+}
+
+/// Adds `module` to the contents of a modules-load.d file unless already listed.
+pub fn add_to_modules_load(existing: &str, module: &str) -> Option<String> {
+    let module = normalize(module);
+    if existing
+        .lines()
+        .map(str::trim)
+        .any(|line| !line.starts_with('#') && normalize(line) == module)
+    {
+        return None;
+    }
+
+    let mut contents = if existing.is_empty() {
+        "# Modules loaded at boot by ardenthat\n".to_string()
+    } else {
+        existing.to_string()
+    };
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&module);
+    contents.push('\n');
+    Some(contents)
+}
+
+/// Adds a `blacklist` line for `module` to a modprobe.d file unless already present.
+pub fn add_to_blacklist(existing: &str, module: &str) -> Option<String> {
+    let module = normalize(module);
+    if existing
+        .lines()
+        .any(|line| line.split_whitespace().collect::<Vec<_>>() == ["blacklist", module.as_str()])
+    {
+        return None;
+    }
+
+    let mut contents = if existing.is_empty() {
+        "# Modules blacklisted by ardenthat\n".to_string()
+    } else {
+        existing.to_string()
+    };
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&format!("blacklist {}\n", module));
+    Some(contents)
+}
+
+async fn read(dir: &Path, file: &str) -> Result<String> {
+    let path = dir.join(file);
+    fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    const DEP: &str = "\
+kernel/drivers/gpu/drm/i915/i915.ko.zst: kernel/drivers/gpu/drm/drm_kms_helper.ko.zst
+kernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst: kernel/net/wireless/cfg80211.ko.zst
+kernel/drivers/hid/hid-logitech-dj.ko.xz:
+kernel/drivers/bluetooth/btusb.ko:
+";
+    const BUILTIN: &str = "kernel/drivers/nvme/host/nvme.ko\nkernel/drivers/usb/host/xhci-pci.ko\n";
+    const PROC_MODULES: &str =
+        "i915 4759552 42 - Live 0x0000000000000000\nbtusb 81920 0 - Live 0x0000000000000000\n";
+
+    fn index() -> ModuleIndex {
+        let aliases = ModuleAliases::parse(
+            "alias pci:v00008086d00002723sv*sd*bc*sc*i* iwlwifi\nalias hid:b0003g*v0000046Dp0000C52B hid_logitech_dj\n",
+        );
+        ModuleIndex::parse(DEP, BUILTIN, aliases, PROC_MODULES)
+    }
+
+    #[test]
+    fn resolves_names_aliases_and_dashes() {
+        let index = index();
+        assert_eq!(index.resolve("iwlwifi").as_deref(), Some("iwlwifi"));
+        assert_eq!(
+            index.resolve("hid-logitech-dj").as_deref(),
+            Some("hid_logitech_dj")
+        );
+        assert_eq!(index.resolve("xhci-pci").as_deref(), Some("xhci_pci"));
+        assert_eq!(
+            index
+                .resolve("pci:v00008086d00002723sv00008086sd00000084bc02sc80i00")
+                .as_deref(),
+            Some("iwlwifi")
+        );
+        assert_eq!(index.resolve("nvidia-utils"), None);
+    }
+
+    #[test]
+    fn reports_module_state() {
+        let index = index();
+        assert_eq!(index.state("nvme"), ModuleState::Builtin);
+        assert_eq!(index.state("i915"), ModuleState::Loaded);
+        assert_eq!(index.state("iwlwifi"), ModuleState::Available);
+        assert_eq!(index.state("nvidia"), ModuleState::Missing);
+    }
+
+    #[test]
+    fn modules_load_entries_are_idempotent() {
+        let created = add_to_modules_load("", "hid-logitech-dj").unwrap();
+        assert_eq!(
+            created,
+            "# Modules loaded at boot by ardenthat\nhid_logitech_dj\n"
+        );
+        assert_eq!(add_to_modules_load(&created, "hid_logitech_dj"), None);
+
+        let appended = add_to_modules_load("btusb", "iwlwifi").unwrap();
+        assert_eq!(appended, "btusb\niwlwifi\n");
+    }
+
+    #[test]
+    fn blacklist_entries_are_idempotent() {
+        let created = add_to_blacklist("", "nouveau").unwrap();
+        assert_eq!(
+            created,
+            "# Modules blacklisted by ardenthat\nblacklist nouveau\n"
+        );
+        assert_eq!(add_to_blacklist(&created, "nouveau"), None);
+    }
+
+    #[tokio::test]
+    async fn loads_index_from_modules_dir() {
+        let dir = TempTree::new("kmod-index");
+        dir.write("modules.dep", DEP);
+        dir.write("modules.builtin", BUILTIN);
+        dir.write("modules.alias", "alias fs-btrfs btrfs\n");
+
+        let index = ModuleIndex::load(&KernelModules::new(dir.path()), PROC_MODULES)
+            .await
+            .unwrap();
+        assert_eq!(index.state("xhci_pci"), ModuleState::Builtin);
+        assert_eq!(index.state("btusb"), ModuleState::Loaded);
+    }
+}
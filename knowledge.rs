@@ -0,0 +1,504 @@
+//! Declarative driver knowledge base mapping hardware to Arch packages
+//!
+//! Rules are JSON documents with a `version` and a list of `rules`. The bundled
+//! defaults are loaded first, then every `*.json` file in `/etc/ardenthat/rules.d`
+//! in name order; a rule with an existing `id` replaces the earlier one.
+
+use crate::modalias::fnmatch;
+use crate::HardwareComponent;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::path::Path;
+use tokio::fs;
+
+pub const RULES_VERSION: u32 = 1;
+const RULES_DIR: &str = "etc/ardenthat/rules.d";
+const BUNDLED_RULES: &str = include_str!("data/rules.json");
+
+#[derive(Debug, Deserialize)]
+struct RuleFile {
+    version: u32,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub id: String,
+    #[serde(rename = "match")]
+    pub matcher: RuleMatch,
+    pub packages: Vec<String>,
+    /// Kernel modules that must be in the initramfs for early KMS
+    #[serde(default)]
+    pub early_modules: Vec<String>,
+    pub reason: String,
+    #[serde(default)]
+    pub priority: i32,
+    /// IDs of rules that cannot be applied together with this one
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    /// Chooses the packages from the detected device instead of `packages`
+    #[serde(default)]
+    pub select: Option<Selector>,
+    /// Packages the rule may install that only the AUR has
+    #[serde(default)]
+    pub aur: Vec<AurPackage>,
+}
+
+/// An AUR-only package, written as its name or as `{"name", "pkgbase"}` when it
+/// is split from a differently named AUR repository.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "AurEntry")]
+pub struct AurPackage {
+    pub name: String,
+    pub pkgbase: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum AurEntry {
+    Name(String),
+    Split { name: String, pkgbase: String },
+}
+
+impl From<AurEntry> for AurPackage {
+    fn from(entry: AurEntry) -> Self {
+        match entry {
+            AurEntry::Name(name) => AurPackage {
+                pkgbase: name.clone(),
+                name,
+            },
+            AurEntry::Split { name, pkgbase } => AurPackage { name, pkgbase },
+        }
+    }
+}
+
+/// Package selection logic a rule can defer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Selector {
+    /// NVIDIA driver branch by GPU architecture and installed kernels
+    Nvidia,
+    /// amdgpu or radeon stack by AMD GPU family
+    Amd,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleMatch {
+    pub bus: Option<Bus>,
+    #[serde(default, deserialize_with = "hex_opt")]
+    pub vendor: Option<u16>,
+    #[serde(default, deserialize_with = "hex_list")]
+    pub devices: Vec<u16>,
+    /// Inclusive device ID ranges
+    #[serde(default, deserialize_with = "hex_ranges")]
+    pub device_ranges: Vec<(u16, u16)>,
+    #[serde(default, deserialize_with = "hex_opt")]
+    pub class: Option<u16>,
+    #[serde(default, deserialize_with = "hex_opt")]
+    pub subclass: Option<u16>,
+    /// `/proc/cpuinfo` vendor string, e.g. `GenuineIntel`
+    pub cpu_vendor: Option<String>,
+    /// Glob matched against the device's candidate kernel modules
+    pub module: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    Pci,
+    Usb,
+    Cpu,
+}
+
+/// A rule that matched a detected device.
+#[derive(Debug, Clone)]
+pub struct Recommendation {
+    pub rule: String,
+    pub device: String,
+    /// Indices of every matching component in the evaluated slice
+    pub components: Vec<usize>,
+    pub packages: Vec<String>,
+    pub early_modules: Vec<String>,
+    pub reason: String,
+    pub select: Option<Selector>,
+}
+
+#[derive(Debug, Default)]
+pub struct KnowledgeBase {
+    rules: Vec<Rule>,
+}
+
+impl KnowledgeBase {
+    /// Bundled rules plus local overrides from `/etc/ardenthat/rules.d`.
+    pub async fn load(root: &Path) -> Result<Self> {
+        Self::load_from(&root.join(RULES_DIR)).await
+    }
+
+    pub async fn load_from(rules_dir: &Path) -> Result<Self> {
+        let mut kb = Self::default();
+        kb.add_rules(BUNDLED_RULES, "bundled rules")?;
+
+        let mut files = Vec::new();
+        if let Ok(mut entries) = fs::read_dir(rules_dir).await {
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+
+        for path in files {
+            let text = fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            kb.add_rules(&text, &path.display().to_string())?;
+        }
+
+        Ok(kb)
+    }
+
+    pub fn add_rules(&mut self, text: &str, source: &str) -> Result<()> {
+        let file: RuleFile =
+            serde_json::from_str(text).with_context(|| format!("Invalid rule file {}", source))?;
+        if file.version != RULES_VERSION {
+            anyhow::bail!(
+                "Unsupported rule file version {} in {} (expected {})",
+                file.version,
+                source,
+                RULES_VERSION
+            );
+        }
+
+        for rule in file.rules {
+            match self.rules.iter_mut().find(|r| r.id == rule.id) {
+                Some(existing) => *existing = rule,
+                None => self.rules.push(rule),
+            }
+        }
+        Ok(())
+    }
+
+    /// Every package the rules mark as AUR-only.
+    pub fn aur_packages(&self) -> Vec<AurPackage> {
+        let mut packages: Vec<AurPackage> = Vec::new();
+        for package in self.rules.iter().flat_map(|r| &r.aur) {
+            if !packages.iter().any(|p| p.name == package.name) {
+                packages.push(package.clone());
+            }
+        }
+        packages
+    }
+
+    /// Evaluates every rule against the detected hardware.
+    ///
+    /// When conflicting rules both match a component, the lower priority one is
+    /// dropped for that component and still applies to the others it matches.
+    /// The result is ordered by descending priority.
+    pub fn evaluate(&self, components: &[HardwareComponent]) -> Vec<Recommendation> {
+        let mut matched: Vec<(&Rule, Vec<usize>)> = Vec::new();
+        for rule in &self.rules {
+            let indices: Vec<usize> = components
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| rule.matcher.matches(c))
+                .map(|(i, _)| i)
+                .collect();
+            if !indices.is_empty() {
+                matched.push((rule, indices));
+            }
+        }
+        matched.sort_by_key(|(rule, _)| std::cmp::Reverse(rule.priority));
+
+        let mut accepted: Vec<(&Rule, Vec<usize>)> = Vec::new();
+        for (rule, mut indices) in matched {
+            for (other, claimed) in &accepted {
+                if other.conflicts.contains(&rule.id) || rule.conflicts.contains(&other.id) {
+                    indices.retain(|i| !claimed.contains(i));
+                }
+            }
+            if !indices.is_empty() {
+                accepted.push((rule, indices));
+            }
+        }
+
+        accepted
+            .into_iter()
+            .map(|(rule, indices)| {
+                let first = &components[indices[0]];
+                Recommendation {
+                    rule: rule.id.clone(),
+                    device: format!("{} {}", first.vendor, first.model),
+                    components: indices,
+                    packages: rule.packages.clone(),
+                    early_modules: rule.early_modules.clone(),
+                    reason: rule.reason.clone(),
+                    select: rule.select,
+                }
+            })
+            .collect()
+    }
+}
+
+impl RuleMatch {
+    pub fn matches(&self, component: &HardwareComponent) -> bool {
+        let ids = if let Some(device) = &component.pci {
+            Some((
+                Bus::Pci,
+                device.vendor_id,
+                device.device_id,
+                device.class,
+                device.subclass,
+            ))
+        } else if let Some(device) = &component.usb {
+            let (class, subclass) = device
+                .interfaces
+                .first()
+                .map(|i| (i.class, i.subclass))
+                .unwrap_or((device.device_class, 0));
+            Some((
+                Bus::Usb,
+                device.vendor_id,
+                device.product_id,
+                class,
+                subclass,
+            ))
+        } else {
+            None
+        };
+
+        if let Some(cpu_vendor) = &self.cpu_vendor {
+            match &component.cpu {
+                Some(cpu) if &cpu.vendor_id == cpu_vendor => {}
+                _ => return false,
+            }
+        }
+
+        match ids {
+            Some((bus, vendor, device, class, subclass)) => {
+                self.bus.is_none_or(|b| b == bus)
+                    && self.vendor.is_none_or(|v| v == vendor)
+                    && (self.devices.is_empty() || self.devices.contains(&device))
+                    && (self.device_ranges.is_empty()
+                        || self
+                            .device_ranges
+                            .iter()
+                            .any(|&(lo, hi)| (lo..=hi).contains(&device)))
+                    && self.class.is_none_or(|c| c == class as u16)
+                    && self.subclass.is_none_or(|s| s == subclass as u16)
+                    && self.module.as_ref().is_none_or(|pattern| {
+                        component
+                            .kernel_modules
+                            .iter()
+                            .any(|m| fnmatch(pattern.as_bytes(), m.as_bytes()))
+                    })
+            }
+            // CPUs only match rules that ask for a CPU
+            None => {
+                component.cpu.is_some()
+                    && (self.bus == Some(Bus::Cpu) || self.cpu_vendor.is_some())
+                    && self.vendor.is_none()
+                    && self.devices.is_empty()
+                    && self.device_ranges.is_empty()
+            }
+        }
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("invalid hex ID: {}", value))
+}
+
+fn hex_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|v| parse_hex(&v).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn hex_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u16>, D::Error> {
+    let values: Vec<String> = Vec::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|v| parse_hex(v).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn hex_ranges<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u16, u16)>, D::Error> {
+    let values: Vec<(String, String)> = Vec::deserialize(deserializer)?;
+    values
+        .iter()
+        .map(|(lo, hi)| {
+            Ok((
+                parse_hex(lo).map_err(serde::de::Error::custom)?,
+                parse_hex(hi).map_err(serde::de::Error::custom)?,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn pci_gpu(vendor_id: u16, device_id: u16, modules: &[&str]) -> HardwareComponent {
+        HardwareComponent {
+            device_type: "PCI".to_string(),
+            vendor: format!("{:04x}", vendor_id),
+            model: format!("{:04x}", device_id),
+            kernel_modules: modules.iter().map(|m| m.to_string()).collect(),
+            pci: Some(crate::pci::PciDevice {
+                vendor_id,
+                device_id,
+                class: 0x03,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn cpu(vendor_id: &str) -> HardwareComponent {
+        HardwareComponent {
+            device_type: "CPU".to_string(),
+            cpu: Some(crate::cpu::CpuInfo {
+                vendor_id: vendor_id.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn bundled() -> KnowledgeBase {
+        let mut kb = KnowledgeBase::default();
+        kb.add_rules(BUNDLED_RULES, "bundled rules").unwrap();
+        kb
+    }
+
+    fn rule_ids(recommendations: &[Recommendation]) -> Vec<&str> {
+        recommendations.iter().map(|r| r.rule.as_str()).collect()
+    }
+
+    #[test]
+    fn matches_by_id_range_and_cpu_vendor() {
+        let components = vec![pci_gpu(0x10de, 0x2204, &["nouveau"]), cpu("AuthenticAMD")];
+        let recommendations = bundled().evaluate(&components);
+        assert_eq!(rule_ids(&recommendations), vec!["amd-ucode", "nvidia-open"]);
+        assert_eq!(
+            recommendations[1].packages,
+            vec!["nvidia-open", "nvidia-utils"]
+        );
+        assert_eq!(
+            recommendations[1].early_modules,
+            vec!["nvidia", "nvidia_modeset", "nvidia_uvm", "nvidia_drm"]
+        );
+    }
+
+    #[test]
+    fn module_glob_restricts_matches() {
+        let with_amdgpu = vec![pci_gpu(0x1002, 0x73bf, &["amdgpu"])];
+        assert_eq!(
+            rule_ids(&bundled().evaluate(&with_amdgpu)),
+            vec!["vulkan-radeon"]
+        );
+
+        // A Southern Islands card on a kernel built without amdgpu SI support
+        let radeon_only = vec![pci_gpu(0x1002, 0x6818, &["radeon"])];
+        assert!(bundled().evaluate(&radeon_only).is_empty());
+    }
+
+    #[test]
+    fn rules_mark_aur_packages() {
+        let aur = bundled().aur_packages();
+        assert!(aur.contains(&AurPackage {
+            name: "nvidia-470xx-dkms".to_string(),
+            pkgbase: "nvidia-470xx-utils".to_string(),
+        }));
+        assert!(aur.contains(&AurPackage {
+            name: "lib32-nvidia-390xx-utils".to_string(),
+            pkgbase: "lib32-nvidia-390xx-utils".to_string(),
+        }));
+
+        let mut kb = KnowledgeBase::default();
+        let bad = r#"{"version": 1, "rules": [{"id": "x", "match": {}, "packages": [],
+            "reason": "x", "aur": [{"name": "x", "base": "y"}]}]}"#;
+        assert!(kb.add_rules(bad, "test").is_err());
+    }
+
+    #[test]
+    fn conflicting_rules_keep_higher_priority_per_component() {
+        let mut kb = bundled();
+        let beta = r#"{"version": 1, "rules": [{"id": "nvidia-beta",
+            "match": {"bus": "pci", "vendor": "10de", "devices": ["2684"]},
+            "packages": ["nvidia-beta-dkms"], "reason": "x", "priority": 70,
+            "conflicts": ["nvidia-open"]}]}"#;
+        kb.add_rules(beta, "test").unwrap();
+        let components = vec![
+            pci_gpu(0x10de, 0x2684, &["nouveau"]),
+            pci_gpu(0x10de, 0x2204, &["nouveau"]),
+        ];
+        let recommendations = kb.evaluate(&components);
+        assert_eq!(
+            rule_ids(&recommendations),
+            vec!["nvidia-beta", "nvidia-open"]
+        );
+        assert_eq!(recommendations[0].components, vec![0]);
+        assert_eq!(recommendations[1].components, vec![1]);
+        assert!(kb
+            .evaluate(&components[..1])
+            .iter()
+            .all(|r| r.rule != "nvidia-open"));
+
+        // Conflicting rules for different GPUs both apply
+        let intel = vec![
+            pci_gpu(0x8086, 0x0166, &["i915"]),
+            pci_gpu(0x8086, 0x5917, &["i915"]),
+        ];
+        assert_eq!(
+            rule_ids(&bundled().evaluate(&intel)),
+            vec!["intel-media-driver", "libva-intel-driver"]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_rules_override_bundled_ones() {
+        let dir = TempTree::new("rules-d");
+        dir.write(
+            "50-local.json",
+            r#"{
+                "version": 1,
+                "rules": [
+                    {
+                        "id": "amd-ucode",
+                        "match": { "bus": "cpu", "cpu_vendor": "AuthenticAMD" },
+                        "packages": ["amd-ucode-git"],
+                        "reason": "Local microcode build",
+                        "priority": 70
+                    }
+                ]
+            }"#,
+        );
+        dir.write("README", "not a rule file");
+
+        let kb = KnowledgeBase::load_from(dir.path()).await.unwrap();
+        let recommendations = kb.evaluate(&[cpu("AuthenticAMD")]);
+        assert_eq!(recommendations.len(), 1);
+        assert_eq!(recommendations[0].packages, vec!["amd-ucode-git"]);
+    }
+
+    #[test]
+    fn rejects_unsupported_versions_and_bad_ids() {
+        let mut kb = KnowledgeBase::default();
+        assert!(kb
+            .add_rules(r#"{"version": 2, "rules": []}"#, "test")
+            .is_err());
+
+        let bad = r#"{"version": 1, "rules": [{"id": "x", "match": {"vendor": "zz"},
+            "packages": [], "reason": ""}]}"#;
+        assert!(kb.add_rules(bad, "test").is_err());
+    }
+}
@@ -1,10 +1,39 @@
 //! ArdentHat - Arch Linux Hardware Detection and Driver Management
 //! Created by MelvinSGjr
 
+mod amd;
+mod aur;
+mod bootloader;
+mod command;
+mod config;
+mod cpu;
+mod diff;
+mod firmware;
+mod hybrid;
+mod ids;
+mod initramfs;
+mod journal;
+mod kernel;
+mod kmod;
+mod knowledge;
+mod microcode;
+mod modalias;
+mod nvidia;
+mod packages;
+mod pci;
+mod plan;
+mod privilege;
+mod snapshot;
+mod status;
+#[cfg(test)]
+mod testutil;
+mod usb;
+
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use command::{CommandLine, CommandRunner};
 use serde::{Deserialize, Serialize};
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 
 #[derive(Parser)]
@@ -19,196 +48,2053 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Detect all hardware components
-    Detect,
+    Detect {
+        /// Include USB hubs and root hubs
+        #[arg(long)]
+        include_hubs: bool,
+    },
     /// Automatically setup required drivers
     Setup {
         /// Run without making actual changes
         #[arg(short, long)]
         dry_run: bool,
+        /// Load enabled kernel modules at boot via /etc/modules-load.d/ardenthat.conf
+        #[arg(long)]
+        persist_modules: bool,
+        /// Add GPU modules to the initramfs configuration for early KMS
+        #[arg(long)]
+        early_kms: bool,
+        /// Install a pacman hook that rebuilds the initramfs when the NVIDIA driver is upgraded
+        #[arg(long, requires = "early_kms")]
+        nvidia_hook: bool,
+        /// Switch Southern and Sea Islands AMD GPUs from radeon to amdgpu for Vulkan
+        #[arg(long)]
+        amdgpu: bool,
+        /// How to use the GPUs of a hybrid graphics laptop
+        #[arg(long, value_enum, value_name = "PROFILE")]
+        hybrid: Option<hybrid::Profile>,
+        /// Build drivers that only the AUR packages, as the user running setup
+        #[arg(long)]
+        allow_aur: bool,
+        /// How to gain root (default: from config.json, else auto-detected)
+        #[arg(long, value_enum)]
+        escalation: Option<privilege::Escalation>,
+        /// Also write the plan as JSON to FILE for review
+        #[arg(long, value_name = "FILE")]
+        plan_out: Option<PathBuf>,
+        /// Apply exactly a plan saved with --plan-out, refusing if the system changed since
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["persist_modules", "early_kms", "nvidia_hook", "amdgpu", "hybrid", "allow_aur", "plan_out"]
+        )]
+        apply_plan: Option<PathBuf>,
+    },
+    /// Revert a setup transaction recorded in /var/lib/ardenthat
+    Undo {
+        /// Transaction to undo (default: the most recent one not yet undone)
+        transaction: Option<u32>,
+        /// List recorded transactions instead of undoing one
+        #[arg(long)]
+        list: bool,
+        /// Show what would be reverted without making changes
+        #[arg(short, long)]
+        dry_run: bool,
+        /// How to gain root (default: from config.json, else auto-detected)
+        #[arg(long, value_enum)]
+        escalation: Option<privilege::Escalation>,
     },
     /// Generate hardware report
     Report {
         /// Output file (default: ahd-report.txt)
         #[arg(short, long)]
         output: Option<String>,
+        /// Include USB hubs and root hubs
+        #[arg(long)]
+        include_hubs: bool,
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct HardwareComponent {
     device_type: String,
     vendor: String,
     model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<String>,
     driver: Option<String>,
+    /// Kernel modules whose aliases match the device, empty if the kernel has no driver
+    kernel_modules: Vec<String>,
     status: DriverStatus,
+    /// Human-readable explanation of `status`
+    status_reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pci: Option<pci::PciDevice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usb: Option<usb::UsbDevice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu: Option<cpu::CpuInfo>,
+    /// Display controller role and power state, for PCI display devices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gpu: Option<hybrid::GpuInfo>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A driver package or kernel module that setup should install or enable.
+#[derive(Debug)]
+struct RequiredDriver {
+    name: String,
+    reason: String,
+    /// Matched from a device's modalias, so it names a kernel module and not a package
+    module: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 enum DriverStatus {
     Installed,
     NotInstalled,
     Available,
+    /// Bound to a generic driver such as nouveau or simpledrm
+    FallbackDriver,
+    FirmwareMissing,
+    #[default]
     Unknown,
 }
 
+/// Command line choices that shape the setup plan, saved with it so `--apply-plan`
+/// can make the same plan again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct SetupOptions {
+    persist_modules: bool,
+    early_kms: bool,
+    nvidia_hook: bool,
+    amdgpu: bool,
+    hybrid: Option<hybrid::Profile>,
+    allow_aur: bool,
+}
+
+/// Detected hardware with the matching knowledge-base rules and pacman state.
+struct Scan {
+    components: Vec<HardwareComponent>,
+    recommendations: Vec<knowledge::Recommendation>,
+    /// `None` when pacman is not available
+    packages: Option<packages::PackageDatabase>,
+    /// Firmware the bound drivers need that no installed package provides
+    firmware: Vec<firmware::MissingFirmware>,
+    /// Packages the knowledge base marks as AUR-only
+    aur: Vec<knowledge::AurPackage>,
+}
+
+/// The system being inspected: where its files live and how commands are run on it.
+struct Host<'a> {
+    runner: &'a dyn CommandRunner,
+    root: PathBuf,
+}
+
+impl Host<'_> {
+    /// Resolves an absolute system path below the host root.
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn run(&self, command: CommandLine) -> Result<command::CommandOutput> {
+        self.runner.run(&command)
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let host = Host {
+        runner: &command::SystemRunner,
+        root: PathBuf::from("/"),
+    };
 
     match cli.command {
-        Commands::Detect => detect_hardware().await?,
-        Commands::Setup { dry_run } => setup_drivers(dry_run).await?,
-        Commands::Report { output } => generate_report(output).await?,
+        Commands::Detect { include_hubs } => detect_hardware(&host, include_hubs).await?,
+        Commands::Setup {
+            dry_run,
+            persist_modules,
+            early_kms,
+            nvidia_hook,
+            amdgpu,
+            hybrid,
+            allow_aur,
+            escalation,
+            plan_out,
+            apply_plan,
+        } => {
+            if !dry_run {
+                reexec_as_root(&host, escalation).await?;
+            }
+            let options = SetupOptions {
+                persist_modules,
+                early_kms,
+                nvidia_hook,
+                amdgpu,
+                hybrid,
+                allow_aur,
+            };
+            match apply_plan {
+                Some(path) => apply_saved_plan(&host, &path, dry_run).await?,
+                None => setup_drivers(&host, dry_run, options, plan_out.as_deref()).await?,
+            }
+        }
+        Commands::Undo {
+            transaction,
+            list,
+            dry_run,
+            escalation,
+        } => {
+            if !list && !dry_run {
+                reexec_as_root(&host, escalation).await?;
+            }
+            undo_transaction(&host, transaction, list, dry_run).await?
+        }
+        Commands::Report {
+            output,
+            include_hubs,
+        } => generate_report(&host, output, include_hubs).await?,
     }
 
     Ok(())
 }
 
-async fn detect_hardware() -> Result<()> {
-    let components = scan_system().await?;
-    display_hardware_table(&components).await?;
+/// Exits with the status of an escalated re-run of this command unless already root.
+async fn reexec_as_root(
+    host: &Host<'_>,
+    escalation: Option<privilege::Escalation>,
+) -> Result<()> {
+    let exe = std::env::current_exe().context("Failed to locate the ardenthat executable")?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = escalate(host, escalation, &exe.to_string_lossy(), &args).await? {
+        std::process::exit(code);
+    }
     Ok(())
 }
 
-async fn scan_system() -> Result<Vec<HardwareComponent>> {
+/// Re-runs ardenthat as root through the escalation backend unless already root,
+/// so a whole transaction authenticates once. Returns the exit code of that run.
+async fn escalate(
+    host: &Host<'_>,
+    requested: Option<privilege::Escalation>,
+    exe: &str,
+    args: &[String],
+) -> Result<Option<i32>> {
+    let config = config::Config::load(&host.root).await?;
+    let escalation = privilege::resolve(&host.root, requested.or(config.escalation)).await?;
+    let command = match escalation.reexec(exe, args) {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+
+    let output = host
+        .run(command)
+        .context("Failed to run ardenthat with elevated privileges")?;
+    Ok(Some(output.code.unwrap_or(1)))
+}
+
+async fn detect_hardware(host: &Host<'_>, include_hubs: bool) -> Result<()> {
+    let scan = scan_system(host, include_hubs).await?;
+    display_hardware_table(&scan.components).await?;
+    Ok(())
+}
+
+async fn scan_system(host: &Host<'_>, include_hubs: bool) -> Result<Scan> {
     let mut components = Vec::new();
     
-    // PCI Devices (stub implementation)
-    let pci_output = Command::new("lspci")
-        .arg("-v")
-        .output()
-        .context("Failed to execute lspci")?;
-    
-    components.extend(parse_pci_output(&pci_output.stdout).await?);
+    // PCI Devices
+    components.extend(scan_pci_devices(host).await?);
 
-    // USB Devices (stub implementation)
-    let usb_output = Command::new("lsusb")
-        .output()
-        .context("Failed to execute lsusb")?;
-    
-    components.extend(parse_usb_output(&usb_output.stdout).await?);
+    // USB Devices
+    components.extend(scan_usb_devices(host, include_hubs).await?);
 
-    // CPU Detection (stub implementation)
-    let cpu_info = fs::read_to_string("/proc/cpuinfo")
+    // CPU Detection
+    let cpu_info = fs::read_to_string(host.path("/proc/cpuinfo"))
         .await
         .context("Failed to read CPU info")?;
     
     components.extend(parse_cpu_info(&cpu_info).await?);
 
-    Ok(components)
+    resolve_names(&mut components, &ids::IdResolver::load(&host.root).await);
+    hybrid::inspect(&host.path("/sys"), &mut components).await;
+
+    // Without a modules directory (e.g. in a container) keep what lspci reported
+    if let Ok(aliases) = load_module_aliases(&host.root).await {
+        match_kernel_modules(&mut components, &aliases);
+    }
+
+    let knowledge = knowledge::KnowledgeBase::load(&host.root)
+        .await
+        .context("Failed to load driver knowledge base")?;
+    let mut recommendations = knowledge.evaluate(&components);
+    let packages = packages::PackageDatabase::load(host.runner).ok();
+    let kernels = initramfs::installed_kernels(&host.root)
+        .await
+        .unwrap_or_default();
+    nvidia::apply(&mut recommendations, &components, &kernels, packages.as_ref());
+    amd::apply(&mut recommendations, &components, false, packages.as_ref());
+    status::compute_statuses(&mut components, &recommendations, packages.as_ref());
+    let firmware = firmware::find_missing(host, &components, packages.as_ref()).await;
+    firmware::mark_components(&mut components, &firmware);
+
+    Ok(Scan {
+        components,
+        recommendations,
+        packages,
+        firmware,
+        aur: knowledge.aur_packages(),
+    })
 }
 
-async fn setup_drivers(dry_run: bool) -> Result<()> {
-    let components = scan_system().await?;
-    let required_drivers = identify_required_drivers(&components).await?;
+async fn load_module_aliases(root: &Path) -> Result<modalias::ModuleAliases> {
+    modalias::KernelModules::running(root).await?.load_aliases().await
+}
 
-    for driver in required_drivers {
-        if dry_run {
-            println!("[Dry Run] Would install driver: {}", driver);
-        } else {
-            install_driver(&driver).await?;
+/// Resolves each device's modalias to the kernel modules that can drive it.
+fn match_kernel_modules(components: &mut [HardwareComponent], aliases: &modalias::ModuleAliases) {
+    for component in components {
+        let mut modules = Vec::new();
+
+        if let Some(device) = component.pci.as_ref() {
+            modules = aliases.lookup(&device.modalias());
+        }
+        if let Some(device) = component.usb.as_ref() {
+            for interface in &device.interfaces {
+                let matched = interface
+                    .modalias
+                    .as_deref()
+                    .map(|modalias| aliases.lookup(modalias))
+                    .unwrap_or_default();
+                for module in matched {
+                    if !modules.contains(&module) {
+                        modules.push(module);
+                    }
+                }
+            }
+        }
+
+        component.kernel_modules = modules;
+    }
+}
+
+/// Replaces hex IDs with names from the ID database and fills in class descriptions.
+fn resolve_names(components: &mut [HardwareComponent], ids: &ids::IdResolver) {
+    for component in components {
+        if let Some(device) = component.pci.as_mut() {
+            if device.vendor_name.is_empty() {
+                if let Some(name) = ids.pci.vendor(device.vendor_id) {
+                    device.vendor_name = name.to_string();
+                    component.vendor = name.to_string();
+                }
+            }
+            if device.device_name.is_empty() {
+                if let Some(name) = ids.pci.device(device.vendor_id, device.device_id) {
+                    device.device_name = name.to_string();
+                    component.model = name.to_string();
+                }
+            }
+            if device.class_name.is_empty() {
+                if let Some(name) = ids.pci.subclass(device.class, device.subclass) {
+                    device.class_name = name.to_string();
+                }
+            }
+            if let (Some(sv), Some(sd)) = (device.subsystem_vendor_id, device.subsystem_device_id) {
+                device.subsystem_name = ids
+                    .pci
+                    .subsystem(device.vendor_id, device.device_id, sv, sd)
+                    .map(str::to_string);
+            }
+            if !device.class_name.is_empty() {
+                component.class = Some(device.class_name.clone());
+            }
+        }
+
+        if let Some(device) = component.usb.as_ref() {
+            if let Some(name) = ids.usb.vendor(device.vendor_id) {
+                component.vendor = name.to_string();
+            }
+            if let Some(name) = ids.usb.device(device.vendor_id, device.product_id) {
+                component.model = name.to_string();
+            }
+            // Composite devices declare their class per interface
+            let class = match device.device_class {
+                0x00 | 0xef => device.interfaces.first().map(|i| (i.class, i.subclass)),
+                class => Some((class, 0)),
+            };
+            component.class = class
+                .and_then(|(class, subclass)| ids.usb.subclass(class, subclass))
+                .map(str::to_string);
         }
     }
+}
 
-    if !dry_run {
-        update_initramfs().await?;
+async fn setup_drivers(
+    host: &Host<'_>,
+    dry_run: bool,
+    options: SetupOptions,
+    plan_out: Option<&Path>,
+) -> Result<()> {
+    let scan = scan_system(host, false).await?;
+    let plan = plan_setup(host, &scan, options).await?;
+
+    println!("Setup plan:");
+    print!("{}", plan);
+    if let Some(path) = plan_out {
+        plan.save(path).await?;
+        println!("Plan written to {}", path.display());
     }
+    if dry_run {
+        println!("[Dry Run] No changes were made");
+        return Ok(());
+    }
+    execute_setup(host, &scan, &plan).await
+}
 
-    Ok(())
+/// Applies a plan saved with `--plan-out` after checking the system still matches
+/// it and that setup, run again with the saved options, makes the same plan.
+async fn apply_saved_plan(host: &Host<'_>, path: &Path, dry_run: bool) -> Result<()> {
+    let plan = plan::Plan::load(path).await?;
+    let scan = scan_system(host, false).await?;
+
+    println!("Setup plan from {}:", path.display());
+    print!("{}", plan);
+    plan.check_drift(host, &scan.components, scan.packages.as_ref())
+        .await?;
+    let regenerated = plan_setup(host, &scan, plan.options).await?;
+    plan.check_regenerated(&regenerated)?;
+    if dry_run {
+        println!("[Dry Run] The system still matches the plan, no changes were made");
+        return Ok(());
+    }
+    execute_setup(host, &scan, &plan).await
 }
 
-async fn install_driver(driver: &str) -> Result<()> {
-    if is_kernel_module(driver).await? {
-        enable_kernel_module(driver).await?;
-    } else {
-        install_package(driver).await?;
+/// Takes the pre-setup snapshot, then executes `plan` as a journaled transaction.
+async fn execute_setup(host: &Host<'_>, scan: &Scan, plan: &plan::Plan) -> Result<()> {
+    let journal = journal::Journal::new(&host.root);
+    let mut transaction = journal.begin().await?;
+    if plan.has_changes() && plan.conflicts.is_empty() {
+        transaction.snapshot = take_snapshot(host, transaction.timestamp).await?;
+    }
+    plan.execute(host, scan.packages.as_ref(), &journal, &mut transaction)
+        .await?;
+    if !transaction.is_empty() {
+        println!(
+            "Setup complete, recorded as transaction {} (revert with `ardenthat undo {}`)",
+            transaction.id, transaction.id
+        );
     }
     Ok(())
 }
 
-async fn is_kernel_module(_module: &str) -> Result<bool> {
-    Ok(false)
+/// Takes a pre-setup snapshot if a backend is available.
+///
+/// Fails when `require_snapshot` is set and no snapshot could be taken.
+async fn take_snapshot(host: &Host<'_>, timestamp: u64) -> Result<Option<snapshot::Snapshot>> {
+    let config = config::Config::load(&host.root).await?;
+    let label = format!("ardenthat-pre-setup-{}", timestamp);
+
+    let result = match snapshot::detect(host) {
+        Some(backend) => snapshot::create(host, backend, &label).map(Some),
+        None => Ok(None),
+    };
+    match result {
+        Ok(Some(snapshot)) => {
+            println!(
+                "Created {} snapshot {} before setup",
+                snapshot.backend.name(),
+                snapshot.id
+            );
+            Ok(Some(snapshot))
+        }
+        Ok(None) if config.require_snapshot => anyhow::bail!(
+            "A snapshot is required but no snapper config, Timeshift or btrfs root was found, no changes were made"
+        ),
+        Ok(None) => {
+            println!("No snapshot backend found, continuing without a snapshot");
+            Ok(None)
+        }
+        Err(err) if config.require_snapshot => {
+            Err(err.context("A snapshot is required, no changes were made"))
+        }
+        Err(err) => {
+            println!("Warning: {:#}, continuing without a snapshot", err);
+            Ok(None)
+        }
+    }
 }
 
-async fn enable_kernel_module(module: &str) -> Result<()> {
-    println!("Enabling kernel module: {}", module);
+async fn undo_transaction(
+    host: &Host<'_>,
+    id: Option<u32>,
+    list: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let journal = journal::Journal::new(&host.root);
+    let transactions = journal.list().await?;
+    if list {
+        if transactions.is_empty() {
+            println!("No setup transactions recorded");
+        }
+        for transaction in &transactions {
+            println!("{}", transaction.summary());
+        }
+        return Ok(());
+    }
+
+    let target = match id {
+        Some(id) => transactions
+            .iter()
+            .find(|t| t.id == id)
+            .with_context(|| format!("No transaction {} in the journal", id))?,
+        None => transactions
+            .iter()
+            .rev()
+            .find(|t| !t.undone)
+            .context("No setup transaction to undo")?,
+    };
+    if target.undone {
+        anyhow::bail!("Transaction {} was already undone", target.id);
+    }
+    if let Some(later) = transactions
+        .iter()
+        .find(|t| t.id > target.id && !t.undone && t.overlaps(target))
+    {
+        anyhow::bail!(
+            "Transaction {} changed the same files or packages, undo it first",
+            later.id
+        );
+    }
+
+    let packages = packages::PackageDatabase::load(host.runner).ok();
+    let regenerate = match initramfs::detect_generator(&host.root).await {
+        Some(generator) if target.initramfs => {
+            let kernels = initramfs::installed_kernels(&host.root)
+                .await
+                .context("Failed to list installed kernels")?;
+            generator.regenerate_commands(&kernels)
+        }
+        _ => Vec::new(),
+    };
+
+    println!("Undoing transaction:\n{}", target.summary());
+    let cached = target.cached_packages(&host.root).await;
+    for line in target.restore_summary(&cached) {
+        println!("  {}", line);
+    }
+    for command in target.undo_commands(packages.as_ref(), &cached, regenerate) {
+        if dry_run {
+            println!("[Dry Run] Would run: {}", command);
+            continue;
+        }
+        let output = host.run(command.clone())?;
+        if !output.success() {
+            anyhow::bail!("Failed to undo transaction {}: {} failed", target.id, command);
+        }
+    }
+
+    if !dry_run {
+        let mut undone = target.clone();
+        undone.undone = true;
+        journal.save(&undone).await?;
+        println!("Transaction {} undone", target.id);
+    }
     Ok(())
 }
 
-async fn install_package(package: &str) -> Result<()> {
-    let status = Command::new("sudo")
-        .arg("pacman")
-        .arg("-S")
-        .arg("--noconfirm")
-        .arg(package)
-        .status()
-        .context("Failed to install package")?;
+/// Works out every change setup needs before touching the system.
+async fn plan_setup(host: &Host<'_>, scan: &Scan, options: SetupOptions) -> Result<plan::Plan> {
+    let modules = kmod::ModuleIndex::load_running(&host.root)
+        .await
+        .context("Failed to index kernel modules")?;
+    let mut plan = plan::Plan {
+        options,
+        ..Default::default()
+    };
+    let mut persisted: Vec<String> = Vec::new();
+    let generator = initramfs::detect_generator(&host.root).await;
+
+    // Integrated-only graphics leaves the discrete GPU without a driver
+    let topology = hybrid::topology(&scan.components);
+    let powered_off = match (options.hybrid, topology) {
+        (Some(hybrid::Profile::IntegratedOnly), Some(topology)) => Some(topology.discrete),
+        _ => None,
+    };
+    let mut recommendations: Vec<knowledge::Recommendation> = scan
+        .recommendations
+        .iter()
+        .filter(|r| !r.components.iter().all(|&i| Some(i) == powered_off))
+        .cloned()
+        .collect();
+    if options.amdgpu {
+        amd::apply(&mut recommendations, &scan.components, true, scan.packages.as_ref());
+        plan_amdgpu_switch(host, scan, &recommendations, &mut plan).await;
+    }
+
+    let nvidia_branch = plan_nvidia(host, &recommendations, &mut plan).await;
+    if let Some(profile) = options.hybrid {
+        plan_hybrid(host, scan, topology, profile, &mut plan).await;
+    }
+
+    for driver in identify_required_drivers(&scan.components, &recommendations) {
+        if let Some(module) = modules.resolve(&driver.name) {
+            // e.g. nouveau for a GPU that the NVIDIA driver takes over
+            if plan.blacklist_modules.iter().any(|m| m.name == module) {
+                continue;
+            }
+            match modules.state(&module) {
+                kmod::ModuleState::Available => plan.load_module(&module, &driver.reason),
+                kmod::ModuleState::Loaded => {
+                    plan.unchanged(&module, "kernel module is already loaded")
+                }
+                // Nothing to load or persist
+                _ => {
+                    plan.unchanged(&module, "kernel module is built into the kernel");
+                    continue;
+                }
+            }
+            if options.persist_modules {
+                persisted.push(module);
+            }
+            continue;
+        }
+
+        if driver.module {
+            plan.conflict(format!(
+                "Kernel module {} not found for the running kernel",
+                driver.name
+            ));
+            continue;
+        }
+        require_package(&mut plan, scan, &driver.name, &driver.reason);
+    }
+
+    if !persisted.is_empty() {
+        let existing = fs::read_to_string(host.path(kmod::MODULES_LOAD_CONF))
+            .await
+            .unwrap_or_default();
+        let contents = persisted.iter().fold(existing.clone(), |contents, module| {
+            kmod::add_to_modules_load(&contents, module).unwrap_or(contents)
+        });
+        if contents != existing {
+            plan.write_file(kmod::MODULES_LOAD_CONF, contents, false, "load modules at boot");
+        }
+    }
+
+    if !plan.blacklist_modules.is_empty() {
+        let existing = fs::read_to_string(host.path(kmod::BLACKLIST_CONF))
+            .await
+            .unwrap_or_default();
+        let contents = plan.blacklist_modules.iter().fold(existing.clone(), |contents, module| {
+            kmod::add_to_blacklist(&contents, &module.name).unwrap_or(contents)
+        });
+        if contents != existing {
+            plan.write_file(kmod::BLACKLIST_CONF, contents, false, "blacklist modules");
+        }
+    }
+
+    if options.early_kms {
+        let early_modules = early_kms_modules(&recommendations);
+        match generator {
+            Some(generator) if !early_modules.is_empty() => {
+                let path = generator.config_path();
+                let existing = fs::read_to_string(host.path(path)).await.ok();
+                let contents = generator
+                    .add_modules(existing.as_deref().unwrap_or_default(), &early_modules);
+                if let Some(contents) = contents {
+                    let reason = format!("early KMS: {}", early_modules.join(" "));
+                    plan.write_file(path, contents, existing.is_some(), &reason);
+                }
+            }
+            Some(_) => plan.note("No detected device needs early KMS modules".to_string()),
+            None => plan.note("No initramfs generator found, skipping early KMS setup".to_string()),
+        }
+    }
+
+    plan_firmware(scan, options.allow_aur, &mut plan);
+    plan_kernel_modules(host, scan, &mut plan).await;
+    if let Some(branch) = nvidia_branch.filter(|_| options.nvidia_hook) {
+        plan_nvidia_hook(host, scan, branch, generator, &mut plan).await?;
+    }
+    plan_aur(host, options.allow_aur, &mut plan).await;
+    plan_microcode(host, scan, generator, &mut plan).await;
+    plan_bootloader(host, &mut plan).await;
+
+    plan.resolve_targets(host);
+    plan.check_conflicts();
+
+    if plan.has_changes() {
+        match generator {
+            Some(generator) => {
+                let kernels = initramfs::installed_kernels(&host.root)
+                    .await
+                    .context("Failed to list installed kernels")?;
+                for command in generator.regenerate_commands(&kernels) {
+                    plan.initramfs.push(plan::Action {
+                        command: CommandLine::new(command),
+                        reason: format!("regenerate initramfs with {}", generator.name()),
+                    });
+                }
+            }
+            None => {
+                plan.note("No initramfs generator found, skipping initramfs update".to_string())
+            }
+        }
+    }
+    plan.record_state(host, &scan.components, scan.packages.as_ref())
+        .await;
+
+    Ok(plan)
+}
+
+/// Installs `package` unless it is already installed, from the AUR when the
+/// knowledge base marks it and the sync databases lack it, recording a conflict
+/// when neither can provide it.
+fn require_package(plan: &mut plan::Plan, scan: &Scan, package: &str, reason: &str) {
+    match &scan.packages {
+        Some(db) if db.is_installed(package) => {}
+        Some(db) if !db.is_available(package) => match scan.aur.iter().find(|p| p.name == package) {
+            Some(aur) => plan.aur(aur, reason),
+            None => plan.conflict(format!(
+                "{} is neither a kernel module nor in the sync databases",
+                package
+            )),
+        },
+        Some(_) => plan.install(package, reason),
+        None => plan.conflict(format!("Cannot install {} without pacman", package)),
+    }
+}
 
-    if !status.success() {
-        anyhow::bail!("Failed to install package: {}", package);
+/// Writes `contents` to `path` unless the file already has exactly that content.
+async fn write_if_changed(
+    host: &Host<'_>,
+    plan: &mut plan::Plan,
+    path: &str,
+    contents: String,
+    reason: &str,
+) {
+    let existing = fs::read_to_string(host.path(path)).await.ok();
+    if existing.as_deref() != Some(contents.as_str()) {
+        plan.write_file(path, contents, false, reason);
     }
+}
+
+/// Blacklists `module` unless the ardenthat blacklist already has it.
+async fn blacklist_module(host: &Host<'_>, plan: &mut plan::Plan, module: &str, reason: String) {
+    let blacklist = fs::read_to_string(host.path(kmod::BLACKLIST_CONF))
+        .await
+        .unwrap_or_default();
+    let planned = plan.blacklist_modules.iter().any(|m| m.name == module);
+    if !planned && kmod::add_to_blacklist(&blacklist, module).is_some() {
+        plan.blacklist_modules.push(plan::PlanItem {
+            name: module.to_string(),
+            reason,
+        });
+    }
+}
+
+/// Blacklists nouveau and enables NVIDIA DRM KMS for the GPU that gets an NVIDIA
+/// driver branch, returning the branch.
+async fn plan_nvidia(
+    host: &Host<'_>,
+    recommendations: &[knowledge::Recommendation],
+    plan: &mut plan::Plan,
+) -> Option<nvidia::Branch> {
+    let (package, branch) = recommendations
+        .iter()
+        .filter(|r| r.select == Some(knowledge::Selector::Nvidia))
+        .find_map(|r| {
+            let package = r.packages.first()?;
+            Some((package, nvidia::Branch::from_modules_package(package)?))
+        })?;
+
+    blacklist_module(host, plan, "nouveau", format!("{} replaces nouveau", package)).await;
+    write_if_changed(
+        host,
+        plan,
+        nvidia::MODPROBE_CONF,
+        branch.modprobe_options(),
+        "NVIDIA DRM kernel mode setting for Wayland",
+    )
+    .await;
+    Some(branch)
+}
+
+/// Installs the pacman hook that rebuilds the initramfs when the kernel modules
+/// of `branch` or its user-space driver change. Runs after the module packages
+/// were matched to the installed kernels, so every package the hook targets is
+/// one that ends up installed.
+async fn plan_nvidia_hook(
+    host: &Host<'_>,
+    scan: &Scan,
+    branch: nvidia::Branch,
+    generator: Option<initramfs::Generator>,
+    plan: &mut plan::Plan,
+) -> Result<()> {
+    let Some(generator) = generator else {
+        plan.note("No initramfs generator found, skipping the NVIDIA pacman hook".to_string());
+        return Ok(());
+    };
+    let kernels = initramfs::installed_kernels(&host.root)
+        .await
+        .context("Failed to list installed kernels")?;
+
+    let installed = scan
+        .packages
+        .iter()
+        .flat_map(|db| db.installed.keys())
+        .filter(|name| !plan.remove.iter().any(|p| &p.name == *name));
+    let candidates = plan
+        .install
+        .iter()
+        .map(|p| &p.name)
+        .chain(plan.aur.iter().map(|t| &t.name))
+        .chain(installed);
+    let mut targets: Vec<&str> = Vec::new();
+    for name in candidates {
+        let ours = nvidia::Branch::from_modules_package(name) == Some(branch);
+        if ours && !targets.contains(&name.as_str()) {
+            targets.push(name);
+        }
+    }
+    targets.push(branch.utils());
+
+    let contents = nvidia::pacman_hook(&targets, &kernels, generator);
+    write_if_changed(
+        host,
+        plan,
+        nvidia::PACMAN_HOOK,
+        contents,
+        "rebuild the initramfs on NVIDIA driver upgrades",
+    )
+    .await;
     Ok(())
 }
 
-async fn parse_pci_output(_output: &[u8]) -> Result<Vec<HardwareComponent>> {
-    Ok(vec![HardwareComponent {
+/// Installs the packages that ship firmware the bound drivers are missing.
+fn plan_firmware(scan: &Scan, allow_aur: bool, plan: &mut plan::Plan) {
+    for missing in &scan.firmware {
+        let driver = missing.module.as_deref().unwrap_or("The kernel");
+        match &missing.package {
+            Some(package) if package.aur && allow_aur => {
+                let aur = knowledge::AurPackage {
+                    name: package.name.clone(),
+                    pkgbase: package.name.clone(),
+                };
+                plan.aur(&aur, &format!("missing firmware for {}", driver));
+            }
+            Some(package) if package.aur => plan.note(format!(
+                "{} needs {} from the AUR package {}, rerun setup with --allow-aur to build it",
+                driver, missing.blob, package.name
+            )),
+            Some(package) => {
+                let reason = format!("missing firmware for {}", driver);
+                require_package(plan, scan, &package.name, &reason);
+            }
+            None => plan.note(format!(
+                "{} failed to load {} and no known package ships it",
+                driver, missing.blob
+            )),
+        }
+    }
+}
+
+/// Matches the planned and installed kernel module packages to the installed
+/// kernels: prebuilt packages when every kernel has one, else the DKMS package
+/// plus the headers of every kernel it has to be built for. Installed packages
+/// the choice leaves out are removed.
+async fn plan_kernel_modules(host: &Host<'_>, scan: &Scan, plan: &mut plan::Plan) {
+    let kernels = initramfs::installed_kernels(&host.root)
+        .await
+        .unwrap_or_default();
+    let installed: Vec<&String> = scan
+        .packages
+        .iter()
+        .flat_map(|db| db.installed.keys())
+        .collect();
+
+    let mut families: Vec<(&kernel::ModulePackages, String)> = Vec::new();
+    for item in &plan.install {
+        if let Some(family) = kernel::ModulePackages::find(&item.name) {
+            if !families.iter().any(|(f, _)| *f == family) {
+                families.push((family, item.reason.clone()));
+            }
+        }
+    }
+    for name in &installed {
+        let Some(family) = kernel::ModulePackages::find(name) else {
+            continue;
+        };
+        // DKMS already builds the modules for every kernel
+        let dkms_installed = installed.iter().any(|n| *n == family.dkms);
+        if dkms_installed || families.iter().any(|(f, _)| *f == family) {
+            continue;
+        }
+        families.push((family, format!("{} is installed", name)));
+    }
+
+    for (family, reason) in families {
+        let choice = family.choose(&kernels, scan.packages.as_ref());
+        plan.install
+            .retain(|p| !family.contains(&p.name) || choice.packages.contains(&p.name));
+        for package in &choice.packages {
+            require_package(plan, scan, package, &format!("{}; {}", reason, choice.reason));
+        }
+        for name in installed.iter().filter(|n| family.contains(n)) {
+            if !choice.packages.contains(name) {
+                plan.remove(name, &format!("replaced by {}", choice.packages.join(", ")));
+            }
+        }
+    }
+
+    let dkms: Vec<String> = plan
+        .install
+        .iter()
+        .map(|p| p.name.clone())
+        .chain(plan.aur.iter().map(|t| t.name.clone()))
+        .filter(|name| kernel::is_dkms(name))
+        .collect();
+    for package in dkms {
+        for installed in &kernels {
+            let headers = kernel::headers(installed);
+            match &scan.packages {
+                Some(db) if db.is_installed(&headers) => {}
+                Some(db) if !db.is_available(&headers) => {
+                    let kind = match kernel::Variant::of(&installed.pkgbase) {
+                        kernel::Variant::Custom => "custom kernel",
+                        _ => "kernel",
+                    };
+                    plan.conflict(format!(
+                        "{} cannot be built for the {} {}: {} is not in the sync databases",
+                        package, kind, installed.pkgbase, headers
+                    ));
+                }
+                _ => plan.install(
+                    &headers,
+                    &format!("build {} for {}", package, installed.pkgbase),
+                ),
+            }
+        }
+    }
+}
+
+/// Picks the user and tool that build the planned AUR packages, which needs
+/// `--allow-aur` and a user who started setup. Helpers need an escalation tool.
+async fn plan_aur(host: &Host<'_>, allow_aur: bool, plan: &mut plan::Plan) {
+    if plan.aur.is_empty() {
+        return;
+    }
+    if !allow_aur {
+        let names: Vec<String> = plan.aur.iter().map(|t| t.name.clone()).collect();
+        for name in names {
+            plan.conflict(format!(
+                "{} is only in the AUR, rerun setup with --allow-aur to build it",
+                name
+            ));
+        }
+        return;
+    }
+    let configured = config::Config::load(&host.root).await.ok().and_then(|c| c.escalation);
+    let escalation = configured.or_else(|| privilege::detect(&host.root));
+    let helper = escalation.and(aur::detect_helper(&host.root));
+    match aur::invoking_user(&host.root).await {
+        Some(user) => plan.aur_builder = Some(aur::Builder { user, helper, escalation }),
+        None => plan.conflict(
+            "AUR packages are built as the user who started setup, run it from a user session"
+                .to_string(),
+        ),
+    }
+}
+
+/// Reports the packaged and running microcode revisions and makes sure the
+/// microcode image is loaded before the initramfs: through the mkinitcpio hook,
+/// else through GRUB's initrd line or the first initrd of systemd-boot entries.
+async fn plan_microcode(
+    host: &Host<'_>,
+    scan: &Scan,
+    generator: Option<initramfs::Generator>,
+    plan: &mut plan::Plan,
+) {
+    let Some(cpu) = scan.components.iter().find_map(|c| c.cpu.as_ref()) else {
+        return;
+    };
+    let Some(package) = microcode::package(&cpu.vendor_id) else {
+        return;
+    };
+    let image = microcode::image_path(package);
+
+    if let (Ok(contents), Some(signature)) =
+        (fs::read(host.path(&image)).await, microcode::signature(cpu))
+    {
+        let packaged = microcode::packaged_revision(&contents, &cpu.vendor_id, signature);
+        match (microcode::running_revision(cpu), packaged) {
+            (Some(running), Some(packaged)) if packaged > running => plan.note(format!(
+                "CPU runs microcode revision {:#x}, {} has the newer {:#x}",
+                running, package, packaged
+            )),
+            (Some(running), Some(_)) => {
+                plan.note(format!("CPU microcode revision {:#x} is up to date", running))
+            }
+            (_, None) => plan.note(format!("{} has no microcode update for this CPU", package)),
+            _ => {}
+        }
+    }
+
+    let installed = scan.packages.as_ref().is_some_and(|db| db.is_installed(package));
+    if !installed && !plan.install.iter().any(|p| p.name == package) {
+        return;
+    }
+
+    let early = match generator {
+        Some(initramfs::Generator::Mkinitcpio) => {
+            let path = initramfs::Generator::Mkinitcpio.config_path();
+            let existing = match plan.planned_contents(path) {
+                Some(contents) => Some(contents.to_string()),
+                None => fs::read_to_string(host.path(path)).await.ok(),
+            };
+            let config = existing.unwrap_or_default();
+            match initramfs::add_microcode_hook(&config) {
+                Some(contents) => {
+                    let backup = host.path(path).exists();
+                    plan.write_file(path, contents, backup, "load CPU microcode early");
+                    true
+                }
+                None => initramfs::mkinitcpio_hooks(&config).contains(&"microcode"),
+            }
+        }
+        // dracut adds early microcode unless early_microcode="no"
+        Some(initramfs::Generator::Dracut) => true,
+        _ => false,
+    };
+
+    if !early {
+        let grub_cfg = fs::read_to_string(host.path(bootloader::GRUB_CFG)).await;
+        if grub_cfg.is_ok_and(|cfg| !microcode::grub_loads(&cfg, &image)) {
+            plan.bootloader_action(
+                CommandLine::new(["grub-mkconfig", "-o", bootloader::GRUB_CFG]),
+                &format!("add {} to the GRUB initrd lines", image),
+            );
+        }
+    }
+
+    for path in bootloader::loader_entries(&host.root).await {
+        let Ok(entry) = fs::read_to_string(host.path(&path)).await else {
+            continue;
+        };
+        if let Some(contents) = microcode::fix_loader_entry(&entry, &image, !early) {
+            plan.write_file(&path, contents, true, "load CPU microcode before the initramfs");
+        }
+    }
+}
+
+/// Adds the planned kernel parameters to the configuration of the detected
+/// bootloader. Parameters the configuration already sets need only a reboot,
+/// and ones it sets to another value are left to the user as conflicts.
+async fn plan_bootloader(host: &Host<'_>, plan: &mut plan::Plan) {
+    if plan.kernel_params.is_empty() {
+        return;
+    }
+    let params: Vec<String> = plan.kernel_params.iter().map(|p| p.name.clone()).collect();
+    let Some(detected) = bootloader::detect(&host.root).await else {
+        plan.conflict(format!(
+            "No supported bootloader found to add {} to the kernel command line",
+            params.join(" ")
+        ));
+        return;
+    };
+
+    let mut changed = false;
+    let mut clashed = false;
+    for path in &detected.paths {
+        let existing = match plan.planned_contents(path) {
+            Some(contents) => contents.to_string(),
+            None => fs::read_to_string(host.path(path)).await.unwrap_or_default(),
+        };
+        match detected.bootloader.add_params(&existing, &params) {
+            Ok(Some(contents)) => {
+                plan.write_file(path, contents, true, "add kernel parameters");
+                changed = true;
+            }
+            Ok(None) => {}
+            Err(set) => {
+                plan.conflict(format!(
+                    "{} sets {}, remove it to use {}",
+                    path,
+                    set.join(" "),
+                    params.join(" ")
+                ));
+                clashed = true;
+            }
+        }
+    }
+
+    if !changed && !clashed {
+        plan.note(format!(
+            "{} already has {}, reboot to apply",
+            detected.bootloader.name(),
+            params.join(" ")
+        ));
+        plan.kernel_params.clear();
+    } else if let Some(command) = detected.bootloader.regenerate_command().filter(|_| changed) {
+        plan.bootloader_action(command, "apply the new kernel parameters");
+    }
+}
+
+/// Adds the kernel parameters that move Southern and Sea Islands GPUs from
+/// radeon to amdgpu, unless the running kernel already has them.
+async fn plan_amdgpu_switch(
+    host: &Host<'_>,
+    scan: &Scan,
+    recommendations: &[knowledge::Recommendation],
+    plan: &mut plan::Plan,
+) {
+    let cmdline = fs::read_to_string(host.path("/proc/cmdline"))
+        .await
+        .unwrap_or_default();
+    let gpus = recommendations
+        .iter()
+        .filter(|r| r.select == Some(knowledge::Selector::Amd))
+        .flat_map(|r| &r.components)
+        .filter_map(|&i| scan.components[i].pci.as_ref());
+    for device in gpus {
+        let Some(family) = amd::Family::from_device_id(device.device_id) else {
+            continue;
+        };
+        for param in family.amdgpu_params() {
+            if !cmdline.split_whitespace().any(|p| p == param) {
+                let reason = format!("run the {} GPU on amdgpu", family.name());
+                plan.kernel_param(&param, &reason);
+            }
+        }
+    }
+}
+
+/// Configures the discrete GPU of a hybrid graphics laptop for `profile`.
+async fn plan_hybrid(
+    host: &Host<'_>,
+    scan: &Scan,
+    topology: Option<hybrid::Topology>,
+    profile: hybrid::Profile,
+    plan: &mut plan::Plan,
+) {
+    let Some(device) = topology.and_then(|t| scan.components[t.discrete].pci.as_ref()) else {
+        plan.conflict(
+            "Hybrid graphics profiles need an integrated and a discrete GPU".to_string(),
+        );
+        return;
+    };
+    let nvidia = device.vendor_id == 0x10de;
+
+    match profile {
+        hybrid::Profile::IntegratedOnly => {
+            write_if_changed(
+                host,
+                plan,
+                hybrid::REMOVE_DGPU_RULES,
+                hybrid::remove_dgpu_rules(device.vendor_id, device.device_id),
+                "power off the discrete GPU",
+            )
+            .await;
+            if nvidia {
+                let reason = "the discrete GPU is powered off".to_string();
+                blacklist_module(host, plan, "nouveau", reason).await;
+            }
+        }
+        hybrid::Profile::Offload if nvidia => {
+            require_package(plan, scan, "nvidia-prime", "prime-run wrapper for PRIME render offload");
+            let turing_or_newer = nvidia::Architecture::from_device_id(device.device_id)
+                .is_some_and(|arch| arch.branch() == Some(nvidia::Branch::Open));
+            if turing_or_newer {
+                let reason = "runtime D3 power management of the discrete GPU";
+                write_if_changed(
+                    host,
+                    plan,
+                    hybrid::NVIDIA_PM_RULES,
+                    hybrid::nvidia_pm_rules().to_string(),
+                    reason,
+                )
+                .await;
+                write_if_changed(
+                    host,
+                    plan,
+                    hybrid::NVIDIA_PM_CONF,
+                    hybrid::nvidia_pm_options().to_string(),
+                    reason,
+                )
+                .await;
+            } else {
+                plan.note(
+                    "Runtime D3 needs a Turing or newer NVIDIA GPU, leaving dGPU power \
+                     management unchanged"
+                        .to_string(),
+                );
+            }
+        }
+        hybrid::Profile::Offload => {
+            plan.note(
+                "PRIME offload works out of the box, run programs on the discrete GPU \
+                 with DRI_PRIME=1"
+                    .to_string(),
+            );
+        }
+        hybrid::Profile::DgpuAlways => {
+            let (xorg_driver, drm_driver) = match device.vendor_id {
+                0x10de => ("nvidia", "nvidia-drm"),
+                0x1002 => ("amdgpu", "amdgpu"),
+                _ => ("modesetting", device.driver.as_deref().unwrap_or("i915")),
+            };
+            write_if_changed(
+                host,
+                plan,
+                hybrid::PRIMARY_GPU_CONF,
+                hybrid::primary_gpu_conf(xorg_driver, drm_driver),
+                "render everything on the discrete GPU",
+            )
+            .await;
+        }
+    }
+}
+
+async fn scan_pci_devices(host: &Host<'_>) -> Result<Vec<HardwareComponent>> {
+    // Prefer lspci when pciutils is installed, fall back to walking sysfs
+    match host.run(CommandLine::new(["lspci", "-vmmnnk"]).capture()) {
+        Ok(output) if output.success() => parse_pci_output(output.stdout.as_bytes()).await,
+        _ => {
+            let devices = pci::SysfsPciDetector::new(host.path("/sys"))
+                .scan()
+                .await
+                .context("Failed to enumerate PCI devices from sysfs")?;
+            Ok(devices.into_iter().map(pci_component).collect())
+        }
+    }
+}
+
+async fn parse_pci_output(output: &[u8]) -> Result<Vec<HardwareComponent>> {
+    let output = String::from_utf8_lossy(output);
+    let devices = pci::parse_lspci_records(&output).context("Failed to parse lspci output")?;
+
+    Ok(devices.into_iter().map(pci_component).collect())
+}
+
+fn pci_component(device: pci::PciDevice) -> HardwareComponent {
+    let vendor = if device.vendor_name.is_empty() {
+        format!("{:04x}", device.vendor_id)
+    } else {
+        device.vendor_name.clone()
+    };
+    let model = if device.device_name.is_empty() {
+        format!("{:04x}", device.device_id)
+    } else {
+        device.device_name.clone()
+    };
+
+    HardwareComponent {
         device_type: "PCI".to_string(),
-        vendor: "VENDOR".to_string(),
-        model: "DEVICE".to_string(),
-        driver: None,
-        status: DriverStatus::Unknown,
-    }])
+        vendor,
+        model,
+        driver: device.driver.clone(),
+        kernel_modules: device.modules.clone(),
+        pci: Some(device),
+        ..Default::default()
+    }
 }
 
-async fn parse_usb_output(_output: &[u8]) -> Result<Vec<HardwareComponent>> {
-    Ok(vec![HardwareComponent {
-        device_type: "USB".to_string(),
-        vendor: "VENDOR".to_string(),
-        model: "DEVICE".to_string(),
-        driver: None,
-        status: DriverStatus::Unknown,
-    }])
+async fn scan_usb_devices(host: &Host<'_>, include_hubs: bool) -> Result<Vec<HardwareComponent>> {
+    // Prefer sysfs, fall back to lsusb when /sys/bus/usb is not available
+    let devices = match usb::SysfsUsbDetector::new(host.path("/sys")).scan().await {
+        Ok(devices) => devices,
+        Err(_) => match host.run(CommandLine::new(["lsusb"]).capture()) {
+            Ok(output) if output.success() => {
+                return parse_usb_output(output.stdout.as_bytes(), include_hubs).await
+            }
+            Ok(_) => {
+                println!("Warning: lsusb failed, skipping USB devices");
+                return Ok(Vec::new());
+            }
+            Err(err) => {
+                println!("Warning: {:#}, skipping USB devices", err);
+                return Ok(Vec::new());
+            }
+        },
+    };
+
+    Ok(usb_components(devices, include_hubs))
+}
+
+async fn parse_usb_output(output: &[u8], include_hubs: bool) -> Result<Vec<HardwareComponent>> {
+    let output = String::from_utf8_lossy(output);
+    let devices = usb::parse_lsusb_output(&output).context("Failed to parse lsusb output")?;
+
+    Ok(usb_components(devices, include_hubs))
+}
+
+fn usb_components(devices: Vec<usb::UsbDevice>, include_hubs: bool) -> Vec<HardwareComponent> {
+    devices
+        .into_iter()
+        .filter(|device| include_hubs || !device.is_hub())
+        .map(|device| HardwareComponent {
+            device_type: "USB".to_string(),
+            vendor: device
+                .manufacturer
+                .clone()
+                .unwrap_or_else(|| format!("{:04x}", device.vendor_id)),
+            model: device
+                .product
+                .clone()
+                .unwrap_or_else(|| format!("{:04x}", device.product_id)),
+            driver: device.driver().map(str::to_string),
+            usb: Some(device),
+            ..Default::default()
+        })
+        .collect()
 }
 
-async fn parse_cpu_info(_info: &str) -> Result<Vec<HardwareComponent>> {
+async fn parse_cpu_info(info: &str) -> Result<Vec<HardwareComponent>> {
+    let cpu = cpu::parse_cpuinfo(info).context("Failed to parse CPU info")?;
+
     Ok(vec![HardwareComponent {
         device_type: "CPU".to_string(),
-        vendor: "Intel".to_string(),
-        model: "Core i7".to_string(),
-        driver: None,
-        status: DriverStatus::Installed,
+        vendor: cpu.vendor_name().to_string(),
+        model: cpu.model_name.clone(),
+        cpu: Some(cpu),
+        ..Default::default()
     }])
 }
 
-async fn identify_required_drivers(_components: &[HardwareComponent]) -> Result<Vec<String>> {
-    Ok(vec!["example-driver".to_string()])
+fn identify_required_drivers(
+    components: &[HardwareComponent],
+    recommendations: &[knowledge::Recommendation],
+) -> Vec<RequiredDriver> {
+    let mut drivers: Vec<RequiredDriver> = Vec::new();
+
+    for recommendation in recommendations {
+        for package in &recommendation.packages {
+            if !drivers.iter().any(|d| &d.name == package) {
+                drivers.push(RequiredDriver {
+                    name: package.clone(),
+                    reason: format!(
+                        "{}: {} [rule {}]",
+                        recommendation.device, recommendation.reason, recommendation.rule
+                    ),
+                    module: false,
+                });
+            }
+        }
+    }
+
+    // Devices without a bound driver whose kernel ships a matching module
+    for component in components.iter().filter(|c| c.driver.is_none()) {
+        if let Some(module) = component.kernel_modules.first() {
+            if !drivers.iter().any(|d| &d.name == module) {
+                drivers.push(RequiredDriver {
+                    name: module.clone(),
+                    reason: format!(
+                        "{} {}: kernel module is not loaded",
+                        component.vendor, component.model
+                    ),
+                    module: true,
+                });
+            }
+        }
+    }
+
+    drivers
+}
+
+/// Modules the matched rules want in the initramfs, in rule order without duplicates.
+fn early_kms_modules(recommendations: &[knowledge::Recommendation]) -> Vec<String> {
+    let mut modules: Vec<String> = Vec::new();
+    for module in recommendations.iter().flat_map(|r| &r.early_modules) {
+        if !modules.contains(module) {
+            modules.push(module.clone());
+        }
+    }
+    modules
 }
 
 async fn display_hardware_table(components: &[HardwareComponent]) -> Result<()> {
     println!("Detected Hardware:");
     for component in components {
-        println!("- {}: {} {} ({:?})", 
+        let class = component
+            .class
+            .as_deref()
+            .map(|class| format!(" [{}]", class))
+            .unwrap_or_default();
+        let no_driver = if component.cpu.is_none()
+            && component.driver.is_none()
+            && component.kernel_modules.is_empty()
+        {
+            " - no kernel driver"
+        } else {
+            ""
+        };
+        println!("- {}{}: {} {} ({:?}){}", 
             component.device_type,
+            class,
             component.vendor,
             component.model,
-            component.status
+            component.status,
+            no_driver
         );
+        if !component.status_reason.is_empty() {
+            println!("    {}", component.status_reason);
+        }
+    }
+    if let Some(topology) = hybrid::topology(components) {
+        println!("Hybrid graphics:");
+        for line in topology.describe(components) {
+            println!("  {}", line);
+        }
     }
     Ok(())
 }
 
-async fn generate_report(output: Option<String>) -> Result<()> {
-    let components = scan_system().await?;
+/// What `report` writes: the detected hardware and the snapshot taken before
+/// the latest setup, to roll back to by hand.
+#[derive(Serialize)]
+struct Report {
+    components: Vec<HardwareComponent>,
+    /// Taken before the latest setup transaction that has one and was not undone
+    snapshot: Option<snapshot::Snapshot>,
+}
+
+async fn generate_report(host: &Host<'_>, output: Option<String>, include_hubs: bool) -> Result<()> {
+    let components = scan_system(host, include_hubs).await?.components;
     let output_path = output.unwrap_or_else(|| "ahd-report.txt".to_string());
-    
-    let report = serde_json::to_string_pretty(&components)?;
-    tokio::fs::write(&output_path, report).await?;
-    
+    let transactions = journal::Journal::new(&host.root).list().await?;
+    let report = Report {
+        components,
+        snapshot: transactions.into_iter().rev().filter(|t| !t.undone).find_map(|t| t.snapshot),
+    };
+
+    tokio::fs::write(&output_path, serde_json::to_string_pretty(&report)?).await?;
+
     println!("Report generated at: {}", output_path);
+    if let Some(snapshot) = &report.snapshot {
+        println!(
+            "Latest setup snapshot: {} {}",
+            snapshot.backend.name(),
+            snapshot.id
+        );
+    }
     Ok(())
 }
 
-async fn update_initramfs() -> Result<()> {
-    println!("Updating initramfs...");
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MockRunner;
+    use crate::testutil::TempTree;
+
+    const RELEASE: &str = "6.11.5-arch1-1";
+
+    /// `--persist-modules --early-kms`
+    const PERSIST_EARLY_KMS: SetupOptions = SetupOptions {
+        persist_modules: true,
+        early_kms: true,
+        nvidia_hook: false,
+        amdgpu: false,
+        hybrid: None,
+        allow_aur: false,
+    };
+
+    fn test_host<'a>(root: &TempTree, runner: &'a MockRunner) -> Host<'a> {
+        Host { runner, root: root.path().to_path_buf() }
+    }
+
+    /// Scripted scan of the devices in `lspci` without USB devices; `installed` is
+    /// the `pacman -Q` output and `available` the `pacman -Slq` output.
+    fn scan_runner(lspci: &str, installed: &str, available: &str) -> MockRunner {
+        MockRunner::new()
+            .reply(&["lspci", "-vmmnnk"], 0, lspci)
+            .reply(&["pacman", "-Q"], 0, installed)
+            .reply(&["pacman", "-Slq"], 0, available)
+    }
+
+    /// A ThinkPad T480 without sysfs, so devices come from the scripted lspci and lsusb.
+    fn thinkpad_root(name: &str) -> TempTree {
+        let root = TempTree::new(name);
+        root.symlink("lib", "usr/lib");
+        root.write("proc/cpuinfo", include_str!("fixtures/cpuinfo/kabylake-r-i5-8350u.txt"));
+        root.write("proc/sys/kernel/osrelease", &format!("{}\n", RELEASE));
+        root.write("proc/modules", "i915 4759552 42 - Live 0x0000000000000000\n");
+        let modules = format!("usr/lib/modules/{}", RELEASE);
+        root.write(
+            &format!("{}/modules.alias", modules),
+            include_str!("fixtures/modules/modules.alias"),
+        );
+        root.write(
+            &format!("{}/modules.dep", modules),
+            "kernel/drivers/gpu/drm/i915/i915.ko.zst:\nkernel/drivers/net/wireless/intel/iwlwifi/iwlwifi.ko.zst:\n",
+        );
+        root.write(&format!("{}/pkgbase", modules), "linux\n");
+        root.write("usr/bin/mkinitcpio", "");
+        root.write("etc/mkinitcpio.conf", "MODULES=()\nHOOKS=(base udev autodetect)\n");
+        root
+    }
+
+    const THINKPAD_PACKAGES: [&str; 5] = [
+        "intel-ucode",
+        "intel-media-driver",
+        "vulkan-intel",
+        "sof-firmware",
+        "alsa-ucm-conf",
+    ];
+
+    /// Scripted queries for the T480; `resolve_code` is the exit code of `pacman -S --print`.
+    fn thinkpad_runner(resolve_code: i32) -> MockRunner {
+        let mut resolve = vec!["pacman", "-S", "--print", "--print-format", "%n %v %s"];
+        resolve.extend(THINKPAD_PACKAGES);
+        scan_runner(
+            include_str!("fixtures/lspci/thinkpad-t480.txt"),
+            "linux 6.11.5.arch1-1\nmesa 1:24.2.5-1\n",
+            "intel-media-driver\nvulkan-intel\nintel-ucode\nsof-firmware\nalsa-ucm-conf\nlibva\n",
+        )
+        .reply(&["lsusb"], 0, include_str!("fixtures/lsusb/thinkpad-t480.txt"))
+        .reply(
+            &resolve,
+            resolve_code,
+            "intel-ucode 20250812-1 8123456\n\
+             libva 2.22.0-1 212876\n\
+             intel-media-driver 25.2.6-1 3041556\n\
+             vulkan-intel 1:25.1.7-1 2379812\n\
+             sof-firmware 2025.05-1 7016640\n\
+             alsa-ucm-conf 1.2.14-1 101288\n",
+        )
+    }
+
+    /// Compares against `fixtures/golden/<name>`, rewriting it when `UPDATE_GOLDEN` is set.
+    fn assert_golden(name: &str, actual: &str) {
+        let path = Path::new(file!()).with_file_name("fixtures/golden").join(name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, actual).unwrap();
+            return;
+        }
+        let expected = std::fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("missing golden file {}", path.display()));
+        assert_eq!(actual, expected, "golden file {} differs", path.display());
+    }
+
+    #[tokio::test]
+    async fn dry_run_setup_only_queries_the_system() {
+        let root = thinkpad_root("setup-dry-run");
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+
+        setup_drivers(&host, true, PERSIST_EARLY_KMS, None).await.unwrap();
+        assert_golden("setup-dry-run.txt", &runner.transcript());
+    }
+
+    #[tokio::test]
+    async fn scan_skips_usb_without_sysfs_or_lsusb() {
+        let root = thinkpad_root("scan-no-lsusb");
+        let lspci = include_str!("fixtures/lspci/thinkpad-t480.txt");
+        let runner = scan_runner(lspci, "", "").missing("lsusb");
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        assert!(scan.components.iter().all(|c| c.usb.is_none()));
+        assert!(scan.components.iter().any(|c| c.pci.is_some()));
+
+        let lsusb = include_str!("fixtures/lsusb/thinkpad-t480.txt");
+        let runner = scan_runner(lspci, "", "").reply(&["lsusb"], 1, lsusb);
+        let scan = scan_system(&test_host(&root, &runner), false).await.unwrap();
+        assert!(scan.components.iter().all(|c| c.usb.is_none()));
+    }
+
+    #[tokio::test]
+    async fn setup_installs_packages_and_rebuilds_initramfs() {
+        let root = thinkpad_root("setup-apply");
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+
+        setup_drivers(&host, false, PERSIST_EARLY_KMS, None).await.unwrap();
+        assert_golden("setup-apply.txt", &runner.transcript());
+    }
+
+    #[tokio::test]
+    async fn failed_package_install_aborts_setup() {
+        let root = thinkpad_root("setup-failure");
+        let mut install = vec!["pacman", "-S", "--needed", "--noconfirm"];
+        install.extend(THINKPAD_PACKAGES);
+        let runner = thinkpad_runner(0).reply(&install, 1, "");
+        let host = test_host(&root, &runner);
+
+        let err = setup_drivers(&host, false, SetupOptions::default(), None).await.unwrap_err();
+        assert_eq!(err.to_string(), "Failed to install packages");
+        assert!(!runner.transcript().contains("mkinitcpio -P"));
+    }
+
+    #[tokio::test]
+    async fn unresolvable_packages_abort_before_any_change() {
+        let root = thinkpad_root("setup-conflict");
+        let runner = thinkpad_runner(1);
+        let host = test_host(&root, &runner);
+
+        let err = setup_drivers(&host, false, PERSIST_EARLY_KMS, None).await.unwrap_err();
+        assert!(err.to_string().starts_with("Setup plan has conflicts"));
+        assert_eq!(runner.commands().len(), 12);
+    }
+
+    #[tokio::test]
+    async fn nvidia_setup_blacklists_nouveau_and_enables_modeset() {
+        let root = thinkpad_root("setup-nvidia");
+        let packages = ["intel-ucode", "nvidia-open", "nvidia-utils", "lib32-nvidia-utils"];
+        let mut resolve = vec!["pacman", "-S", "--print", "--print-format", "%n %v %s"];
+        resolve.extend(packages);
+        let runner = scan_runner(
+            include_str!("fixtures/lspci/rtx3060-desktop.txt"),
+            "linux 6.11.5.arch1-1\n",
+            &packages.join("\n"),
+        )
+        .reply(
+            &resolve,
+            0,
+            "intel-ucode 20250812-1 8123456\nnvidia-utils 575.64-1 206569472\n\
+             nvidia-open 575.64-1 4194304\nlib32-nvidia-utils 575.64-1 42991616\n",
+        );
+        let host = test_host(&root, &runner);
+
+        let options = SetupOptions {
+            early_kms: true,
+            nvidia_hook: true,
+            ..Default::default()
+        };
+        setup_drivers(&host, false, options, None).await.unwrap();
+        assert_golden("setup-nvidia.txt", &runner.transcript());
+
+        let transaction = &journal::Journal::new(root.path()).list().await.unwrap()[0];
+        assert_eq!(transaction.blacklisted, vec!["nouveau"]);
+    }
+
+    /// A ThinkPad with Intel Iris Xe on the panel and a suspended RTX 3050 Mobile.
+    fn optimus_root(name: &str) -> TempTree {
+        let root = thinkpad_root(name);
+        for (card, address) in [("card0", "0000:00:02.0"), ("card1", "0000:01:00.0")] {
+            root.symlink(
+                &format!("sys/class/drm/{}/device", card),
+                &format!("sys/devices/pci0000:00/{}", address),
+            );
+        }
+        root.write("sys/class/drm/card0-eDP-1/status", "connected\n");
+        root.write("sys/bus/pci/devices/0000:00:02.0/boot_vga", "1\n");
+        root.write("sys/bus/pci/devices/0000:01:00.0/power/runtime_status", "suspended\n");
+        root.write("sys/bus/pci/devices/0000:01:00.0/power/control", "auto\n");
+        root
+    }
+
+    fn optimus_runner() -> MockRunner {
+        scan_runner(
+            include_str!("fixtures/lspci/optimus-rtx3050.txt"),
+            "linux 6.11.5.arch1-1\n",
+            "intel-ucode\nintel-media-driver\nvulkan-intel\nnvidia-open\nnvidia-utils\nnvidia-prime\n",
+        )
+    }
+
+    #[tokio::test]
+    async fn hybrid_profiles_configure_the_discrete_gpu() {
+        let root = optimus_root("setup-hybrid");
+        let runner = optimus_runner();
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+
+        let offload = SetupOptions {
+            hybrid: Some(hybrid::Profile::Offload),
+            ..Default::default()
+        };
+        let plan = plan_setup(&host, &scan, offload).await.unwrap();
+        let installed: Vec<&str> = plan.install.iter().map(|p| p.name.as_str()).collect();
+        assert!(installed.contains(&"nvidia-open"));
+        assert!(installed.contains(&"nvidia-prime"));
+        let written: Vec<&str> = plan.files.iter().map(|f| f.path.as_str()).collect();
+        assert!(written.contains(&hybrid::NVIDIA_PM_RULES));
+        assert!(written.contains(&hybrid::NVIDIA_PM_CONF));
+
+        let integrated_only = SetupOptions {
+            hybrid: Some(hybrid::Profile::IntegratedOnly),
+            ..Default::default()
+        };
+        let plan = plan_setup(&host, &scan, integrated_only).await.unwrap();
+        assert!(!plan.install.iter().any(|p| p.name.starts_with("nvidia")));
+        assert_eq!(plan.blacklist_modules[0].name, "nouveau");
+        let written: Vec<&str> = plan.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            written,
+            vec![hybrid::REMOVE_DGPU_RULES, kmod::BLACKLIST_CONF, "/etc/mkinitcpio.conf"]
+        );
+    }
+
+    #[tokio::test]
+    async fn hybrid_profile_needs_two_gpus() {
+        let root = thinkpad_root("setup-hybrid-single");
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        let options = SetupOptions {
+            hybrid: Some(hybrid::Profile::DgpuAlways),
+            ..Default::default()
+        };
+        let plan = plan_setup(&host, &scan, options).await.unwrap();
+        assert_eq!(
+            plan.conflicts,
+            vec!["Hybrid graphics profiles need an integrated and a discrete GPU"]
+        );
+    }
+
+    #[tokio::test]
+    async fn amdgpu_switch_adds_kernel_parameters_to_grub_once() {
+        let root = thinkpad_root("setup-amdgpu");
+        root.write("proc/cmdline", "root=/dev/sda2 rw quiet\n");
+        let grub = "GRUB_DEFAULT=0\nGRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet\"\n";
+        root.write("etc/default/grub", grub);
+        let runner = scan_runner(
+            include_str!("fixtures/lspci/radeon-hd7870.txt"),
+            "linux 6.11.5.arch1-1\nmesa 1:24.2.5-1\n",
+            "mesa\nxf86-video-ati\nvulkan-radeon\nlibva-mesa-driver\nintel-ucode\n",
+        );
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        let plan = plan_setup(&host, &scan, SetupOptions::default()).await.unwrap();
+        assert!(plan.kernel_params.is_empty());
+
+        let options = SetupOptions { amdgpu: true, ..Default::default() };
+        let plan = plan_setup(&host, &scan, options).await.unwrap();
+        let params: Vec<&str> = plan.kernel_params.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(params, vec!["radeon.si_support=0", "amdgpu.si_support=1"]);
+        let installed: Vec<&str> = plan.install.iter().map(|p| p.name.as_str()).collect();
+        assert!(installed.contains(&"vulkan-radeon"));
+        assert!(installed.contains(&"libva-mesa-driver"));
+        assert_eq!(
+            plan.planned_contents("/etc/default/grub"),
+            Some(
+                "GRUB_DEFAULT=0\nGRUB_CMDLINE_LINUX_DEFAULT=\"loglevel=3 quiet \
+                 radeon.si_support=0 amdgpu.si_support=1\"\n"
+            )
+        );
+        let bootloader: Vec<String> = plan.bootloader.iter().map(|a| a.command.to_string()).collect();
+        assert_eq!(bootloader, vec!["grub-mkconfig -o /boot/grub/grub.cfg"]);
+
+        // Configured but not booted yet: nothing left to change
+        root.write("etc/default/grub", plan.planned_contents("/etc/default/grub").unwrap());
+        let plan = plan_setup(&host, &scan, options).await.unwrap();
+        assert!(plan.kernel_params.is_empty());
+        assert!(plan.bootloader.is_empty());
+        assert!(plan.planned_contents("/etc/default/grub").is_none());
+
+        // Booted with the parameters from elsewhere
+        root.write("etc/default/grub", grub);
+        root.write("proc/cmdline", "root=/dev/sda2 rw radeon.si_support=0 amdgpu.si_support=1\n");
+        let plan = plan_setup(&host, &scan, options).await.unwrap();
+        assert!(plan.kernel_params.is_empty());
+    }
+
+    #[tokio::test]
+    async fn kernel_modules_already_in_place_are_listed() {
+        let root = thinkpad_root("setup-module-states");
+        let modules = format!("usr/lib/modules/{}", RELEASE);
+        root.write(
+            &format!("{}/modules.alias", modules),
+            "alias pci:v00001234d00000001sv*sd*bc*sc*i* i915\n\
+             alias pci:v00001234d00000002sv*sd*bc*sc*i* xhci_pci\n\
+             alias pci:v00001234d00000003sv*sd*bc*sc*i* ardent_gone\n",
+        );
+        root.write(
+            &format!("{}/modules.builtin", modules),
+            "kernel/drivers/usb/host/xhci-pci.ko\n",
+        );
+        let devices: Vec<String> = (1..=3)
+            .map(|i| {
+                format!(
+                    "Slot:\t00:0{i}.0\nClass:\tOther [ff00]\nVendor:\tTest [1234]\n\
+                     Device:\tDevice {i} [000{i}]\n"
+                )
+            })
+            .collect();
+        let installed = "linux 6.11.5.arch1-1\nintel-ucode 20250812-1\n";
+        let runner = scan_runner(&devices.join("\n"), installed, "");
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        let plan = plan_setup(&host, &scan, SetupOptions::default()).await.unwrap();
+
+        let unchanged: Vec<String> = plan
+            .unchanged
+            .iter()
+            .map(|item| format!("{} ({})", item.name, item.reason))
+            .collect();
+        assert_eq!(
+            unchanged,
+            vec![
+                "i915 (kernel module is already loaded)",
+                "xhci_pci (kernel module is built into the kernel)",
+            ]
+        );
+        assert!(plan.load_modules.is_empty());
+        assert_eq!(plan.conflicts, vec!["Kernel module ardent_gone not found for the running kernel"]);
+        let shown = plan.to_string();
+        assert!(shown.contains("Already set up:\n  = i915 (kernel module is already loaded)\n"));
+    }
+
+    #[tokio::test]
+    async fn dkms_modules_get_headers_for_every_kernel() {
+        let root = thinkpad_root("setup-dkms");
+        root.write("usr/lib/modules/6.12.1-zen1-1-zen/pkgbase", "linux-zen\n");
+        root.write("usr/lib/modules/6.12.1-1-cachyos/pkgbase", "linux-cachyos\n");
+        let runner = scan_runner(
+            include_str!("fixtures/lspci/rtx3060-desktop.txt"),
+            "linux 6.11.5.arch1-1\nlinux-headers 6.11.5.arch1-1\nnvidia-open 575.64.05-1\n",
+            "intel-ucode\nnvidia-open\nnvidia-open-dkms\nnvidia-utils\n\
+             linux-headers\nlinux-zen-headers\n",
+        );
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        let plan = plan_setup(&host, &scan, SetupOptions::default()).await.unwrap();
+
+        let installed: Vec<&str> = plan.install.iter().map(|p| p.name.as_str()).collect();
+        assert!(installed.contains(&"nvidia-open-dkms"));
+        assert!(!installed.contains(&"nvidia-open"));
+        assert!(!installed.contains(&"linux-headers"));
+        assert_eq!(
+            plan.remove,
+            vec![plan::PlanItem {
+                name: "nvidia-open".to_string(),
+                reason: "replaced by nvidia-open-dkms".to_string(),
+            }]
+        );
+        let zen = plan.install.iter().find(|p| p.name == "linux-zen-headers").unwrap();
+        assert_eq!(zen.reason, "build nvidia-open-dkms for linux-zen");
+        assert!(plan.conflicts.contains(
+            &"nvidia-open-dkms cannot be built for the custom kernel linux-cachyos: \
+              linux-cachyos-headers is not in the sync databases"
+                .to_string()
+        ));
+    }
+
+    #[tokio::test]
+    async fn aur_drivers_need_opt_in_and_build_as_the_user() {
+        let root = thinkpad_root("setup-aur");
+        root.write("proc/self/loginuid", "1000");
+        root.write(
+            "etc/passwd",
+            "root:x:0:0::/root:/bin/bash\nalex:x:1000:1000::/home/alex:/bin/bash\n",
+        );
+        root.write("usr/bin/paru", "");
+        root.write("usr/bin/doas", "");
+        let runner = scan_runner(
+            include_str!("fixtures/lspci/gtx680-kepler.txt"),
+            "linux 6.11.5.arch1-1\n",
+            "intel-ucode\nlinux-headers\n",
+        );
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+
+        let plan = plan_setup(&host, &scan, SetupOptions::default()).await.unwrap();
+        assert!(plan.conflicts.contains(
+            &"nvidia-470xx-dkms is only in the AUR, rerun setup with --allow-aur to build it"
+                .to_string()
+        ));
+        let shown = plan.to_string();
+        assert!(shown.contains("AUR packages to build:\n  + nvidia-470xx-dkms ("));
+        assert!(shown.contains(
+            "    PKGBUILD: https://aur.archlinux.org/cgit/aur.git/tree/PKGBUILD?h=nvidia-470xx-utils\n"
+        ));
+
+        let options = SetupOptions {
+            allow_aur: true,
+            ..Default::default()
+        };
+        let plan = plan_setup(&host, &scan, options).await.unwrap();
+        assert_eq!(plan.conflicts, Vec::<String>::new());
+        let aur: Vec<&str> = plan.aur.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(aur, vec!["nvidia-470xx-dkms", "nvidia-470xx-utils"]);
+        let builder = plan.aur_builder.as_ref().unwrap();
+        assert_eq!(builder.user.name, "alex");
+        assert_eq!(builder.tool(), "paru");
+        assert_eq!(builder.escalation, Some(privilege::Escalation::Doas));
+        let headers = plan.install.iter().find(|p| p.name == "linux-headers").unwrap();
+        assert_eq!(headers.reason, "build nvidia-470xx-dkms for linux");
+    }
+
+    #[tokio::test]
+    async fn microcode_is_wired_into_the_bootloader_without_mkinitcpio() {
+        let root = thinkpad_root("setup-microcode");
+        root.write("boot/intel-ucode.img", "");
+        root.write("usr/bin/booster", "");
+        root.write("etc/booster.yaml", "universal: false\n");
+        root.write(
+            "boot/loader/entries/arch.conf",
+            "title Arch Linux\nlinux /vmlinuz-linux\ninitrd /booster-linux.img\noptions rw\n",
+        );
+        root.write(
+            "boot/grub/grub.cfg",
+            "menuentry 'Arch Linux' {\n\tlinux /vmlinuz-linux rw\n\tinitrd /booster-linux.img\n}\n",
+        );
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        let plan = plan_setup(&host, &scan, SetupOptions::default()).await.unwrap();
+
+        let entry = plan.planned_contents("/boot/loader/entries/arch.conf").unwrap();
+        assert!(entry.contains("initrd  /intel-ucode.img\ninitrd /booster-linux.img\n"));
+        let bootloader: Vec<String> = plan.bootloader.iter().map(|a| a.command.to_string()).collect();
+        assert_eq!(bootloader, vec!["grub-mkconfig -o /boot/grub/grub.cfg"]);
+        // The image has no update for the CPU, which the reviewed plan says
+        assert!(plan
+            .to_string()
+            .contains("Notes:\n  - intel-ucode has no microcode update for this CPU\n"));
+    }
+
+    #[tokio::test]
+    async fn missing_firmware_packages_join_the_plan() {
+        let root = thinkpad_root("setup-firmware");
+        root.write("usr/lib/firmware/i915/kbl_dmc_ver1_04.bin.zst", "");
+        let runner = scan_runner(
+            include_str!("fixtures/lspci/thinkpad-t480.txt"),
+            "linux 6.11.5.arch1-1\n",
+            "intel-media-driver\nvulkan-intel\nintel-ucode\nsof-firmware\nalsa-ucm-conf\nlinux-firmware-intel\n",
+        )
+        .reply(
+            &["modinfo", "-F", "firmware", "i915"],
+            0,
+            "i915/kbl_dmc_ver1_04.bin\n",
+        )
+        .reply(
+            &["journalctl", "-k", "-b", "-o", "cat", "--no-pager"],
+            0,
+            "iwlwifi 0000:02:00.0: Direct firmware load for iwlwifi-8265-36.ucode failed with error -2\n\
+             iwlwifi 0000:02:00.0: no suitable firmware found!\n",
+        );
+        let host = test_host(&root, &runner);
+        let scan = scan_system(&host, false).await.unwrap();
+        assert_eq!(scan.firmware.len(), 1);
+        let wifi = scan.components.iter().find(|c| c.driver.as_deref() == Some("iwlwifi")).unwrap();
+        assert!(matches!(wifi.status, DriverStatus::FirmwareMissing));
+        assert_eq!(
+            wifi.status_reason,
+            "iwlwifi is bound but firmware iwlwifi-8265-36.ucode is missing (linux-firmware-intel)"
+        );
+
+        let plan = plan_setup(&host, &scan, SetupOptions::default()).await.unwrap();
+        let firmware = plan.install.iter().find(|p| p.name == "linux-firmware-intel").unwrap();
+        assert_eq!(firmware.reason, "missing firmware for iwlwifi");
+    }
+
+    #[tokio::test]
+    async fn saved_plan_applies_only_while_the_system_matches() {
+        let root = thinkpad_root("setup-plan-file");
+        let plan_file = root.path().join("plan.json");
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+
+        setup_drivers(&host, true, PERSIST_EARLY_KMS, Some(&plan_file)).await.unwrap();
+        let saved = plan::Plan::load(&plan_file).await.unwrap();
+        assert_eq!(
+            saved.targets[0],
+            plan::Target {
+                name: "intel-ucode".to_string(),
+                version: "20250812-1".to_string(),
+                size: 8_123_456,
+            }
+        );
+        assert!(saved.state.hardware.contains(&"pci 00:02.0 8086:5917".to_string()));
+        assert_eq!(saved.state.packages["libva"], None);
+
+        // The initramfs configuration was edited after the plan was reviewed
+        let original = std::fs::read_to_string(root.path().join("etc/mkinitcpio.conf")).unwrap();
+        root.write("etc/mkinitcpio.conf", "MODULES=(btrfs)\nHOOKS=(base udev autodetect)\n");
+        let err = apply_saved_plan(&host, &plan_file, false).await.unwrap_err();
+        assert!(err.to_string().contains("  /etc/mkinitcpio.conf changed\n"));
+        assert!(!runner.transcript().contains("--noconfirm"));
+
+        root.write("etc/mkinitcpio.conf", &original);
+
+        // Saved plans run as root, so edited commands must not run
+        let text = std::fs::read_to_string(&plan_file).unwrap();
+        let tampered = plan_file.with_file_name("tampered.json");
+        std::fs::write(&tampered, text.replace("\"mkinitcpio\"", "\"/tmp/evil\"")).unwrap();
+        let err = apply_saved_plan(&host, &tampered, false).await.unwrap_err();
+        assert!(err.to_string().contains("  initramfs commands\n"));
+        assert!(!runner.transcript().contains("--noconfirm"));
+
+        apply_saved_plan(&host, &plan_file, false).await.unwrap();
+        assert!(runner.transcript().contains("$ pacman -S --needed --noconfirm intel-ucode"));
+    }
+
+    #[tokio::test]
+    async fn setup_records_a_snapper_snapshot() {
+        let root = thinkpad_root("setup-snapper");
+        root.write("usr/bin/snapper", "");
+        root.write("etc/snapper/configs/root", "SUBVOLUME=\"/\"\n");
+        root.write("etc/ardenthat/config.json", r#"{ "require_snapshot": true }"#);
+        let runner = thinkpad_runner(0).reply_prefix(&["snapper", "-c", "root", "create"], 0, "42\n");
+        let host = test_host(&root, &runner);
+
+        setup_drivers(&host, false, SetupOptions::default(), None).await.unwrap();
+        // The snapshot comes before the pacman transaction
+        let commands = runner.transcript();
+        let snapper = commands.find("$ snapper -c root create").unwrap();
+        assert!(snapper < commands.find("--noconfirm").unwrap());
+
+        // Later transactions without a snapshot or undone do not hide it
+        let journal = journal::Journal::new(root.path());
+        let snapshot = snapshot::Snapshot { backend: snapshot::Backend::Snapper, id: "43".to_string() };
+        let undone = journal::Transaction { id: 2, snapshot: Some(snapshot), undone: true, ..Default::default() };
+        journal.save(&undone).await.unwrap();
+        journal.save(&journal.begin().await.unwrap()).await.unwrap();
+        let report_file = root.path().join("report.json");
+        let output = Some(report_file.to_string_lossy().into_owned());
+        generate_report(&host, output, false).await.unwrap();
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&report_file).unwrap()).unwrap();
+        assert_eq!(report["snapshot"], serde_json::json!({ "backend": "snapper", "id": "42" }));
+    }
+
+    #[tokio::test]
+    async fn required_snapshot_blocks_setup_without_a_backend() {
+        let root = thinkpad_root("setup-no-snapshot");
+        root.write("etc/ardenthat/config.json", r#"{ "require_snapshot": true }"#);
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+
+        let err = setup_drivers(&host, false, SetupOptions::default(), None).await.unwrap_err();
+        assert!(err.to_string().starts_with("A snapshot is required"));
+        assert!(!runner.transcript().contains("--noconfirm"));
+    }
+
+    #[tokio::test]
+    async fn undo_reverts_the_last_setup() {
+        let root = thinkpad_root("setup-undo");
+        let runner = thinkpad_runner(0);
+        let host = test_host(&root, &runner);
+        setup_drivers(&host, false, PERSIST_EARLY_KMS, None).await.unwrap();
+
+        let undo_runner = MockRunner::new().reply(
+            &["pacman", "-Q"],
+            0,
+            "intel-ucode 20250812-1\nintel-media-driver 25.2.6-1\nvulkan-intel 1:25.1.7-1\n\
+             libva 2.22.0-1\nsof-firmware 2025.05-1\nalsa-ucm-conf 1.2.14-1\n",
+        );
+        let host = test_host(&root, &undo_runner);
+        undo_transaction(&host, None, false, false).await.unwrap();
+        assert_golden("undo.txt", &undo_runner.transcript());
+
+        let journal = journal::Journal::new(root.path());
+        assert!(journal.list().await.unwrap()[0].undone);
+        let err = undo_transaction(&host, Some(1), false, false).await.unwrap_err();
+        assert_eq!(err.to_string(), "Transaction 1 was already undone");
+    }
+
+    #[tokio::test]
+    async fn setup_escalates_once_when_not_root() {
+        let root = TempTree::new("setup-escalate");
+        root.write("proc/self/status", "Uid:\t1000\t1000\t1000\t1000\n");
+        root.write("usr/bin/sudo", "");
+        root.write("etc/ardenthat/config.json", r#"{ "escalation": "doas" }"#);
+        let runner = MockRunner::new().reply(&["doas", "/usr/bin/ardenthat", "setup"], 3, "");
+        let host = test_host(&root, &runner);
+
+        let args = vec!["setup".to_string()];
+        let code = escalate(&host, None, "/usr/bin/ardenthat", &args).await.unwrap();
+        assert_eq!(code, Some(3));
+        assert_eq!(runner.transcript(), "$ doas /usr/bin/ardenthat setup\n");
+    }
+}
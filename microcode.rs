@@ -0,0 +1,352 @@
+//! CPU microcode packages, revisions and early loading checks
+//!
+//! `intel-ucode` and `amd-ucode` ship an uncompressed cpio image in `/boot` that
+//! the kernel reads before the real initramfs. The image only takes effect when
+//! something puts it first: the mkinitcpio `microcode` hook, a GRUB `initrd` line
+//! generated by grub-mkconfig, or the first `initrd` of a systemd-boot entry.
+
+use crate::cpu::CpuInfo;
+
+/// Microcode package for a `/proc/cpuinfo` vendor string.
+pub fn package(vendor_id: &str) -> Option<&'static str> {
+    match vendor_id {
+        "GenuineIntel" => Some("intel-ucode"),
+        "AuthenticAMD" => Some("amd-ucode"),
+        _ => None,
+    }
+}
+
+/// Early microcode image installed by `package`, e.g. `/boot/intel-ucode.img`.
+pub fn image_path(package: &str) -> String {
+    format!("/boot/{}.img", package)
+}
+
+/// CPUID signature (leaf 1 EAX) rebuilt from the decoded family, model and stepping.
+pub fn signature(cpu: &CpuInfo) -> Option<u32> {
+    let (family, model, stepping) = (cpu.family?, cpu.model?, cpu.stepping?);
+    let (base_family, extended_family) = if family >= 0xf {
+        (0xf, family - 0xf)
+    } else {
+        (family, 0)
+    };
+    Some(
+        extended_family << 20
+            | (model >> 4) << 16
+            | base_family << 8
+            | (model & 0xf) << 4
+            | stepping,
+    )
+}
+
+/// Revision the CPU runs, from the `microcode` field of `/proc/cpuinfo`.
+pub fn running_revision(cpu: &CpuInfo) -> Option<u32> {
+    let revision = cpu.microcode.as_deref()?;
+    u32::from_str_radix(revision.trim_start_matches("0x"), 16).ok()
+}
+
+/// Newest revision for `signature` in an early microcode image, `None` when the
+/// image has no update for this CPU.
+pub fn packaged_revision(image: &[u8], vendor_id: &str, signature: u32) -> Option<u32> {
+    let blob = cpio_file(image, &format!("kernel/x86/microcode/{}.bin", vendor_id))?;
+    match vendor_id {
+        "GenuineIntel" => intel_revision(blob, signature),
+        "AuthenticAMD" => amd_revision(blob, signature),
+        _ => None,
+    }
+}
+
+/// Whether a GRUB configuration passes `image` in an `initrd` line.
+pub fn grub_loads(grub_cfg: &str, image: &str) -> bool {
+    let name = file_name(image);
+    grub_cfg.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next() == Some("initrd") && words.any(|path| file_name(path) == name)
+    })
+}
+
+/// Makes `image` the first `initrd` of a systemd-boot entry, returning `None` if
+/// it already is. With `require` unset an entry without the image is left alone.
+pub fn fix_loader_entry(entry: &str, image: &str, require: bool) -> Option<String> {
+    let name = file_name(image);
+    let is_initrd = |line: &str| line.split_whitespace().next() == Some("initrd");
+    let is_image =
+        |line: &str| is_initrd(line) && line.split_whitespace().nth(1).map(file_name) == Some(name);
+
+    let first_initrd = entry.lines().position(is_initrd);
+    let present = entry.lines().any(is_image);
+    match first_initrd {
+        Some(index) if entry.lines().nth(index).is_some_and(is_image) => return None,
+        _ if !present && !require => return None,
+        _ => {}
+    }
+
+    let mut lines: Vec<String> = entry
+        .lines()
+        .filter(|line| !is_image(line))
+        .map(str::to_string)
+        .collect();
+    let line = format!("initrd  /{}", name);
+    // Before the first initrd, else after the kernel line
+    let at = lines
+        .iter()
+        .position(|l| is_initrd(l))
+        .or_else(|| {
+            lines
+                .iter()
+                .position(|l| l.split_whitespace().next() == Some("linux"))
+                .map(|i| i + 1)
+        })
+        .unwrap_or(lines.len());
+    lines.insert(at, line);
+    Some(lines.join("\n") + "\n")
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Contents of `name` in a newc cpio archive.
+fn cpio_file<'a>(archive: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    const HEADER: usize = 110;
+    let align = |n: usize| (n + 3) & !3;
+    let field = |header: &[u8], index: usize| {
+        let start = 6 + index * 8;
+        let text = std::str::from_utf8(header.get(start..start + 8)?).ok()?;
+        usize::from_str_radix(text, 16).ok()
+    };
+
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + HEADER) {
+        if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+            return None;
+        }
+        let file_size = field(header, 6)?;
+        let name_size = field(header, 11)?;
+        let entry_name = archive.get(offset + HEADER..offset + HEADER + name_size)?;
+        let entry_name = entry_name.strip_suffix(b"\0").unwrap_or(entry_name);
+        let data = align(offset + HEADER + name_size);
+        if entry_name == b"TRAILER!!!" {
+            return None;
+        }
+        if entry_name == name.as_bytes() {
+            return archive.get(data..data + file_size);
+        }
+        offset = align(data + file_size);
+    }
+    None
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Intel updates are concatenated 48 byte headers with their data and an
+/// optional extended signature table.
+fn intel_revision(blob: &[u8], signature: u32) -> Option<u32> {
+    let mut newest = None;
+    let mut offset = 0;
+    while offset + 48 <= blob.len() {
+        if u32_at(blob, offset)? != 1 {
+            break;
+        }
+        let revision = u32_at(blob, offset + 4)?;
+        let data_size = match u32_at(blob, offset + 28)? as usize {
+            0 => 2000,
+            size => size,
+        };
+        let total_size = match u32_at(blob, offset + 32)? as usize {
+            0 => 2048,
+            size => size,
+        };
+
+        let mut signatures = vec![u32_at(blob, offset + 12)?];
+        let table = offset + 48 + data_size;
+        if total_size > 48 + data_size {
+            let count = u32_at(blob, table)? as usize;
+            for entry in 0..count {
+                signatures.push(u32_at(blob, table + 20 + entry * 12)?);
+            }
+        }
+        if signatures.contains(&signature) {
+            newest = newest.max(Some(revision));
+        }
+        offset += total_size;
+    }
+    newest
+}
+
+/// AMD containers map CPU signatures to equivalence IDs, followed by one patch
+/// section per ID. Several containers, one per family, are concatenated.
+fn amd_revision(blob: &[u8], signature: u32) -> Option<u32> {
+    const MAGIC: u32 = 0x0041_4d44;
+    let mut newest = None;
+    let mut offset = 0;
+    while u32_at(blob, offset) == Some(MAGIC) {
+        let table_size = u32_at(blob, offset + 8)? as usize;
+        let table = offset + 12;
+        let equivalent = (0..table_size / 16)
+            .map(|entry| table + entry * 16)
+            .take_while(|&entry| u32_at(blob, entry) != Some(0))
+            .find(|&entry| u32_at(blob, entry) == Some(signature))
+            .and_then(|entry| u16_at(blob, entry + 12));
+
+        offset = table + table_size;
+        // Patch sections of type 1 until the next container
+        while u32_at(blob, offset) == Some(1) {
+            let size = u32_at(blob, offset + 4)? as usize;
+            let patch = offset + 8;
+            if equivalent.is_some() && u16_at(blob, patch + 24) == equivalent {
+                newest = newest.max(Some(u32_at(blob, patch + 4)?));
+            }
+            offset = patch + size;
+        }
+    }
+    newest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu(vendor: &str, family: u32, model: u32, stepping: u32, microcode: &str) -> CpuInfo {
+        CpuInfo {
+            vendor_id: vendor.to_string(),
+            family: Some(family),
+            model: Some(model),
+            stepping: Some(stepping),
+            microcode: Some(microcode.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// A newc archive holding `files`.
+    fn cpio(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let trailer: (&str, &[u8]) = ("TRAILER!!!", &[]);
+        for (name, data) in files.iter().chain([&trailer]) {
+            archive.extend(format!("070701{}", "00000000".repeat(6)).bytes());
+            archive.extend(format!("{:08x}", data.len()).bytes());
+            archive.extend("00000000".repeat(4).bytes());
+            archive.extend(format!("{:08x}00000000", name.len() + 1).bytes());
+            archive.extend(name.bytes());
+            archive.push(0);
+            while archive.len() % 4 != 0 {
+                archive.push(0);
+            }
+            archive.extend(*data);
+            while archive.len() % 4 != 0 {
+                archive.push(0);
+            }
+        }
+        archive
+    }
+
+    fn intel_update(signature: u32, revision: u32) -> Vec<u8> {
+        let mut update = vec![0u8; 48 + 16];
+        for (offset, value) in [(0, 1), (4, revision), (12, signature), (28, 16), (32, 64)] {
+            update[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        }
+        update
+    }
+
+    fn amd_container(signature: u32, equivalent: u16, revision: u32) -> Vec<u8> {
+        let mut container = Vec::new();
+        for value in [0x0041_4d44, 0, 32, signature, 0, 0] {
+            container.extend(u32::to_le_bytes(value));
+        }
+        container.extend(u16::to_le_bytes(equivalent));
+        container.extend([0; 2 + 16]);
+        let mut patch = vec![0u8; 64];
+        patch[4..8].copy_from_slice(&u32::to_le_bytes(revision));
+        patch[24..26].copy_from_slice(&u16::to_le_bytes(equivalent));
+        container.extend(u32::to_le_bytes(1));
+        container.extend(u32::to_le_bytes(patch.len() as u32));
+        container.extend(patch);
+        container
+    }
+
+    #[test]
+    fn signatures_from_cpuinfo() {
+        let alderlake = cpu("GenuineIntel", 6, 154, 3, "0x432");
+        assert_eq!(signature(&alderlake), Some(0x906a3));
+        assert_eq!(running_revision(&alderlake), Some(0x432));
+        let rome = cpu("AuthenticAMD", 23, 49, 0, "0x830107a");
+        assert_eq!(signature(&rome), Some(0x830f10));
+        assert_eq!(package(&rome.vendor_id), Some("amd-ucode"));
+        assert_eq!(package("ARM"), None);
+    }
+
+    #[test]
+    fn finds_the_newest_intel_revision() {
+        let blob: Vec<u8> = [
+            intel_update(0x906a3, 0x430),
+            intel_update(0x806c1, 0xb8),
+            intel_update(0x906a3, 0x435),
+        ]
+        .concat();
+        let image = cpio(&[("kernel/x86/microcode/GenuineIntel.bin", &blob)]);
+        assert_eq!(
+            packaged_revision(&image, "GenuineIntel", 0x906a3),
+            Some(0x435)
+        );
+        assert_eq!(packaged_revision(&image, "GenuineIntel", 0x906a4), None);
+        assert_eq!(packaged_revision(&image, "AuthenticAMD", 0x906a3), None);
+    }
+
+    #[test]
+    fn reads_amd_containers() {
+        let blob = [
+            amd_container(0x00800f12, 0x8012, 0x0800126e),
+            amd_container(0x00830f10, 0x8310, 0x0830107c),
+        ]
+        .concat();
+        let image = cpio(&[
+            ("kernel", &[]),
+            ("kernel/x86/microcode/AuthenticAMD.bin", &blob),
+        ]);
+        assert_eq!(
+            packaged_revision(&image, "AuthenticAMD", 0x830f10),
+            Some(0x0830107c)
+        );
+        assert_eq!(packaged_revision(&image, "AuthenticAMD", 0xa20f12), None);
+    }
+
+    #[test]
+    fn microcode_goes_first_in_loader_entries() {
+        let entry = "title Arch Linux\nlinux /vmlinuz-linux\ninitrd /initramfs-linux.img\noptions root=/dev/sda2 rw\n";
+        let fixed = fix_loader_entry(entry, "/boot/intel-ucode.img", true).unwrap();
+        assert_eq!(
+            fixed,
+            "title Arch Linux\nlinux /vmlinuz-linux\ninitrd  /intel-ucode.img\ninitrd /initramfs-linux.img\noptions root=/dev/sda2 rw\n"
+        );
+        assert_eq!(
+            fix_loader_entry(&fixed, "/boot/intel-ucode.img", true),
+            None
+        );
+        assert_eq!(
+            fix_loader_entry(entry, "/boot/intel-ucode.img", false),
+            None
+        );
+
+        let late = "linux /vmlinuz-linux\ninitrd /initramfs-linux.img\ninitrd /intel-ucode.img\n";
+        assert_eq!(
+            fix_loader_entry(late, "/boot/intel-ucode.img", false).unwrap(),
+            "linux /vmlinuz-linux\ninitrd  /intel-ucode.img\ninitrd /initramfs-linux.img\n"
+        );
+    }
+
+    #[test]
+    fn grub_initrd_lines() {
+        let cfg = "menuentry 'Arch Linux' {\n\tlinux\t/vmlinuz-linux root=UUID=1 rw\n\tinitrd\t/intel-ucode.img /initramfs-linux.img\n}\n";
+        assert!(grub_loads(cfg, "/boot/intel-ucode.img"));
+        assert!(!grub_loads(cfg, "/boot/amd-ucode.img"));
+    }
+}
@@ -0,0 +1,256 @@
+//! Kernel module lookup for device modaliases using `modules.alias`
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// A kernel modules directory, normally `/lib/modules/$(uname -r)`.
+pub struct KernelModules {
+    dir: PathBuf,
+}
+
+impl KernelModules {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Modules directory of the running kernel on the system mounted at `root`.
+    pub async fn running(root: &Path) -> Result<Self> {
+        let release = fs::read_to_string(root.join("proc/sys/kernel/osrelease"))
+            .await
+            .context("Failed to read running kernel release")?;
+        Ok(Self::new(root.join("lib/modules").join(release.trim())))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads `modules.alias` plus the aliases of built-in drivers from `modules.builtin.modinfo`.
+    pub async fn load_aliases(&self) -> Result<ModuleAliases> {
+        let path = self.dir.join("modules.alias");
+        let text = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut aliases = ModuleAliases::parse(&text);
+
+        if let Ok(modinfo) = fs::read(self.dir.join("modules.builtin.modinfo")).await {
+            aliases.extend_builtin(&String::from_utf8_lossy(&modinfo));
+        }
+
+        Ok(aliases)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ModuleAliases {
+    entries: Vec<(String, String)>,
+}
+
+impl ModuleAliases {
+    /// Parses `alias <pattern> <module>` lines.
+    pub fn parse(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some("alias"), Some(pattern), Some(module)) => {
+                        Some((pattern.to_string(), module.to_string()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Adds `<module>.alias=<pattern>` records from the NUL-separated `modules.builtin.modinfo`.
+    fn extend_builtin(&mut self, modinfo: &str) {
+        for record in modinfo.split('\0') {
+            if let Some((module, pattern)) = record
+                .split_once(".alias=")
+                .filter(|(module, _)| !module.contains('='))
+            {
+                self.entries.push((pattern.to_string(), module.to_string()));
+            }
+        }
+    }
+
+    /// Modules whose alias pattern matches `modalias`, in `modules.alias` order.
+    pub fn lookup(&self, modalias: &str) -> Vec<String> {
+        let mut modules: Vec<String> = Vec::new();
+        for (pattern, module) in &self.entries {
+            if fnmatch(pattern.as_bytes(), modalias.as_bytes()) && !modules.contains(module) {
+                modules.push(module.clone());
+            }
+        }
+        modules
+    }
+}
+
+/// Shell-style glob match supporting `*`, `?` and `[...]` classes, as used by modprobe.
+pub fn fnmatch(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(&pattern[p..], text[t]) {
+                        if matched {
+                            p += next;
+                            t += 1;
+                            continue;
+                        }
+                    } else if text[t] == b'[' {
+                        // Unterminated class, treat `[` literally
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c if c == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        match backtrack {
+            Some((star, matched)) => {
+                p = star + 1;
+                t = matched + 1;
+                backtrack = Some((star, matched + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class at the start of `pattern`, returning the class length.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(pattern.get(i), Some(b'!') | Some(b'^'));
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        if pattern[i] == b']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&e| e != b']') {
+            matched |= (pattern[i]..=pattern[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+        first = false;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    const ALIASES: &str = include_str!("fixtures/modules/modules.alias");
+
+    #[test]
+    fn glob_semantics_match_modprobe() {
+        assert!(fnmatch(b"pci:v*d*", b"pci:v00008086d00005917"));
+        assert!(fnmatch(b"a?c", b"abc"));
+        assert!(!fnmatch(b"a?c", b"ac"));
+        assert!(fnmatch(b"x[a-c]y", b"xby"));
+        assert!(!fnmatch(b"x[!a-c]y", b"xby"));
+        assert!(fnmatch(b"x[!a-c]y", b"xdy"));
+        assert!(fnmatch(b"*in*", b"in"));
+        assert!(!fnmatch(b"abc", b"abcd"));
+        assert!(fnmatch(b"abc*", b"abcd"));
+    }
+
+    #[test]
+    fn looks_up_pci_modules() {
+        let aliases = ModuleAliases::parse(ALIASES);
+
+        let gpu = "pci:v00008086d00005917sv000017AAsd0000225Dbc03sc00i00";
+        assert_eq!(aliases.lookup(gpu), vec!["i915"]);
+
+        let audio = "pci:v00008086d00009D71sv000017AAsd0000225Dbc04sc03i00";
+        assert_eq!(
+            aliases.lookup(audio),
+            vec!["snd_hda_intel", "snd_soc_skl", "snd_sof_pci_intel_skl"]
+        );
+
+        let radeon = "pci:v00001002d00006818sv0000174Bsd0000E221bc03sc00i00";
+        assert_eq!(aliases.lookup(radeon), vec!["radeon", "amdgpu"]);
+
+        let broadcom = "pci:v000014E4d000043A0sv0000106Bsd00000117bc02sc80i00";
+        assert_eq!(aliases.lookup(broadcom), vec!["bcma"]);
+
+        // The kernel ships no driver for this device at all
+        let unknown = "pci:v00001234d00005678sv00000000sd00000000bc0Csc80i00";
+        assert!(aliases.lookup(unknown).is_empty());
+    }
+
+    #[test]
+    fn looks_up_usb_interface_modules() {
+        let aliases = ModuleAliases::parse(ALIASES);
+
+        let bluetooth = "usb:v8087p0A2Bd0001dcE0dsc01dp01icE0isc01ip01in00";
+        assert_eq!(aliases.lookup(bluetooth), vec!["btusb"]);
+
+        let camera = "usb:v5986p2113d5406dcEFdsc02dp01ic0Eisc01ip00in00";
+        assert_eq!(aliases.lookup(camera), vec!["uvcvideo"]);
+
+        let rtl = "usb:v0BDAp8178d0200dc00dsc00dp00icFFiscFFipFFin00";
+        assert_eq!(aliases.lookup(rtl), vec!["rtl8xxxu"]);
+        let excluded = "usb:v0BDAp8179d0200dc00dsc00dp00icFFiscFFipFFin00";
+        assert!(aliases.lookup(excluded).is_empty());
+    }
+
+    #[tokio::test]
+    async fn loads_overridden_modules_dir() {
+        let dir = TempTree::new("kernel-modules");
+        dir.write("modules.alias", ALIASES);
+        dir.write(
+            "modules.builtin.modinfo",
+            "nvme.license=GPL\0nvme.alias=pci:v*d*sv*sd*bc01sc08i02*\0xhci_pci.description=xHCI\0",
+        );
+
+        let aliases = KernelModules::new(dir.path()).load_aliases().await.unwrap();
+        let nvme = "pci:v0000144Dd0000A808sv0000144Dsd0000A801bc01sc08i02";
+        assert_eq!(aliases.lookup(nvme), vec!["nvme"]);
+        assert_eq!(
+            aliases.lookup("pci:v00008086d00002723sv00008086sd00000084bc02sc80i00"),
+            vec!["iwlwifi"]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_modules_dir_is_an_error() {
+        let modules = KernelModules::new("/nonexistent/lib/modules/0.0.0");
+        assert!(modules.load_aliases().await.is_err());
+    }
+}
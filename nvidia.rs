@@ -0,0 +1,439 @@
+//! NVIDIA driver branch selection by GPU architecture and installed kernels
+//!
+//! The architecture comes from the PCI device ID. Turing and newer use the open
+//! kernel modules, Maxwell to Volta the proprietary ones, and Kepler and Fermi the
+//! legacy 470xx and 390xx branches. Prebuilt modules exist for `linux` and, when
+//! the repositories still carry them, `linux-lts`; any other installed kernel
+//! switches to the DKMS package.
+//!
+//! Setup also blacklists nouveau, enables DRM KMS through modprobe options and can
+//! install a pacman hook that rebuilds the initramfs when the driver is upgraded.
+
+use crate::initramfs::{Generator, Kernel};
+use crate::kernel::ModulePackages;
+use crate::knowledge::{Recommendation, Selector};
+use crate::packages::PackageDatabase;
+use crate::HardwareComponent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Tesla,
+    Fermi,
+    Kepler,
+    Maxwell,
+    Pascal,
+    Volta,
+    Turing,
+    Ampere,
+    Hopper,
+    Ada,
+    Blackwell,
+}
+
+/// Driver branch, i.e. which kernel module package family supports a GPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Branch {
+    Open,
+    Proprietary,
+    Legacy470,
+    Legacy390,
+}
+
+/// modprobe options for the NVIDIA modules.
+pub const MODPROBE_CONF: &str = "/etc/modprobe.d/ardenthat-nvidia.conf";
+pub const PACMAN_HOOK: &str = "/etc/pacman.d/hooks/ardenthat-nvidia.hook";
+
+/// Device ID ranges per architecture, by chip family.
+const ARCHITECTURES: &[(u16, u16, Architecture)] = &[
+    (0x0190, 0x019f, Architecture::Tesla),
+    (0x0400, 0x06bf, Architecture::Tesla),
+    (0x06c0, 0x06ff, Architecture::Fermi),
+    (0x07e0, 0x07ff, Architecture::Tesla),
+    (0x0840, 0x08bf, Architecture::Tesla),
+    (0x0a20, 0x0a7f, Architecture::Tesla),
+    (0x0ca0, 0x0cbf, Architecture::Tesla),
+    (0x0dc0, 0x0dff, Architecture::Fermi),
+    (0x0e20, 0x0e3f, Architecture::Fermi),
+    (0x0fc0, 0x103f, Architecture::Kepler),
+    (0x1040, 0x109f, Architecture::Fermi),
+    (0x1180, 0x11ff, Architecture::Kepler),
+    (0x1200, 0x127f, Architecture::Fermi),
+    (0x1280, 0x12bf, Architecture::Kepler),
+    (0x1340, 0x142f, Architecture::Maxwell),
+    (0x15f0, 0x15ff, Architecture::Pascal),
+    (0x17c0, 0x17ff, Architecture::Maxwell),
+    (0x1b00, 0x1d7f, Architecture::Pascal),
+    (0x1d80, 0x1dff, Architecture::Volta),
+    (0x1e00, 0x1fff, Architecture::Turing),
+    (0x2080, 0x20ff, Architecture::Ampere),
+    (0x2180, 0x21ff, Architecture::Turing),
+    (0x2200, 0x22ff, Architecture::Ampere),
+    (0x2300, 0x233f, Architecture::Hopper),
+    (0x2340, 0x25ff, Architecture::Ampere),
+    (0x2600, 0x28ff, Architecture::Ada),
+    (0x2900, 0x2fff, Architecture::Blackwell),
+];
+
+impl Architecture {
+    pub fn from_device_id(device_id: u16) -> Option<Self> {
+        ARCHITECTURES
+            .iter()
+            .find(|(lo, hi, _)| (*lo..=*hi).contains(&device_id))
+            .map(|&(_, _, arch)| arch)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Architecture::Tesla => "Tesla",
+            Architecture::Fermi => "Fermi",
+            Architecture::Kepler => "Kepler",
+            Architecture::Maxwell => "Maxwell",
+            Architecture::Pascal => "Pascal",
+            Architecture::Volta => "Volta",
+            Architecture::Turing => "Turing",
+            Architecture::Ampere => "Ampere",
+            Architecture::Hopper => "Hopper",
+            Architecture::Ada => "Ada Lovelace",
+            Architecture::Blackwell => "Blackwell",
+        }
+    }
+
+    /// Newest driver branch supporting the architecture, `None` when no packaged
+    /// branch does and only nouveau is left.
+    pub fn branch(self) -> Option<Branch> {
+        match self {
+            Architecture::Tesla => None,
+            Architecture::Fermi => Some(Branch::Legacy390),
+            Architecture::Kepler => Some(Branch::Legacy470),
+            Architecture::Maxwell | Architecture::Pascal | Architecture::Volta => {
+                Some(Branch::Proprietary)
+            }
+            Architecture::Turing
+            | Architecture::Ampere
+            | Architecture::Hopper
+            | Architecture::Ada
+            | Architecture::Blackwell => Some(Branch::Open),
+        }
+    }
+}
+
+impl Branch {
+    const ALL: [Branch; 4] = [
+        Branch::Open,
+        Branch::Proprietary,
+        Branch::Legacy470,
+        Branch::Legacy390,
+    ];
+
+    /// Branch of a kernel module package such as `nvidia-open-dkms`.
+    pub fn from_modules_package(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|branch| {
+            branch.dkms() == name
+                || ModulePackages::find(name).is_some_and(|f| f.dkms == branch.dkms())
+        })
+    }
+
+    /// `/etc/modprobe.d` contents enabling DRM KMS, which Wayland compositors need.
+    ///
+    /// The fbdev console driver needs 545 or newer, so legacy branches only get modeset.
+    pub fn modprobe_options(self) -> String {
+        let fbdev = match self {
+            Branch::Open | Branch::Proprietary => " fbdev=1",
+            Branch::Legacy470 | Branch::Legacy390 => "",
+        };
+        format!(
+            "# NVIDIA DRM kernel mode setting, written by ardenthat\noptions nvidia_drm modeset=1{}\n",
+            fbdev
+        )
+    }
+
+    fn dkms(self) -> &'static str {
+        match self {
+            Branch::Open => "nvidia-open-dkms",
+            Branch::Proprietary => "nvidia-dkms",
+            Branch::Legacy470 => "nvidia-470xx-dkms",
+            Branch::Legacy390 => "nvidia-390xx-dkms",
+        }
+    }
+
+    /// User-space driver package, which has to match the kernel modules' version.
+    pub fn utils(self) -> &'static str {
+        match self {
+            Branch::Open | Branch::Proprietary => "nvidia-utils",
+            Branch::Legacy470 => "nvidia-470xx-utils",
+            Branch::Legacy390 => "nvidia-390xx-utils",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Branch::Open => "the open kernel modules",
+            Branch::Proprietary => "the proprietary kernel modules",
+            Branch::Legacy470 => "the 470xx legacy branch",
+            Branch::Legacy390 => "the 390xx legacy branch",
+        }
+    }
+}
+
+/// Packages for one GPU and why they were chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub architecture: Architecture,
+    pub packages: Vec<String>,
+    pub reasons: Vec<String>,
+}
+
+/// Chooses the driver packages for the NVIDIA GPU `device_id`, `None` for unknown IDs.
+///
+/// `lib32` utilities are only added when the multilib repository provides them.
+pub fn select(
+    device_id: u16,
+    kernels: &[Kernel],
+    packages: Option<&PackageDatabase>,
+) -> Option<Selection> {
+    let architecture = Architecture::from_device_id(device_id)?;
+    let Some(branch) = architecture.branch() else {
+        return Some(Selection {
+            architecture,
+            packages: Vec::new(),
+            reasons: vec![format!(
+                "NVIDIA {} GPU is not supported by any packaged NVIDIA branch, nouveau is the only driver",
+                architecture.name()
+            )],
+        });
+    };
+
+    let mut reasons = vec![format!(
+        "NVIDIA {} GPU is supported by {}",
+        architecture.name(),
+        branch.description()
+    )];
+    let modules = match ModulePackages::find(branch.dkms()) {
+        Some(family) => {
+            let choice = family.choose(kernels, packages);
+            reasons.push(choice.reason);
+            choice.packages
+        }
+        None => {
+            reasons.push("the branch is only packaged for DKMS".to_string());
+            vec![branch.dkms().to_string()]
+        }
+    };
+
+    let utils = branch.utils();
+    let lib32 = format!("lib32-{}", utils);
+    let mut selected = modules;
+    selected.push(utils.to_string());
+    if packages.is_some_and(|db| db.is_available(&lib32)) {
+        reasons.push(format!(
+            "{} adds 32-bit OpenGL and Vulkan for Steam and Wine",
+            lib32
+        ));
+        selected.push(lib32);
+    } else {
+        reasons.push(format!(
+            "{} is skipped because multilib is not enabled",
+            lib32
+        ));
+    }
+
+    Some(Selection {
+        architecture,
+        packages: selected,
+        reasons,
+    })
+}
+
+/// pacman hook that rebuilds the initramfs with `generator` when one of
+/// `packages` is upgraded, so the early-loaded modules match the installed driver.
+///
+/// Kernel upgrades are skipped because the kernel's own hook already rebuilds.
+pub fn pacman_hook(packages: &[&str], kernels: &[Kernel], generator: Generator) -> String {
+    let mut hook = String::from(
+        "# Written by ardenthat\n[Trigger]\nOperation=Install\nOperation=Upgrade\nOperation=Remove\nType=Package\n",
+    );
+    for package in packages {
+        hook.push_str(&format!("Target={}\n", package));
+    }
+    for kernel in kernels {
+        hook.push_str(&format!("Target={}\n", kernel.pkgbase));
+    }
+    let rebuild = match generator {
+        Generator::Mkinitcpio => "/usr/bin/mkinitcpio -P",
+        Generator::Dracut => "/usr/bin/dracut --regenerate-all --force",
+        Generator::Booster => "/usr/lib/booster/regenerate_images",
+    };
+    hook.push_str(&format!(
+        "\n[Action]\nDescription=Updating NVIDIA modules in the initramfs\nDepends={}\nWhen=PostTransaction\nNeedsTargets\n\
+         Exec=/bin/sh -c 'while read -r trg; do case $trg in linux*) exit 0; esac; done; {}'\n",
+        generator.name(),
+        rebuild
+    ));
+    hook
+}
+
+/// Replaces the packages of recommendations from rules with `"select": "nvidia"`
+/// by the branch chosen for their GPU.
+pub fn apply(
+    recommendations: &mut [Recommendation],
+    components: &[HardwareComponent],
+    kernels: &[Kernel],
+    packages: Option<&PackageDatabase>,
+) {
+    for recommendation in recommendations
+        .iter_mut()
+        .filter(|r| r.select == Some(Selector::Nvidia))
+    {
+        let device_id = recommendation
+            .components
+            .iter()
+            .find_map(|&i| components[i].pci.as_ref())
+            .map(|device| device.device_id);
+        let Some(selection) = device_id.and_then(|id| select(id, kernels, packages)) else {
+            continue;
+        };
+        if selection.packages.is_empty() {
+            recommendation.early_modules.clear();
+        }
+        recommendation.packages = selection.packages;
+        recommendation.reason = selection.reasons.join("; ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kernels(pkgbases: &[&str]) -> Vec<Kernel> {
+        pkgbases
+            .iter()
+            .map(|pkgbase| Kernel {
+                pkgbase: pkgbase.to_string(),
+                version: "6.11.5".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classifies_device_ids() {
+        let cases = [
+            (0x0402, Architecture::Tesla),     // GeForce 8600 GT
+            (0x0dc4, Architecture::Fermi),     // GeForce GTS 450
+            (0x1180, Architecture::Kepler),    // GeForce GTX 680
+            (0x13c2, Architecture::Maxwell),   // GeForce GTX 970
+            (0x1b80, Architecture::Pascal),    // GeForce GTX 1080
+            (0x1db4, Architecture::Volta),     // Tesla V100
+            (0x2184, Architecture::Turing),    // GeForce GTX 1660
+            (0x2504, Architecture::Ampere),    // GeForce RTX 3060
+            (0x2684, Architecture::Ada),       // GeForce RTX 4090
+            (0x2b85, Architecture::Blackwell), // GeForce RTX 5090
+        ];
+        for (device_id, architecture) in cases {
+            assert_eq!(
+                Architecture::from_device_id(device_id),
+                Some(architecture),
+                "{:04x}",
+                device_id
+            );
+        }
+        assert_eq!(Architecture::from_device_id(0x0020), None);
+    }
+
+    #[test]
+    fn prebuilt_modules_only_with_the_stock_kernel() {
+        let multilib = PackageDatabase::parse("", "nvidia-utils\nlib32-nvidia-utils\n");
+
+        let stock = select(0x2504, &kernels(&["linux"]), Some(&multilib)).unwrap();
+        assert_eq!(
+            stock.packages,
+            vec!["nvidia-open", "nvidia-utils", "lib32-nvidia-utils"]
+        );
+        assert_eq!(
+            stock.reasons[1],
+            "only the linux kernel is installed, so the prebuilt nvidia-open modules fit"
+        );
+
+        let mixed = select(0x1b80, &kernels(&["linux", "linux-lts", "linux-zen"]), None).unwrap();
+        assert_eq!(mixed.packages, vec!["nvidia-dkms", "nvidia-utils"]);
+        assert_eq!(
+            mixed.reasons,
+            vec![
+                "NVIDIA Pascal GPU is supported by the proprietary kernel modules",
+                "linux-lts, linux-zen have no prebuilt modules, so they are built with DKMS",
+                "lib32-nvidia-utils is skipped because multilib is not enabled",
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_gpus_get_legacy_dkms_branches() {
+        let kepler = select(0x1180, &kernels(&["linux"]), None).unwrap();
+        assert_eq!(
+            kepler.packages,
+            vec!["nvidia-470xx-dkms", "nvidia-470xx-utils"]
+        );
+        let fermi = select(0x0dc4, &kernels(&["linux"]), None).unwrap();
+        assert_eq!(
+            fermi.packages,
+            vec!["nvidia-390xx-dkms", "nvidia-390xx-utils"]
+        );
+        let tesla = select(0x0a20, &kernels(&["linux"]), None).unwrap();
+        assert!(tesla.packages.is_empty());
+    }
+
+    #[test]
+    fn modeset_options_per_branch() {
+        assert_eq!(
+            Branch::from_modules_package("nvidia-open-dkms"),
+            Some(Branch::Open)
+        );
+        assert_eq!(Branch::from_modules_package("nvidia-utils"), None);
+        assert!(Branch::Open
+            .modprobe_options()
+            .ends_with("options nvidia_drm modeset=1 fbdev=1\n"));
+        assert!(Branch::Legacy470
+            .modprobe_options()
+            .ends_with("options nvidia_drm modeset=1\n"));
+    }
+
+    #[test]
+    fn hook_targets_driver_and_kernels() {
+        let hook = pacman_hook(
+            &["nvidia-open", "nvidia-open-lts", "nvidia-utils"],
+            &kernels(&["linux", "linux-lts"]),
+            Generator::Dracut,
+        );
+        assert!(hook.contains(
+            "Target=nvidia-open\nTarget=nvidia-open-lts\nTarget=nvidia-utils\n\
+             Target=linux\nTarget=linux-lts\n"
+        ));
+        assert!(hook.contains("Depends=dracut\n"));
+        assert!(hook.ends_with("done; /usr/bin/dracut --regenerate-all --force'\n"));
+    }
+
+    #[test]
+    fn rewrites_selector_recommendations() {
+        let gpu = HardwareComponent {
+            pci: Some(crate::pci::PciDevice {
+                vendor_id: 0x10de,
+                device_id: 0x2684,
+                class: 0x03,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut kb = crate::knowledge::KnowledgeBase::default();
+        kb.add_rules(include_str!("data/rules.json"), "bundled rules")
+            .unwrap();
+        let mut recommendations = kb.evaluate(std::slice::from_ref(&gpu));
+
+        apply(&mut recommendations, &[gpu], &kernels(&["linux-lts"]), None);
+        assert_eq!(
+            recommendations[0].packages,
+            vec!["nvidia-open-dkms", "nvidia-utils"]
+        );
+        assert!(recommendations[0]
+            .reason
+            .starts_with("NVIDIA Ada Lovelace GPU is supported by the open kernel modules; "));
+    }
+}
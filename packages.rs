@@ -0,0 +1,54 @@
+//! Installed and available package lists from the pacman databases
+
+use crate::command::{CommandLine, CommandRunner};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Default)]
+pub struct PackageDatabase {
+    /// Locally installed packages and their versions
+    pub installed: BTreeMap<String, String>,
+    /// Package names present in the sync databases
+    pub available: BTreeSet<String>,
+}
+
+impl PackageDatabase {
+    pub fn load(runner: &dyn CommandRunner) -> Result<Self> {
+        let local = runner
+            .run(&CommandLine::new(["pacman", "-Q"]).capture())
+            .context("Failed to query local pacman database")?;
+        let sync = runner
+            .run(&CommandLine::new(["pacman", "-Slq"]).capture())
+            .context("Failed to query pacman sync databases")?;
+
+        Ok(Self::parse(&local.stdout, &sync.stdout))
+    }
+
+    /// Builds the database from `pacman -Q` and `pacman -Slq` output.
+    pub fn parse(local: &str, sync: &str) -> Self {
+        let installed = local
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, version)| (name.to_string(), version.trim().to_string()))
+            .collect();
+        let available = sync
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        Self {
+            installed,
+            available,
+        }
+    }
+
+    pub fn is_installed(&self, package: &str) -> bool {
+        self.installed.contains_key(package)
+    }
+
+    pub fn is_available(&self, package: &str) -> bool {
+        self.available.contains(package)
+    }
+}
@@ -0,0 +1,409 @@
+//! PCI device detection from machine-readable `lspci -vmmnnk` output or sysfs
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PciDevice {
+    pub slot: String,
+    pub class_name: String,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: Option<u8>,
+    pub vendor_id: u16,
+    pub vendor_name: String,
+    pub device_id: u16,
+    pub device_name: String,
+    pub subsystem_vendor_id: Option<u16>,
+    pub subsystem_device_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subsystem_name: Option<String>,
+    pub revision: Option<u8>,
+    pub driver: Option<String>,
+    pub modules: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modalias: Option<String>,
+}
+
+impl PciDevice {
+    /// The kernel modalias, synthesized from the IDs when it was not read from sysfs.
+    pub fn modalias(&self) -> String {
+        self.modalias.clone().unwrap_or_else(|| {
+            format!(
+                "pci:v{:08X}d{:08X}sv{:08X}sd{:08X}bc{:02X}sc{:02X}i{:02X}",
+                self.vendor_id,
+                self.device_id,
+                self.subsystem_vendor_id.unwrap_or(0),
+                self.subsystem_device_id.unwrap_or(0),
+                self.class,
+                self.subclass,
+                self.prog_if.unwrap_or(0)
+            )
+        })
+    }
+}
+
+/// Parses the blank-line separated records printed by `lspci -vmmnnk`.
+pub fn parse_lspci_records(output: &str) -> Result<Vec<PciDevice>> {
+    let mut devices = Vec::new();
+    let mut record: Vec<(&str, &str)> = Vec::new();
+
+    for line in output.lines().chain(std::iter::once("")) {
+        if line.trim().is_empty() {
+            if !record.is_empty() {
+                devices.push(parse_record(&record)?);
+                record.clear();
+            }
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            record.push((key.trim(), value.trim()));
+        }
+    }
+
+    Ok(devices)
+}
+
+fn parse_record(fields: &[(&str, &str)]) -> Result<PciDevice> {
+    let mut device = PciDevice::default();
+
+    for &(key, value) in fields {
+        match key {
+            "Slot" => device.slot = value.to_string(),
+            "Class" => {
+                let (name, id) = split_name_id(value);
+                let id = parse_hex(id, "class")?;
+                device.class_name = name;
+                device.class = (id >> 8) as u8;
+                device.subclass = (id & 0xff) as u8;
+            }
+            "Vendor" => {
+                let (name, id) = split_name_id(value);
+                device.vendor_name = name;
+                device.vendor_id = parse_hex(id, "vendor")?;
+            }
+            "Device" => {
+                let (name, id) = split_name_id(value);
+                device.device_name = name;
+                device.device_id = parse_hex(id, "device")?;
+            }
+            "SVendor" => {
+                let (_, id) = split_name_id(value);
+                device.subsystem_vendor_id = Some(parse_hex(id, "subsystem vendor")?);
+            }
+            "SDevice" => {
+                let (_, id) = split_name_id(value);
+                device.subsystem_device_id = Some(parse_hex(id, "subsystem device")?);
+            }
+            "Rev" => device.revision = Some(parse_hex_byte(value, "revision")?),
+            "ProgIf" => device.prog_if = Some(parse_hex_byte(value, "prog-if")?),
+            "Driver" => device.driver = Some(value.to_string()),
+            "Module" => device.modules.push(value.to_string()),
+            _ => {}
+        }
+    }
+
+    if device.slot.is_empty() {
+        anyhow::bail!("lspci record without a Slot field");
+    }
+
+    Ok(device)
+}
+
+/// Enumerates PCI devices by walking `<root>/bus/pci/devices` without pciutils.
+pub struct SysfsPciDetector {
+    root: PathBuf,
+}
+
+impl Default for SysfsPciDetector {
+    fn default() -> Self {
+        Self::new("/sys")
+    }
+}
+
+impl SysfsPciDetector {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn devices_dir(&self) -> PathBuf {
+        self.root.join("bus/pci/devices")
+    }
+
+    pub async fn scan(&self) -> Result<Vec<PciDevice>> {
+        let dir = self.devices_dir();
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read {}", dir.display()))?;
+
+        let mut devices = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            devices.push(
+                read_sysfs_device(&entry.path(), &name)
+                    .await
+                    .with_context(|| format!("Failed to read PCI device {}", name))?,
+            );
+        }
+
+        devices.sort_by(|a, b| a.slot.cmp(&b.slot));
+        Ok(devices)
+    }
+}
+
+async fn read_sysfs_device(path: &Path, address: &str) -> Result<PciDevice> {
+    let class = read_sysfs_hex(path, "class").await?;
+    let revision = match read_sysfs_attr(path, "revision").await {
+        Some(value) => Some(parse_sysfs_hex(&value, "revision")? as u8),
+        None => None,
+    };
+    let subsystem_vendor_id = match read_sysfs_attr(path, "subsystem_vendor").await {
+        Some(value) => Some(parse_sysfs_hex(&value, "subsystem_vendor")? as u16),
+        None => None,
+    };
+    let subsystem_device_id = match read_sysfs_attr(path, "subsystem_device").await {
+        Some(value) => Some(parse_sysfs_hex(&value, "subsystem_device")? as u16),
+        None => None,
+    };
+    let driver = fs::read_link(path.join("driver"))
+        .await
+        .ok()
+        .and_then(|target| target.file_name().map(|n| n.to_string_lossy().into_owned()));
+
+    Ok(PciDevice {
+        // lspci omits the domain when it is 0000, keep slots comparable
+        slot: address.strip_prefix("0000:").unwrap_or(address).to_string(),
+        class: (class >> 16) as u8,
+        subclass: (class >> 8) as u8,
+        prog_if: Some(class as u8),
+        vendor_id: read_sysfs_hex(path, "vendor").await? as u16,
+        device_id: read_sysfs_hex(path, "device").await? as u16,
+        subsystem_vendor_id,
+        subsystem_device_id,
+        revision,
+        driver,
+        modalias: read_sysfs_attr(path, "modalias").await,
+        ..Default::default()
+    })
+}
+
+async fn read_sysfs_attr(path: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(path.join(attr))
+        .await
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+async fn read_sysfs_hex(path: &Path, attr: &str) -> Result<u32> {
+    let value = read_sysfs_attr(path, attr)
+        .await
+        .with_context(|| format!("Missing sysfs attribute {}", attr))?;
+    parse_sysfs_hex(&value, attr)
+}
+
+fn parse_sysfs_hex(value: &str, attr: &str) -> Result<u32> {
+    u32::from_str_radix(value.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid sysfs {} value: {}", attr, value))
+}
+
+/// Splits `"Intel Corporation [8086]"` into the name and the bracketed ID.
+fn split_name_id(value: &str) -> (String, Option<&str>) {
+    match value.rsplit_once(" [") {
+        Some((name, rest)) if rest.ends_with(']') => {
+            (name.to_string(), Some(&rest[..rest.len() - 1]))
+        }
+        _ => match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            Some(id) => (String::new(), Some(id)),
+            None => (value.to_string(), None),
+        },
+    }
+}
+
+fn parse_hex(value: Option<&str>, what: &str) -> Result<u16> {
+    let value = value.with_context(|| format!("Missing {} ID in lspci output", what))?;
+    u16::from_str_radix(value, 16)
+        .with_context(|| format!("Invalid {} ID in lspci output: {}", what, value))
+}
+
+fn parse_hex_byte(value: &str, what: &str) -> Result<u8> {
+    u8::from_str_radix(value, 16)
+        .with_context(|| format!("Invalid {} in lspci output: {}", what, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    #[test]
+    fn parses_thinkpad_t480() {
+        let devices =
+            parse_lspci_records(include_str!("fixtures/lspci/thinkpad-t480.txt")).unwrap();
+        assert_eq!(devices.len(), 6);
+
+        let gpu = &devices[1];
+        assert_eq!(gpu.slot, "00:02.0");
+        assert_eq!(gpu.class_name, "VGA compatible controller");
+        assert_eq!(
+            (gpu.class, gpu.subclass, gpu.prog_if),
+            (0x03, 0x00, Some(0x00))
+        );
+        assert_eq!((gpu.vendor_id, gpu.device_id), (0x8086, 0x5917));
+        assert_eq!(gpu.vendor_name, "Intel Corporation");
+        assert_eq!(gpu.device_name, "UHD Graphics 620");
+        assert_eq!(gpu.subsystem_vendor_id, Some(0x17aa));
+        assert_eq!(gpu.subsystem_device_id, Some(0x225d));
+        assert_eq!(gpu.revision, Some(0x07));
+        assert_eq!(gpu.driver.as_deref(), Some("i915"));
+        assert_eq!(gpu.modules, vec!["i915"]);
+
+        let audio = &devices[2];
+        assert_eq!(audio.modules.len(), 3);
+        assert_eq!(audio.driver.as_deref(), Some("snd_hda_intel"));
+
+        let nvme = &devices[5];
+        assert_eq!(
+            (nvme.class, nvme.subclass, nvme.prog_if),
+            (0x01, 0x08, Some(0x02))
+        );
+        assert_eq!(nvme.revision, None);
+    }
+
+    #[test]
+    fn parses_threadripper_with_nvidia() {
+        let devices =
+            parse_lspci_records(include_str!("fixtures/lspci/threadripper-3970x.txt")).unwrap();
+        assert_eq!(devices.len(), 5);
+
+        let root = &devices[0];
+        assert_eq!(root.vendor_name, "Advanced Micro Devices, Inc. [AMD]");
+        assert_eq!(root.vendor_id, 0x1022);
+        assert_eq!(root.revision, None);
+        assert!(root.driver.is_none());
+
+        let gpu = &devices[1];
+        assert_eq!(gpu.device_name, "GA102 [GeForce RTX 3090]");
+        assert_eq!((gpu.vendor_id, gpu.device_id), (0x10de, 0x2204));
+        assert_eq!(gpu.revision, Some(0xa1));
+        assert_eq!(gpu.driver.as_deref(), Some("nvidia"));
+        assert_eq!(gpu.modules, vec!["nouveau", "nvidia_drm", "nvidia"]);
+
+        let wifi = &devices[4];
+        assert!(wifi.driver.is_none());
+        assert_eq!(wifi.modules, vec!["iwlwifi"]);
+    }
+
+    #[test]
+    fn parses_radeon_desktop() {
+        let devices =
+            parse_lspci_records(include_str!("fixtures/lspci/radeon-hd7870.txt")).unwrap();
+        assert_eq!(devices.len(), 3);
+        assert_eq!(devices[0].subsystem_vendor_id, None);
+        assert_eq!(
+            devices[1].vendor_name,
+            "Advanced Micro Devices, Inc. [AMD/ATI]"
+        );
+        assert_eq!(devices[1].modules, vec!["radeon", "amdgpu"]);
+        assert_eq!(devices[2].vendor_id, 0x14e4);
+        assert!(devices[2].driver.is_none());
+    }
+
+    #[test]
+    fn synthesizes_modalias_from_ids() {
+        let devices =
+            parse_lspci_records(include_str!("fixtures/lspci/thinkpad-t480.txt")).unwrap();
+        assert_eq!(
+            devices[1].modalias(),
+            "pci:v00008086d00005917sv000017AAsd0000225Dbc03sc00i00"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_ids() {
+        let err = parse_lspci_records("Slot:\t00:00.0\nVendor:\tAcme [zzzz]\n").unwrap_err();
+        assert!(err.to_string().contains("vendor"));
+    }
+
+    fn add_sysfs_device(
+        sysfs: &TempTree,
+        address: &str,
+        attrs: &[(&str, &str)],
+        driver: Option<&str>,
+    ) {
+        let dir = format!("bus/pci/devices/{}", address);
+        for (attr, value) in attrs {
+            sysfs.write(&format!("{}/{}", dir, attr), &format!("{}\n", value));
+        }
+        if let Some(driver) = driver {
+            sysfs.symlink(
+                &format!("{}/driver", dir),
+                &format!("bus/pci/drivers/{}", driver),
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn scans_fake_sysfs_tree() {
+        let sysfs = TempTree::new("sysfs-pci");
+        add_sysfs_device(
+            &sysfs,
+            "0000:01:00.0",
+            &[
+                ("vendor", "0x10de"),
+                ("device", "0x2204"),
+                ("class", "0x030000"),
+                ("subsystem_vendor", "0x1043"),
+                ("subsystem_device", "0x87b3"),
+                ("revision", "0xa1"),
+                (
+                    "modalias",
+                    "pci:v000010DEd00002204sv00001043sd000087B3bc03sc00i00",
+                ),
+            ],
+            Some("nvidia"),
+        );
+        add_sysfs_device(
+            &sysfs,
+            "0000:00:00.0",
+            &[
+                ("vendor", "0x1022"),
+                ("device", "0x1480"),
+                ("class", "0x060000"),
+            ],
+            None,
+        );
+
+        let devices = SysfsPciDetector::new(sysfs.path()).scan().await.unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let bridge = &devices[0];
+        assert_eq!(bridge.slot, "00:00.0");
+        assert_eq!((bridge.class, bridge.subclass), (0x06, 0x00));
+        assert!(bridge.driver.is_none());
+        assert!(bridge.modalias.is_none());
+
+        let gpu = &devices[1];
+        assert_eq!(gpu.slot, "01:00.0");
+        assert_eq!((gpu.vendor_id, gpu.device_id), (0x10de, 0x2204));
+        assert_eq!(
+            (gpu.class, gpu.subclass, gpu.prog_if),
+            (0x03, 0x00, Some(0x00))
+        );
+        assert_eq!(gpu.subsystem_vendor_id, Some(0x1043));
+        assert_eq!(gpu.subsystem_device_id, Some(0x87b3));
+        assert_eq!(gpu.revision, Some(0xa1));
+        assert_eq!(gpu.driver.as_deref(), Some("nvidia"));
+        assert_eq!(
+            gpu.modalias.as_deref(),
+            Some("pci:v000010DEd00002204sv00001043sd000087B3bc03sc00i00")
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_sysfs_root_is_an_error() {
+        let detector = SysfsPciDetector::new("/nonexistent/ardenthat-sysfs");
+        assert!(detector.scan().await.is_err());
+    }
+}
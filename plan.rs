@@ -0,0 +1,1199 @@
+//! Setup plans: every change setup will make, checked for conflicts before anything runs
+
+use crate::aur::{self, AurTarget};
+use crate::command::CommandLine;
+use crate::journal::{FileRecord, Journal, PackageChange, Transaction};
+use crate::knowledge::AurPackage;
+use crate::packages::PackageDatabase;
+use crate::{HardwareComponent, Host, SetupOptions};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use tokio::fs;
+
+/// A package, kernel module or service with the reason it is part of the plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanItem {
+    pub name: String,
+    pub reason: String,
+}
+
+/// A package pacman will install, as resolved from the sync databases.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub version: String,
+    /// Download size in bytes
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileChange {
+    pub path: String,
+    pub contents: String,
+    /// Contents when the plan was made, `None` if the file does not exist
+    #[serde(default)]
+    pub previous: Option<String>,
+    /// Back up an existing file to `<path>.ardenthat.bak` before the first change
+    pub backup: bool,
+    pub reason: String,
+}
+
+/// A command run after packages, files, modules and services are in place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Action {
+    pub command: CommandLine,
+    pub reason: String,
+}
+
+/// The hardware and packages a plan was made for, checked again before a saved
+/// plan is applied.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemState {
+    /// One entry per device, e.g. `pci 0000:00:02.0 8086:5917`
+    pub hardware: Vec<String>,
+    /// Installed version of every package the plan touches, `None` if not installed
+    pub packages: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    /// Options the plan was made with
+    #[serde(default)]
+    pub options: SetupOptions,
+    pub install: Vec<PlanItem>,
+    pub remove: Vec<PlanItem>,
+    /// Everything pacman will install, including dependencies
+    pub targets: Vec<Target>,
+    /// Packages built from the AUR after the pacman transaction
+    #[serde(default)]
+    pub aur: Vec<AurTarget>,
+    #[serde(default)]
+    pub aur_builder: Option<aur::Builder>,
+    pub load_modules: Vec<PlanItem>,
+    pub blacklist_modules: Vec<PlanItem>,
+    pub files: Vec<FileChange>,
+    /// systemd units to enable, none of the bundled rules needs one yet
+    #[serde(default)]
+    pub services: Vec<PlanItem>,
+    /// Kernel command line parameters added to the bootloader configuration
+    pub kernel_params: Vec<PlanItem>,
+    pub initramfs: Vec<Action>,
+    pub bootloader: Vec<Action>,
+    pub conflicts: Vec<String>,
+    /// Required drivers that need no change, such as modules that are already loaded
+    #[serde(default)]
+    pub unchanged: Vec<PlanItem>,
+    /// Findings made while planning that change nothing, such as microcode revisions
+    #[serde(default)]
+    pub notes: Vec<String>,
+    pub state: SystemState,
+}
+
+impl Plan {
+    pub fn install(&mut self, name: &str, reason: &str) {
+        push_item(&mut self.install, name, reason);
+    }
+
+    pub fn remove(&mut self, name: &str, reason: &str) {
+        push_item(&mut self.remove, name, reason);
+    }
+
+    pub fn aur(&mut self, package: &AurPackage, reason: &str) {
+        if !self.aur.iter().any(|t| t.name == package.name) {
+            self.aur.push(AurTarget {
+                name: package.name.clone(),
+                pkgbase: package.pkgbase.clone(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    pub fn load_module(&mut self, name: &str, reason: &str) {
+        push_item(&mut self.load_modules, name, reason);
+    }
+
+    /// Lists a required driver that is already in place.
+    pub fn unchanged(&mut self, name: &str, reason: &str) {
+        push_item(&mut self.unchanged, name, reason);
+    }
+
+    pub fn kernel_param(&mut self, name: &str, reason: &str) {
+        push_item(&mut self.kernel_params, name, reason);
+    }
+
+    /// Plans a bootloader command once, however many changes need it.
+    pub fn bootloader_action(&mut self, command: CommandLine, reason: &str) {
+        match self.bootloader.iter_mut().find(|a| a.command == command) {
+            Some(action) if !action.reason.split("; ").any(|r| r == reason) => {
+                action.reason = format!("{}; {}", action.reason, reason);
+            }
+            Some(_) => {}
+            None => self.bootloader.push(Action {
+                command,
+                reason: reason.to_string(),
+            }),
+        }
+    }
+
+    /// Plans writing `contents` to `path`. A second write to the same path replaces
+    /// the contents and adds its reason.
+    pub fn write_file(&mut self, path: &str, contents: String, backup: bool, reason: &str) {
+        if let Some(file) = self.files.iter_mut().find(|f| f.path == path) {
+            file.contents = contents;
+            file.backup |= backup;
+            if !file.reason.split("; ").any(|r| r == reason) {
+                file.reason = format!("{}; {}", file.reason, reason);
+            }
+            return;
+        }
+        self.files.push(FileChange {
+            path: path.to_string(),
+            contents,
+            previous: None,
+            backup,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Contents `path` will have after the plan's file writes, if it writes it.
+    pub fn planned_contents(&self, path: &str) -> Option<&str> {
+        self.files
+            .iter()
+            .find(|f| f.path == path)
+            .map(|f| f.contents.as_str())
+    }
+
+    pub fn note(&mut self, message: String) {
+        if !self.notes.contains(&message) {
+            self.notes.push(message);
+        }
+    }
+
+    pub fn conflict(&mut self, message: String) {
+        if !self.conflicts.contains(&message) {
+            self.conflicts.push(message);
+        }
+    }
+
+    /// Whether executing the plan would change the system.
+    pub fn has_changes(&self) -> bool {
+        !(self.install.is_empty()
+            && self.aur.is_empty()
+            && self.remove.is_empty()
+            && self.load_modules.is_empty()
+            && self.blacklist_modules.is_empty()
+            && self.files.is_empty()
+            && self.services.is_empty()
+            && self.kernel_params.is_empty()
+            && self.initramfs.is_empty()
+            && self.bootloader.is_empty())
+    }
+
+    /// Records conflicts between the plan's own steps.
+    pub fn check_conflicts(&mut self) {
+        let mut found = Vec::new();
+        for package in &self.install {
+            if self.remove.iter().any(|p| p.name == package.name) {
+                found.push(format!("{} is both installed and removed", package.name));
+            }
+        }
+        for module in &self.load_modules {
+            if self.blacklist_modules.iter().any(|m| m.name == module.name) {
+                found.push(format!(
+                    "Kernel module {} is both loaded and blacklisted",
+                    module.name
+                ));
+            }
+        }
+        for message in found {
+            self.conflict(message);
+        }
+    }
+
+    /// Resolves the packages to install with pacman, recording dependencies and conflicts.
+    pub fn resolve_targets(&mut self, host: &Host<'_>) {
+        if self.install.is_empty() {
+            return;
+        }
+        let names: Vec<&str> = self.install.iter().map(|p| p.name.as_str()).collect();
+        match query_targets(host, &names) {
+            Ok(targets) => self.targets = targets,
+            Err(err) => self.conflict(format!("{:#}", err)),
+        }
+    }
+
+    /// Records what the plan was made for: devices, package versions and the
+    /// current contents of every file it writes.
+    pub async fn record_state(
+        &mut self,
+        host: &Host<'_>,
+        components: &[HardwareComponent],
+        packages: Option<&PackageDatabase>,
+    ) {
+        self.state = self.current_state(components, packages);
+        for file in &mut self.files {
+            file.previous = fs::read_to_string(host.path(&file.path)).await.ok();
+        }
+    }
+
+    /// Fails if the system no longer matches the state recorded with the plan.
+    pub async fn check_drift(
+        &self,
+        host: &Host<'_>,
+        components: &[HardwareComponent],
+        packages: Option<&PackageDatabase>,
+    ) -> Result<()> {
+        let current = self.current_state(components, packages);
+        let mut changes = Vec::new();
+
+        for device in &current.hardware {
+            if !self.state.hardware.contains(device) {
+                changes.push(format!("New device {}", device));
+            }
+        }
+        for device in &self.state.hardware {
+            if !current.hardware.contains(device) {
+                changes.push(format!("Device {} is gone", device));
+            }
+        }
+        for (name, version) in &self.state.packages {
+            let now = current.packages.get(name).cloned().flatten();
+            if now != *version {
+                changes.push(format!(
+                    "{} is now {}, was {}",
+                    name,
+                    now.as_deref().unwrap_or("not installed"),
+                    version.as_deref().unwrap_or("not installed")
+                ));
+            }
+        }
+        for file in &self.files {
+            if fs::read_to_string(host.path(&file.path)).await.ok() != file.previous {
+                changes.push(format!("{} changed", file.path));
+            }
+        }
+        if !self.install.is_empty() {
+            let names: Vec<&str> = self.install.iter().map(|p| p.name.as_str()).collect();
+            let targets = query_targets(host, &names)?;
+            for target in targets.iter().filter(|t| !self.targets.contains(t)) {
+                changes.push(format!(
+                    "pacman now resolves {} {}",
+                    target.name, target.version
+                ));
+            }
+        }
+
+        if !changes.is_empty() {
+            anyhow::bail!(
+                "The system changed since the plan was made, no changes were made:\n  {}\n\
+                 Run `ardenthat setup --dry-run` again to review a new plan",
+                changes.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails unless `regenerated`, made again with the saved options, has the same
+    /// steps, so a saved plan can only run what setup itself would plan.
+    pub fn check_regenerated(&self, regenerated: &Plan) -> Result<()> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, same: bool| {
+            if !same {
+                changes.push(name.to_string());
+            }
+        };
+        compare("packages to install", self.install == regenerated.install);
+        compare("packages to remove", self.remove == regenerated.remove);
+        compare("AUR packages", self.aur == regenerated.aur);
+        compare("AUR builder", self.aur_builder == regenerated.aur_builder);
+        compare(
+            "kernel modules to load",
+            self.load_modules == regenerated.load_modules,
+        );
+        compare(
+            "kernel modules to blacklist",
+            self.blacklist_modules == regenerated.blacklist_modules,
+        );
+        compare("services", self.services == regenerated.services);
+        compare(
+            "kernel parameters",
+            self.kernel_params == regenerated.kernel_params,
+        );
+        compare(
+            "initramfs commands",
+            self.initramfs == regenerated.initramfs,
+        );
+        compare(
+            "bootloader commands",
+            self.bootloader == regenerated.bootloader,
+        );
+        compare("conflicts", self.conflicts == regenerated.conflicts);
+        for file in &self.files {
+            let same = regenerated.files.iter().any(|f| {
+                f.path == file.path && f.contents == file.contents && f.backup == file.backup
+            });
+            if !same {
+                changes.push(format!("file {}", file.path));
+            }
+        }
+        for file in &regenerated.files {
+            if !self.files.iter().any(|f| f.path == file.path) {
+                changes.push(format!("file {}", file.path));
+            }
+        }
+
+        if !changes.is_empty() {
+            anyhow::bail!(
+                "The saved plan differs from what setup plans now, no changes were made:\n  {}\n\
+                 Run `ardenthat setup --dry-run` again to review a new plan",
+                changes.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    fn current_state(
+        &self,
+        components: &[HardwareComponent],
+        packages: Option<&PackageDatabase>,
+    ) -> SystemState {
+        let mut hardware: Vec<String> = components.iter().filter_map(hardware_id).collect();
+        hardware.sort();
+        let names = self
+            .install
+            .iter()
+            .chain(&self.remove)
+            .map(|p| &p.name)
+            .chain(self.targets.iter().map(|t| &t.name))
+            .chain(self.aur.iter().map(|t| &t.name));
+        let packages = names
+            .map(|name| {
+                let version = packages.and_then(|db| db.installed.get(name).cloned());
+                (name.clone(), version)
+            })
+            .collect();
+        SystemState { hardware, packages }
+    }
+
+    /// Writes the plan as JSON for `setup --apply-plan`.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)?;
+        fs::write(path, text)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Invalid plan in {}", path.display()))
+    }
+
+    /// Applies the plan: one pacman transaction, the AUR builds, then files,
+    /// modules, services, initramfs and bootloader. Nothing runs if the plan has conflicts,
+    /// and replaced packages are removed only once their replacements are downloaded.
+    ///
+    /// Each step is recorded in `transaction` and saved to `journal` before it runs.
+    pub async fn execute(
+        &self,
+        host: &Host<'_>,
+        packages: Option<&PackageDatabase>,
+        journal: &Journal,
+        transaction: &mut Transaction,
+    ) -> Result<()> {
+        if !self.conflicts.is_empty() {
+            anyhow::bail!(
+                "Setup plan has conflicts, no changes were made:\n  {}",
+                self.conflicts.join("\n  ")
+            );
+        }
+        let previous_version = |name: &str| packages.and_then(|db| db.installed.get(name).cloned());
+
+        // A failed download must not leave the system without the replaced packages
+        if !self.remove.is_empty() && !self.install.is_empty() {
+            let mut argv = vec!["pacman", "-Sw", "--noconfirm"];
+            argv.extend(self.install.iter().map(|p| p.name.as_str()));
+            run(
+                host,
+                CommandLine::new(argv),
+                "Failed to download packages, no changes were made",
+            )?;
+        }
+        if !self.remove.is_empty() {
+            for package in &self.remove {
+                transaction.removed.push(PackageChange {
+                    name: package.name.clone(),
+                    previous_version: previous_version(&package.name),
+                });
+            }
+            journal.save(transaction).await?;
+            let mut argv = vec!["pacman", "-R", "--noconfirm"];
+            argv.extend(self.remove.iter().map(|p| p.name.as_str()));
+            run(host, CommandLine::new(argv), "Failed to remove packages")?;
+        }
+        if !self.install.is_empty() {
+            // Dependencies pulled in by the transaction are undone with it
+            let targets: Vec<String> = if self.targets.is_empty() {
+                self.install.iter().map(|p| p.name.clone()).collect()
+            } else {
+                self.targets.iter().map(|t| t.name.clone()).collect()
+            };
+            for name in targets {
+                transaction.installed.push(PackageChange {
+                    previous_version: previous_version(&name),
+                    name,
+                });
+            }
+            journal.save(transaction).await?;
+            let mut argv = vec!["pacman", "-S", "--needed", "--noconfirm"];
+            argv.extend(self.install.iter().map(|p| p.name.as_str()));
+            run(host, CommandLine::new(argv), "Failed to install packages")?;
+        }
+        if !self.aur.is_empty() {
+            for target in &self.aur {
+                transaction.installed.push(PackageChange {
+                    name: target.name.clone(),
+                    previous_version: previous_version(&target.name),
+                });
+            }
+            journal.save(transaction).await?;
+            self.build_aur(host, journal, transaction).await?;
+        }
+        let packages = self.install.iter().map(|p| &p.name);
+        if packages
+            .chain(self.aur.iter().map(|t| &t.name))
+            .any(|name| crate::kernel::is_dkms(name))
+        {
+            verify_dkms_builds(host).await;
+        }
+
+        for file in &self.files {
+            transaction.files.push(FileRecord {
+                path: file.path.clone(),
+                previous: fs::read_to_string(host.path(&file.path)).await.ok(),
+            });
+            journal.save(transaction).await?;
+
+            let backup = crate::initramfs::backup_path(&file.path);
+            if file.backup && host.path(&file.path).exists() && !host.path(&backup).exists() {
+                run(
+                    host,
+                    CommandLine::new(["cp", "-a", file.path.as_str(), &backup]),
+                    &format!("Failed to back up {}", file.path),
+                )?;
+                println!("Backed up {} to {}", file.path, backup);
+            }
+            if let Some(parent) = Path::new(&file.path).parent() {
+                let parent = parent.to_string_lossy();
+                if !host.path(&parent).exists() {
+                    run(
+                        host,
+                        CommandLine::new(["mkdir", "-p", &parent]),
+                        &format!("Failed to create {}", parent),
+                    )?;
+                }
+            }
+            run(
+                host,
+                CommandLine::new(["tee", file.path.as_str()]).stdin(file.contents.as_str()),
+                &format!("Failed to write {}", file.path),
+            )?;
+        }
+        if !self.blacklist_modules.is_empty() {
+            transaction.blacklisted = self
+                .blacklist_modules
+                .iter()
+                .map(|m| m.name.clone())
+                .collect();
+            journal.save(transaction).await?;
+        }
+
+        for module in &self.load_modules {
+            run(
+                host,
+                CommandLine::new(["modprobe", module.name.as_str()]),
+                &format!("Failed to load kernel module: {}", module.name),
+            )?;
+        }
+
+        if !self.services.is_empty() {
+            transaction.services = self.services.iter().map(|s| s.name.clone()).collect();
+            journal.save(transaction).await?;
+            let mut argv = vec!["systemctl", "enable"];
+            argv.extend(self.services.iter().map(|s| s.name.as_str()));
+            run(host, CommandLine::new(argv), "Failed to enable services")?;
+        }
+
+        if !self.initramfs.is_empty() {
+            transaction.initramfs = true;
+            journal.save(transaction).await?;
+        }
+        if !self.bootloader.is_empty() {
+            transaction.bootloader = self.bootloader.iter().map(|a| a.command.clone()).collect();
+            journal.save(transaction).await?;
+        }
+        for action in self.initramfs.iter().chain(&self.bootloader) {
+            run(
+                host,
+                action.command.clone(),
+                &format!("Failed to run {}", action.command),
+            )?;
+        }
+        if !self.kernel_params.is_empty() {
+            println!("Reboot to apply the new kernel parameters");
+        }
+        Ok(())
+    }
+}
+
+impl Plan {
+    /// Builds and installs the AUR packages in one run of the helper, else one AUR
+    /// repository at a time: makepkg runs as the unprivileged user after root
+    /// installed the missing build dependencies, which are recorded in
+    /// `transaction` like every other package.
+    async fn build_aur(
+        &self,
+        host: &Host<'_>,
+        journal: &Journal,
+        transaction: &mut Transaction,
+    ) -> Result<()> {
+        let Some(builder) = &self.aur_builder else {
+            anyhow::bail!("The plan has AUR packages but no user to build them");
+        };
+
+        let names: Vec<&str> = self.aur.iter().map(|t| t.name.as_str()).collect();
+        if let Some(command) = builder.helper_command(&names) {
+            let error = format!("Failed to build AUR packages with {}", builder.tool());
+            return run(host, command, &error);
+        }
+
+        let mut pkgbases: Vec<&str> = Vec::new();
+        for target in &self.aur {
+            if !pkgbases.contains(&target.pkgbase.as_str()) {
+                pkgbases.push(&target.pkgbase);
+            }
+        }
+        for pkgbase in pkgbases {
+            let error = format!("Failed to build {} from the AUR", pkgbase);
+            for command in builder.clone_commands(pkgbase) {
+                run(host, command, &error)?;
+            }
+            let srcinfo = host
+                .run(builder.srcinfo_command(pkgbase))
+                .with_context(|| error.clone())?;
+            if !srcinfo.success() {
+                anyhow::bail!("{}: makepkg cannot read the PKGBUILD", error);
+            }
+            let dependencies = aur::build_dependencies(&srcinfo.stdout);
+            if !dependencies.is_empty() {
+                // pacman -T prints the dependencies nothing installed satisfies
+                let mut argv = vec!["pacman", "-T"];
+                argv.extend(dependencies.iter().map(String::as_str));
+                let missing = host
+                    .run(CommandLine::new(argv).capture())
+                    .context("Failed to run pacman")?;
+                let missing: Vec<&str> = missing.stdout.lines().collect();
+                if !missing.is_empty() {
+                    for dependency in &missing {
+                        transaction.installed.push(PackageChange {
+                            name: aur::dependency_name(dependency).to_string(),
+                            previous_version: None,
+                        });
+                    }
+                    journal.save(transaction).await?;
+                    let mut argv = vec!["pacman", "-S", "--needed", "--asdeps", "--noconfirm"];
+                    argv.extend(missing);
+                    run(host, CommandLine::new(argv), &error)?;
+                }
+            }
+            run(host, builder.build_command(pkgbase), &error)?;
+
+            let list = host
+                .run(builder.packagelist_command(pkgbase))
+                .with_context(|| error.clone())?;
+            let wanted: Vec<&str> = self
+                .aur
+                .iter()
+                .filter(|t| t.pkgbase == pkgbase)
+                .map(|t| t.name.as_str())
+                .collect();
+            let files = aur::built_packages(&list.stdout, &wanted);
+            if files.is_empty() {
+                anyhow::bail!("makepkg built none of {} in {}", wanted.join(" "), pkgbase);
+            }
+            let mut argv = vec!["pacman", "-U", "--noconfirm"];
+            argv.extend(files.iter().map(String::as_str));
+            run(host, CommandLine::new(argv), &error)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.has_changes() && self.conflicts.is_empty() {
+            writeln!(f, "Nothing to do, all required drivers are set up")?;
+            write_items(f, "Already set up", "=", &self.unchanged)?;
+            return write_notes(f, &self.notes);
+        }
+
+        write_items(f, "Packages to install", "+", &self.install)?;
+        let dependencies: Vec<&str> = self
+            .targets
+            .iter()
+            .map(|t| t.name.as_str())
+            .filter(|t| !self.install.iter().any(|p| p.name == *t))
+            .collect();
+        if !dependencies.is_empty() {
+            writeln!(f, "  Dependencies: {}", dependencies.join(" "))?;
+        }
+        let size: u64 = self.targets.iter().map(|t| t.size).sum();
+        if size > 0 {
+            writeln!(f, "  Download size: {}", format_size(size))?;
+        }
+        if !self.aur.is_empty() {
+            let builder = match &self.aur_builder {
+                Some(builder) => format!(" as {} with {}", builder.user.name, builder.tool()),
+                None => String::new(),
+            };
+            writeln!(f, "AUR packages to build{}:", builder)?;
+            for target in &self.aur {
+                writeln!(f, "  + {} ({})", target.name, target.reason)?;
+                writeln!(f, "    PKGBUILD: {}", target.pkgbuild_url())?;
+            }
+        }
+        write_items(f, "Packages to remove", "-", &self.remove)?;
+        write_items(f, "Kernel modules to load", "+", &self.load_modules)?;
+        write_items(
+            f,
+            "Kernel modules to blacklist",
+            "-",
+            &self.blacklist_modules,
+        )?;
+        if !self.files.is_empty() {
+            writeln!(f, "Files to write:")?;
+            for file in &self.files {
+                let backup = if file.backup { ", with backup" } else { "" };
+                writeln!(f, "  * {} ({}{})", file.path, file.reason, backup)?;
+                let diff =
+                    crate::diff::unified(&file.path, file.previous.as_deref(), &file.contents);
+                for line in diff.lines() {
+                    writeln!(f, "    {}", line)?;
+                }
+            }
+        }
+        write_items(f, "Services to enable", "+", &self.services)?;
+        write_items(f, "Kernel parameters to add", "+", &self.kernel_params)?;
+        write_actions(f, "Initramfs", &self.initramfs)?;
+        write_actions(f, "Bootloader", &self.bootloader)?;
+        write_items(f, "Already set up", "=", &self.unchanged)?;
+        write_notes(f, &self.notes)?;
+        if !self.conflicts.is_empty() {
+            writeln!(f, "Conflicts:")?;
+            for conflict in &self.conflicts {
+                writeln!(f, "  ! {}", conflict)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stable identity of a device, without bus numbers that change on replug.
+fn hardware_id(component: &HardwareComponent) -> Option<String> {
+    if let Some(device) = &component.pci {
+        return Some(format!(
+            "pci {} {:04x}:{:04x}",
+            device.slot, device.vendor_id, device.device_id
+        ));
+    }
+    if let Some(device) = &component.usb {
+        return Some(format!(
+            "usb {:04x}:{:04x}",
+            device.vendor_id, device.product_id
+        ));
+    }
+    component
+        .cpu
+        .as_ref()
+        .map(|cpu| format!("cpu {}", cpu.model_name))
+}
+
+/// Packages pacman would install for `names`, dependencies included.
+fn query_targets(host: &Host<'_>, names: &[&str]) -> Result<Vec<Target>> {
+    let mut argv = vec!["pacman", "-S", "--print", "--print-format", "%n %v %s"];
+    argv.extend(names);
+    let output = host
+        .run(CommandLine::new(argv).capture())
+        .context("Failed to run pacman")?;
+    if !output.success() {
+        let details = output.stderr.trim();
+        if details.is_empty() {
+            anyhow::bail!("pacman cannot resolve the packages to install");
+        }
+        anyhow::bail!("pacman cannot resolve the packages to install: {}", details);
+    }
+
+    Ok(output
+        .stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Target {
+                name: fields.next()?.to_string(),
+                version: fields.next().unwrap_or_default().to_string(),
+                size: fields.next().and_then(|s| s.parse().ok()).unwrap_or(0),
+            })
+        })
+        .collect())
+}
+
+/// Human-readable size in binary units, as pacman prints them.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn push_item(items: &mut Vec<PlanItem>, name: &str, reason: &str) {
+    if !items.iter().any(|i| i.name == name) {
+        items.push(PlanItem {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+fn write_notes(f: &mut fmt::Formatter<'_>, notes: &[String]) -> fmt::Result {
+    if !notes.is_empty() {
+        writeln!(f, "Notes:")?;
+        for note in notes {
+            writeln!(f, "  - {}", note)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_items(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    marker: &str,
+    items: &[PlanItem],
+) -> fmt::Result {
+    if !items.is_empty() {
+        writeln!(f, "{}:", title)?;
+        for item in items {
+            writeln!(f, "  {} {} ({})", marker, item.name, item.reason)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_actions(f: &mut fmt::Formatter<'_>, title: &str, actions: &[Action]) -> fmt::Result {
+    if !actions.is_empty() {
+        writeln!(f, "{}:", title)?;
+        for action in actions {
+            writeln!(f, "  $ {} ({})", action.command, action.reason)?;
+        }
+    }
+    Ok(())
+}
+
+/// Warns about DKMS modules that were not built for an installed kernel, which
+/// pacman's DKMS hook only reports in its own output.
+async fn verify_dkms_builds(host: &Host<'_>) {
+    let kernels = crate::initramfs::installed_kernels(&host.root)
+        .await
+        .unwrap_or_default();
+    let status = match host.run(CommandLine::new(["dkms", "status"]).capture()) {
+        Ok(output) if output.success() => output.stdout,
+        _ => {
+            println!("Warning: could not run dkms status to check the module builds");
+            return;
+        }
+    };
+    let entries = crate::kernel::parse_dkms_status(&status);
+    for (module, release) in crate::kernel::missing_builds(&entries, &kernels) {
+        println!(
+            "Warning: DKMS did not build {} for kernel {}, run dkms autoinstall -k {} after fixing its headers",
+            module, release, release
+        );
+    }
+}
+
+fn run(host: &Host<'_>, command: CommandLine, error: &str) -> Result<()> {
+    let output = host.run(command).with_context(|| error.to_string())?;
+    if !output.success() {
+        anyhow::bail!("{}", error);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MockRunner;
+    use crate::testutil::TempTree;
+
+    fn plan() -> Plan {
+        let mut plan = Plan::default();
+        plan.install("nvidia-open", "GPU driver");
+        plan.install("nvidia-utils", "GPU driver");
+        plan.install("nvidia-open", "duplicate");
+        plan.load_module("iwlwifi", "WiFi");
+        plan.write_file(
+            "/etc/mkinitcpio.conf",
+            "MODULES=(nvidia)\n".to_string(),
+            true,
+            "early KMS",
+        );
+        plan.initramfs.push(Action {
+            command: CommandLine::new(["mkinitcpio", "-P"]),
+            reason: "rebuild".to_string(),
+        });
+        plan
+    }
+
+    #[tokio::test]
+    async fn executes_in_one_pacman_transaction() {
+        let root = TempTree::new("plan-execute");
+        root.write("etc/mkinitcpio.conf", "MODULES=()\n");
+        let runner = MockRunner::new();
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+
+        let journal = Journal::new(root.path());
+        let db = PackageDatabase::parse("linux 6.11.5.arch1-1\n", "");
+        let mut transaction = journal.begin().await.unwrap();
+        let mut plan = plan();
+        plan.services.push(PlanItem {
+            name: "nvidia-persistenced".to_string(),
+            reason: "keep the GPU initialized".to_string(),
+        });
+        assert!(plan
+            .to_string()
+            .contains("Services to enable:\n  + nvidia-persistenced (keep the GPU initialized)\n"));
+        plan.execute(&host, Some(&db), &journal, &mut transaction)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.transcript(),
+            "$ pacman -S --needed --noconfirm nvidia-open nvidia-utils\n\
+             $ cp -a /etc/mkinitcpio.conf /etc/mkinitcpio.conf.ardenthat.bak\n\
+             $ tee /etc/mkinitcpio.conf\n\
+             > MODULES=(nvidia)\n\
+             $ modprobe iwlwifi\n\
+             $ systemctl enable nvidia-persistenced\n\
+             $ mkinitcpio -P\n"
+        );
+
+        let recorded = journal.list().await.unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].installed, transaction.installed);
+        assert_eq!(recorded[0].installed[0].name, "nvidia-open");
+        assert_eq!(
+            recorded[0].files,
+            vec![FileRecord {
+                path: "/etc/mkinitcpio.conf".to_string(),
+                previous: Some("MODULES=()\n".to_string()),
+            }]
+        );
+        assert_eq!(recorded[0].services, vec!["nvidia-persistenced"]);
+        assert!(recorded[0].initramfs);
+    }
+
+    #[tokio::test]
+    async fn conflicts_abort_before_any_change() {
+        let runner = MockRunner::new();
+        let host = Host {
+            runner: &runner,
+            root: "/nonexistent".into(),
+        };
+
+        let mut plan = plan();
+        plan.remove.push(PlanItem {
+            name: "nvidia-utils".to_string(),
+            reason: "replaced".to_string(),
+        });
+        plan.blacklist_modules.push(PlanItem {
+            name: "iwlwifi".to_string(),
+            reason: "broken".to_string(),
+        });
+        plan.check_conflicts();
+        assert_eq!(
+            plan.conflicts,
+            vec![
+                "nvidia-utils is both installed and removed",
+                "Kernel module iwlwifi is both loaded and blacklisted"
+            ]
+        );
+
+        let journal = Journal::new(&host.root);
+        let mut transaction = Transaction::default();
+        assert!(plan
+            .execute(&host, None, &journal, &mut transaction)
+            .await
+            .is_err());
+        assert!(runner.commands().is_empty());
+        assert!(plan
+            .to_string()
+            .contains("  ! nvidia-utils is both installed and removed\n"));
+    }
+
+    #[tokio::test]
+    async fn replaced_packages_stay_until_the_download_succeeds() {
+        let root = TempTree::new("plan-replace");
+        let download = ["pacman", "-Sw", "--noconfirm", "nvidia-open-dkms"];
+        let runner = MockRunner::new().reply(&download, 1, "");
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+
+        let mut plan = Plan::default();
+        plan.install("nvidia-open-dkms", "GPU driver");
+        plan.remove("nvidia-open", "replaced by nvidia-open-dkms");
+        let journal = Journal::new(root.path());
+        let mut transaction = journal.begin().await.unwrap();
+        let err = plan
+            .execute(&host, None, &journal, &mut transaction)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to download packages, no changes were made"
+        );
+        assert_eq!(
+            runner.transcript(),
+            "$ pacman -Sw --noconfirm nvidia-open-dkms\n"
+        );
+        assert!(journal.list().await.unwrap().is_empty());
+
+        let runner = MockRunner::new();
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+        plan.execute(&host, None, &journal, &mut transaction)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.transcript(),
+            "$ pacman -Sw --noconfirm nvidia-open-dkms\n\
+             $ pacman -R --noconfirm nvidia-open\n\
+             $ pacman -S --needed --noconfirm nvidia-open-dkms\n\
+             $ dkms status\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn shows_sizes_and_file_diffs() {
+        let root = TempTree::new("plan-display");
+        root.write("etc/mkinitcpio.conf", "MODULES=()\n");
+        let runner = MockRunner::new().reply(
+            &[
+                "pacman",
+                "-S",
+                "--print",
+                "--print-format",
+                "%n %v %s",
+                "nvidia-open",
+                "nvidia-utils",
+            ],
+            0,
+            "egl-wayland 4:1.1.19-1 40960\nnvidia-utils 575.64-1 206569472\nnvidia-open 575.64-1 4194304\n",
+        );
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+
+        let mut plan = plan();
+        plan.resolve_targets(&host);
+        plan.record_state(&host, &[], None).await;
+        assert_eq!(
+            plan.to_string(),
+            "Packages to install:\n  \
+             + nvidia-open (GPU driver)\n  \
+             + nvidia-utils (GPU driver)\n  \
+             Dependencies: egl-wayland\n  \
+             Download size: 201.0 MiB\n\
+             Kernel modules to load:\n  \
+             + iwlwifi (WiFi)\n\
+             Files to write:\n  \
+             * /etc/mkinitcpio.conf (early KMS, with backup)\n    \
+             --- /etc/mkinitcpio.conf\n    \
+             +++ /etc/mkinitcpio.conf\n    \
+             @@ -1 +1 @@\n    \
+             -MODULES=()\n    \
+             +MODULES=(nvidia)\n\
+             Initramfs:\n  \
+             $ mkinitcpio -P (rebuild)\n"
+        );
+        assert_eq!(format_size(512), "512 B");
+    }
+
+    #[tokio::test]
+    async fn builds_aur_packages_as_the_user() {
+        let root = TempTree::new("plan-aur");
+        let builder = aur::Builder {
+            user: aur::User {
+                name: "alex".to_string(),
+                home: "/home/alex".to_string(),
+            },
+            helper: None,
+            escalation: None,
+        };
+        let dir = "/home/alex/.cache/ardenthat/aur/nvidia-470xx-utils";
+        let user = "runuser -u alex -- env -C /home/alex HOME=/home/alex \
+                    XDG_CACHE_HOME=/home/alex/.cache";
+        let as_alex = |argv: &[&'static str]| -> Vec<&str> {
+            user.split_whitespace()
+                .chain(argv.iter().copied())
+                .collect()
+        };
+        let runner = MockRunner::new()
+            .reply(
+                &as_alex(&["makepkg", "-D", dir, "--printsrcinfo"]),
+                0,
+                "pkgbase = nvidia-470xx-utils\n\tmakedepends = git\n\tdepends = dkms\n\n\
+                 pkgname = nvidia-470xx-dkms\n\tdepends = nvidia-470xx-utils\n\n\
+                 pkgname = nvidia-470xx-utils\n",
+            )
+            .reply(&["pacman", "-T", "git", "dkms"], 127, "dkms\n")
+            .reply(
+                &as_alex(&["makepkg", "-D", dir, "--packagelist"]),
+                0,
+                &format!(
+                    "{dir}/nvidia-470xx-utils-470.256.02-8-x86_64.pkg.tar.zst\n\
+                     {dir}/opencl-nvidia-470xx-470.256.02-8-x86_64.pkg.tar.zst\n\
+                     {dir}/nvidia-470xx-dkms-470.256.02-8-x86_64.pkg.tar.zst\n"
+                ),
+            );
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+
+        let mut plan = Plan::default();
+        for name in ["nvidia-470xx-dkms", "nvidia-470xx-utils"] {
+            let package = AurPackage {
+                name: name.to_string(),
+                pkgbase: "nvidia-470xx-utils".to_string(),
+            };
+            plan.aur(&package, "GPU driver");
+        }
+        plan.aur_builder = Some(builder.clone());
+
+        let journal = Journal::new(root.path());
+        let mut transaction = journal.begin().await.unwrap();
+        plan.execute(&host, None, &journal, &mut transaction)
+            .await
+            .unwrap();
+        let commands: Vec<String> = runner.commands().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            commands,
+            vec![
+                format!("{user} rm -rf {dir}"),
+                format!("{user} git clone --depth 1 https://aur.archlinux.org/nvidia-470xx-utils.git {dir}"),
+                format!("{user} makepkg -D {dir} --printsrcinfo"),
+                "pacman -T git dkms".to_string(),
+                "pacman -S --needed --asdeps --noconfirm dkms".to_string(),
+                format!("{user} makepkg -D {dir} --noconfirm"),
+                format!("{user} makepkg -D {dir} --packagelist"),
+                format!(
+                    "pacman -U --noconfirm {dir}/nvidia-470xx-utils-470.256.02-8-x86_64.pkg.tar.zst \
+                     {dir}/nvidia-470xx-dkms-470.256.02-8-x86_64.pkg.tar.zst"
+                ),
+                "dkms status".to_string(),
+            ]
+        );
+        let installed: Vec<&str> = transaction
+            .installed
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(
+            installed,
+            vec!["nvidia-470xx-dkms", "nvidia-470xx-utils", "dkms"]
+        );
+
+        // A helper builds and installs every package in one run
+        let runner = MockRunner::new();
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+        plan.aur_builder = Some(aur::Builder {
+            helper: Some(aur::Helper::Paru),
+            escalation: Some(crate::privilege::Escalation::Sudo),
+            ..builder
+        });
+        let mut transaction = journal.begin().await.unwrap();
+        plan.execute(&host, None, &journal, &mut transaction)
+            .await
+            .unwrap();
+        assert_eq!(
+            runner.transcript(),
+            format!(
+                "$ {user} paru -S --needed --noconfirm --sudo sudo \
+                 nvidia-470xx-dkms nvidia-470xx-utils\n$ dkms status\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn saved_plans_refuse_changed_packages() {
+        let root = TempTree::new("plan-drift");
+        let path = root.path().join("plan.json");
+        let runner = MockRunner::new();
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+
+        let mut plan = Plan::default();
+        plan.remove.push(PlanItem {
+            name: "xf86-video-nouveau".to_string(),
+            reason: "replaced by nvidia-open".to_string(),
+        });
+        let before = PackageDatabase::parse("xf86-video-nouveau 1.0.18-1\n", "");
+        plan.record_state(&host, &[], Some(&before)).await;
+        plan.save(&path).await.unwrap();
+
+        let saved = Plan::load(&path).await.unwrap();
+        assert!(saved.check_drift(&host, &[], Some(&before)).await.is_ok());
+        let after = PackageDatabase::parse("", "");
+        let err = saved
+            .check_drift(&host, &[], Some(&after))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("  xf86-video-nouveau is now not installed, was 1.0.18-1\n"));
+    }
+
+    #[test]
+    fn empty_plan_has_nothing_to_do() {
+        let plan = Plan::default();
+        assert!(!plan.has_changes());
+        assert_eq!(
+            plan.to_string(),
+            "Nothing to do, all required drivers are set up\n"
+        );
+    }
+}
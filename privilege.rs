@@ -0,0 +1,143 @@
+//! Privilege escalation for setup: sudo, doas, run0, pkexec or none when already root
+//!
+//! Setup escalates once by re-running itself through the chosen tool, so the
+//! whole transaction runs as root after a single authentication.
+
+use crate::command::CommandLine;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+    /// Run commands directly, e.g. when already root
+    None,
+    Sudo,
+    Doas,
+    Run0,
+    Pkexec,
+}
+
+/// Tools tried in order when nothing is configured.
+const AUTO_DETECT: [Escalation; 4] = [
+    Escalation::Sudo,
+    Escalation::Doas,
+    Escalation::Run0,
+    Escalation::Pkexec,
+];
+
+impl Escalation {
+    pub fn program(self) -> Option<&'static str> {
+        match self {
+            Escalation::None => None,
+            Escalation::Sudo => Some("sudo"),
+            Escalation::Doas => Some("doas"),
+            Escalation::Run0 => Some("run0"),
+            Escalation::Pkexec => Some("pkexec"),
+        }
+    }
+
+    /// Command that re-runs `exe` with `args` as root, `None` if no escalation is needed.
+    pub fn reexec(self, exe: &str, args: &[String]) -> Option<CommandLine> {
+        let program = self.program()?;
+        Some(CommandLine::new(
+            [program, exe]
+                .into_iter()
+                .map(str::to_string)
+                .chain(args.iter().cloned()),
+        ))
+    }
+}
+
+/// Picks the escalation backend for the system mounted at `root`.
+///
+/// Running as root needs none; otherwise `configured` wins over auto-detection.
+pub async fn resolve(root: &Path, configured: Option<Escalation>) -> Result<Escalation> {
+    let status = fs::read_to_string(root.join("proc/self/status"))
+        .await
+        .unwrap_or_default();
+    if effective_uid(&status) == Some(0) {
+        return Ok(Escalation::None);
+    }
+    if let Some(escalation) = configured {
+        return Ok(escalation);
+    }
+
+    detect(root).ok_or_else(|| {
+        anyhow::anyhow!("Setup needs root, but none of sudo, doas, run0 or pkexec is installed")
+    })
+}
+
+/// First escalation tool installed on the system at `root`.
+pub fn detect(root: &Path) -> Option<Escalation> {
+    AUTO_DETECT.into_iter().find(|e| {
+        e.program()
+            .is_some_and(|program| root.join("usr/bin").join(program).exists())
+    })
+}
+
+/// Effective UID from the `Uid:` line of `/proc/<pid>/status` (real, effective, saved, fs).
+fn effective_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|ids| ids.split_whitespace().nth(1))
+        .and_then(|uid| uid.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    const USER_STATUS: &str =
+        "Name:\tardenthat\nUid:\t1000\t1000\t1000\t1000\nGid:\t1000\t1000\t1000\t1000\n";
+
+    #[test]
+    fn parses_effective_uid() {
+        assert_eq!(effective_uid(USER_STATUS), Some(1000));
+        assert_eq!(effective_uid("Uid:\t1000\t0\t0\t0\n"), Some(0));
+        assert_eq!(effective_uid(""), None);
+    }
+
+    #[tokio::test]
+    async fn root_needs_no_escalation() {
+        let root = TempTree::new("privilege-root");
+        root.write("proc/self/status", "Uid:\t0\t0\t0\t0\n");
+        root.write("usr/bin/sudo", "");
+        assert_eq!(
+            resolve(root.path(), Some(Escalation::Doas)).await.unwrap(),
+            Escalation::None
+        );
+    }
+
+    #[tokio::test]
+    async fn prefers_configured_then_installed_tools() {
+        let root = TempTree::new("privilege-user");
+        root.write("proc/self/status", USER_STATUS);
+        assert!(resolve(root.path(), None).await.is_err());
+
+        root.write("usr/bin/pkexec", "");
+        root.write("usr/bin/doas", "");
+        assert_eq!(resolve(root.path(), None).await.unwrap(), Escalation::Doas);
+        assert_eq!(
+            resolve(root.path(), Some(Escalation::Run0)).await.unwrap(),
+            Escalation::Run0
+        );
+    }
+
+    #[test]
+    fn reexec_wraps_the_current_command() {
+        let args = vec!["setup".to_string(), "--persist-modules".to_string()];
+        assert_eq!(
+            Escalation::Doas
+                .reexec("/usr/bin/ardenthat", &args)
+                .unwrap()
+                .to_string(),
+            "doas /usr/bin/ardenthat setup --persist-modules"
+        );
+        assert_eq!(Escalation::None.reexec("/usr/bin/ardenthat", &args), None);
+    }
+}
@@ -0,0 +1,231 @@
+//! Pre-setup filesystem snapshots with snapper, Timeshift or plain btrfs
+
+use crate::command::CommandLine;
+use crate::Host;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Where plain btrfs snapshots of `/` are kept.
+const BTRFS_SNAPSHOT_DIR: &str = "/.ardenthat-snapshots";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Snapper,
+    Timeshift,
+    Btrfs,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub backend: Backend,
+    /// Snapper number, Timeshift snapshot name or btrfs subvolume path
+    pub id: String,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Snapper => "snapper",
+            Backend::Timeshift => "Timeshift",
+            Backend::Btrfs => "btrfs",
+        }
+    }
+}
+
+/// Picks a snapshot backend: a snapper `root` config, then a configured
+/// Timeshift, then a btrfs root filesystem.
+pub fn detect(host: &Host<'_>) -> Option<Backend> {
+    if host.path("/usr/bin/snapper").exists() && host.path("/etc/snapper/configs/root").exists() {
+        return Some(Backend::Snapper);
+    }
+    if host.path("/usr/bin/timeshift").exists()
+        && host.path("/etc/timeshift/timeshift.json").exists()
+    {
+        return Some(Backend::Timeshift);
+    }
+
+    let root_fs = host.run(CommandLine::new(["findmnt", "-no", "FSTYPE", "/"]).capture());
+    match root_fs {
+        Ok(output) if output.success() && output.stdout.trim() == "btrfs" => Some(Backend::Btrfs),
+        _ => None,
+    }
+}
+
+/// Takes a snapshot of `/` labelled `label`.
+pub fn create(host: &Host<'_>, backend: Backend, label: &str) -> Result<Snapshot> {
+    let id = match backend {
+        Backend::Snapper => {
+            let output = run(
+                host,
+                backend,
+                CommandLine::new([
+                    "snapper",
+                    "-c",
+                    "root",
+                    "create",
+                    "--type",
+                    "single",
+                    "--cleanup-algorithm",
+                    "number",
+                    "--print-number",
+                    "--description",
+                    label,
+                ])
+                .capture(),
+            )?;
+            output.trim().to_string()
+        }
+        Backend::Timeshift => {
+            let output = run(
+                host,
+                backend,
+                CommandLine::new(["timeshift", "--create", "--scripted", "--comments", label])
+                    .capture(),
+            )?;
+            parse_timeshift_name(&output).context("Timeshift did not report the snapshot name")?
+        }
+        Backend::Btrfs => {
+            let path = format!("{}/{}", BTRFS_SNAPSHOT_DIR, label);
+            run(
+                host,
+                backend,
+                CommandLine::new(["mkdir", "-p", BTRFS_SNAPSHOT_DIR]),
+            )?;
+            run(
+                host,
+                backend,
+                CommandLine::new(["btrfs", "subvolume", "snapshot", "-r", "/", path.as_str()])
+                    .capture(),
+            )?;
+            path
+        }
+    };
+
+    if id.is_empty() {
+        anyhow::bail!("{} did not report a snapshot ID", backend.name());
+    }
+    Ok(Snapshot { backend, id })
+}
+
+fn run(host: &Host<'_>, backend: Backend, command: CommandLine) -> Result<String> {
+    let output = host
+        .run(command)
+        .with_context(|| format!("Failed to run {}", backend.name()))?;
+    if !output.success() {
+        anyhow::bail!(
+            "{} failed to create a snapshot: {}",
+            backend.name(),
+            output.stderr.trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Name from Timeshift's `Tagged snapshot '2026-10-14_15-30-12': ondemand` line.
+fn parse_timeshift_name(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let rest = line.trim().strip_prefix("Tagged snapshot '")?;
+        rest.split('\'').next().map(str::to_string)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::MockRunner;
+    use crate::testutil::TempTree;
+
+    const LABEL: &str = "ardenthat-pre-setup-1760451012";
+
+    #[test]
+    fn prefers_snapper_then_timeshift_then_btrfs() {
+        let root = TempTree::new("snapshot-detect");
+        let runner = MockRunner::new().reply(&["findmnt", "-no", "FSTYPE", "/"], 0, "btrfs\n");
+        let host = Host {
+            runner: &runner,
+            root: root.path().to_path_buf(),
+        };
+        assert_eq!(detect(&host), Some(Backend::Btrfs));
+
+        root.write("usr/bin/timeshift", "");
+        root.write("etc/timeshift/timeshift.json", "{}");
+        assert_eq!(detect(&host), Some(Backend::Timeshift));
+
+        root.write("usr/bin/snapper", "");
+        assert_eq!(detect(&host), Some(Backend::Timeshift));
+        root.write("etc/snapper/configs/root", "SUBVOLUME=\"/\"\n");
+        assert_eq!(detect(&host), Some(Backend::Snapper));
+    }
+
+    #[test]
+    fn ext4_root_has_no_backend() {
+        let runner = MockRunner::new().reply(&["findmnt", "-no", "FSTYPE", "/"], 0, "ext4\n");
+        let host = Host {
+            runner: &runner,
+            root: "/nonexistent".into(),
+        };
+        assert_eq!(detect(&host), None);
+    }
+
+    #[test]
+    fn creates_snapshots_with_each_backend() {
+        let runner = MockRunner::new()
+            .reply(
+                &[
+                    "snapper",
+                    "-c",
+                    "root",
+                    "create",
+                    "--type",
+                    "single",
+                    "--cleanup-algorithm",
+                    "number",
+                    "--print-number",
+                    "--description",
+                    LABEL,
+                ],
+                0,
+                "42\n",
+            )
+            .reply(
+                &["timeshift", "--create", "--scripted", "--comments", LABEL],
+                0,
+                "Creating new snapshot...(RSYNC)\n\
+                 RSYNC Snapshot saved successfully (6s)\n\
+                 Tagged snapshot '2026-10-14_15-30-12': ondemand\n",
+            );
+        let host = Host {
+            runner: &runner,
+            root: "/nonexistent".into(),
+        };
+
+        let snapper = create(&host, Backend::Snapper, LABEL).unwrap();
+        assert_eq!(snapper.id, "42");
+        let timeshift = create(&host, Backend::Timeshift, LABEL).unwrap();
+        assert_eq!(timeshift.id, "2026-10-14_15-30-12");
+        let btrfs = create(&host, Backend::Btrfs, LABEL).unwrap();
+        assert_eq!(
+            btrfs.id,
+            "/.ardenthat-snapshots/ardenthat-pre-setup-1760451012"
+        );
+        assert!(runner.transcript().ends_with(
+            "$ mkdir -p /.ardenthat-snapshots\n\
+             $ btrfs subvolume snapshot -r / /.ardenthat-snapshots/ardenthat-pre-setup-1760451012\n"
+        ));
+    }
+
+    #[test]
+    fn failed_snapshot_is_an_error() {
+        let runner = MockRunner::new().reply(
+            &["timeshift", "--create", "--scripted", "--comments", LABEL],
+            1,
+            "",
+        );
+        let host = Host {
+            runner: &runner,
+            root: "/nonexistent".into(),
+        };
+        assert!(create(&host, Backend::Timeshift, LABEL).is_err());
+    }
+}
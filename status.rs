@@ -0,0 +1,257 @@
+//! Per-device driver status from sysfs bindings, kernel modules and pacman state
+
+use crate::knowledge::Recommendation;
+use crate::packages::PackageDatabase;
+use crate::{DriverStatus, HardwareComponent};
+
+/// Generic kernel drivers that keep a display or device alive without real support.
+const FALLBACK_DRIVERS: &[&str] = &["nouveau", "simpledrm", "simplefb", "efifb", "vesafb"];
+
+/// Sets `status` and `status_reason` on every component.
+///
+/// `FirmwareMissing` is left to [`crate::firmware::mark_components`], which knows
+/// the blobs a bound driver actually lacks.
+///
+/// Without a package database (no pacman) package checks are skipped and the
+/// status is derived from the kernel state alone.
+pub fn compute_statuses(
+    components: &mut [HardwareComponent],
+    recommendations: &[Recommendation],
+    packages: Option<&PackageDatabase>,
+) {
+    for (index, component) in components.iter_mut().enumerate() {
+        let matched: Vec<&Recommendation> = recommendations
+            .iter()
+            .filter(|r| r.components.contains(&index))
+            .collect();
+        let (status, reason) = compute_status(component, &matched, packages);
+        component.status = status;
+        component.status_reason = reason;
+    }
+}
+
+fn compute_status(
+    component: &HardwareComponent,
+    recommendations: &[&Recommendation],
+    packages: Option<&PackageDatabase>,
+) -> (DriverStatus, String) {
+    let wanted: Vec<&str> = recommendations
+        .iter()
+        .flat_map(|r| r.packages.iter().map(String::as_str))
+        .collect();
+    let missing: Vec<&str> = match packages {
+        Some(db) => wanted
+            .iter()
+            .copied()
+            .filter(|p| !db.is_installed(p))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if component.cpu.is_some() {
+        return if !missing.is_empty() {
+            (
+                DriverStatus::NotInstalled,
+                format!("{} not installed", missing.join(", ")),
+            )
+        } else if !wanted.is_empty() && packages.is_some() {
+            (
+                DriverStatus::Installed,
+                format!("{} installed", wanted.join(", ")),
+            )
+        } else {
+            (
+                DriverStatus::Unknown,
+                "No microcode package known for this CPU".to_string(),
+            )
+        };
+    }
+
+    if let Some(driver) = component.driver.as_deref() {
+        let better = recommendations
+            .first()
+            .map(|r| r.rule.as_str())
+            .or_else(|| {
+                component
+                    .kernel_modules
+                    .iter()
+                    .map(String::as_str)
+                    .find(|m| !FALLBACK_DRIVERS.contains(m))
+            });
+        if FALLBACK_DRIVERS.contains(&driver) {
+            if let Some(better) = better {
+                return (
+                    DriverStatus::FallbackDriver,
+                    format!(
+                        "Bound to fallback driver {}, {} is recommended",
+                        driver, better
+                    ),
+                );
+            }
+        }
+        if !missing.is_empty() {
+            return (
+                DriverStatus::NotInstalled,
+                format!(
+                    "{} is bound but {} is not installed",
+                    driver,
+                    missing.join(", ")
+                ),
+            );
+        }
+        return (
+            DriverStatus::Installed,
+            format!("Driver {} is bound", driver),
+        );
+    }
+
+    if let (Some(db), false) = (packages, missing.is_empty()) {
+        let (in_repos, elsewhere): (Vec<&str>, Vec<&str>) =
+            missing.iter().partition(|p| db.is_available(p));
+        let mut reason = format!("{} not installed", missing.join(", "));
+        if !elsewhere.is_empty() {
+            reason.push_str(&format!(
+                " ({} not in the sync databases)",
+                elsewhere.join(", ")
+            ));
+        } else if !in_repos.is_empty() {
+            reason.push_str(" (available in the sync databases)");
+        }
+        return (DriverStatus::NotInstalled, reason);
+    }
+    if let Some(module) = component.kernel_modules.first() {
+        return (
+            DriverStatus::Available,
+            format!("Kernel module {} matches but is not bound", module),
+        );
+    }
+    if !wanted.is_empty() {
+        return (
+            DriverStatus::Available,
+            format!("{} installed but no driver is bound", wanted.join(", ")),
+        );
+    }
+
+    (
+        DriverStatus::Unknown,
+        "No kernel module or knowledge-base rule matches this device".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(driver: Option<&str>, modules: &[&str]) -> HardwareComponent {
+        HardwareComponent {
+            device_type: "PCI".to_string(),
+            driver: driver.map(str::to_string),
+            kernel_modules: modules.iter().map(|m| m.to_string()).collect(),
+            pci: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    fn recommend(rule: &str, packages: &[&str]) -> Recommendation {
+        Recommendation {
+            rule: rule.to_string(),
+            device: String::new(),
+            components: vec![0],
+            packages: packages.iter().map(|p| p.to_string()).collect(),
+            early_modules: Vec::new(),
+            reason: String::new(),
+            select: None,
+        }
+    }
+
+    fn db(installed: &[&str], available: &[&str]) -> PackageDatabase {
+        PackageDatabase::parse(
+            &installed
+                .iter()
+                .map(|p| format!("{} 1.0-1\n", p))
+                .collect::<String>(),
+            &available.join("\n"),
+        )
+    }
+
+    fn status_of(
+        component: HardwareComponent,
+        recommendations: &[Recommendation],
+        packages: Option<&PackageDatabase>,
+    ) -> (DriverStatus, String) {
+        let mut components = vec![component];
+        compute_statuses(&mut components, recommendations, packages);
+        let component = components.remove(0);
+        (component.status, component.status_reason)
+    }
+
+    #[test]
+    fn bound_driver_is_installed() {
+        let (status, reason) = status_of(gpu(Some("i915"), &["i915"]), &[], Some(&db(&[], &[])));
+        assert!(matches!(status, DriverStatus::Installed));
+        assert_eq!(reason, "Driver i915 is bound");
+    }
+
+    #[test]
+    fn nouveau_is_a_fallback_when_nvidia_is_recommended() {
+        let recommendations = [recommend("nvidia-open", &["nvidia-open"])];
+        let (status, reason) = status_of(
+            gpu(Some("nouveau"), &["nouveau"]),
+            &recommendations,
+            Some(&db(&[], &["nvidia-open"])),
+        );
+        assert!(matches!(status, DriverStatus::FallbackDriver));
+        assert!(reason.contains("nvidia-open is recommended"));
+    }
+
+    #[test]
+    fn missing_packages_are_not_installed() {
+        let recommendations = [recommend("nvidia-470xx", &["nvidia-470xx-dkms"])];
+        let (status, reason) = status_of(gpu(None, &[]), &recommendations, Some(&db(&[], &[])));
+        assert!(matches!(status, DriverStatus::NotInstalled));
+        assert_eq!(
+            reason,
+            "nvidia-470xx-dkms not installed (nvidia-470xx-dkms not in the sync databases)"
+        );
+    }
+
+    #[test]
+    fn firmware_packages_are_not_firmware_status() {
+        let recommendations = [recommend(
+            "sof-firmware",
+            &["sof-firmware", "alsa-ucm-conf"],
+        )];
+        let mut components = vec![gpu(
+            Some("snd_sof_pci_intel_tgl"),
+            &["snd_sof_pci_intel_tgl"],
+        )];
+        let packages = db(&["alsa-ucm-conf"], &["sof-firmware"]);
+        compute_statuses(&mut components, &recommendations, Some(&packages));
+        assert!(matches!(components[0].status, DriverStatus::NotInstalled));
+        assert_eq!(
+            components[0].status_reason,
+            "snd_sof_pci_intel_tgl is bound but sof-firmware is not installed"
+        );
+
+        let missing = crate::firmware::MissingFirmware {
+            blob: "intel/sof/sof-tgl.ri".to_string(),
+            module: Some("snd_sof_pci_intel_tgl".to_string()),
+            package: None,
+        };
+        crate::firmware::mark_components(&mut components, &[missing]);
+        assert!(matches!(
+            components[0].status,
+            DriverStatus::FirmwareMissing
+        ));
+    }
+
+    #[test]
+    fn unbound_module_is_available() {
+        let (status, reason) = status_of(gpu(None, &["iwlwifi"]), &[], None);
+        assert!(matches!(status, DriverStatus::Available));
+        assert_eq!(reason, "Kernel module iwlwifi matches but is not bound");
+
+        let (status, _) = status_of(gpu(None, &[]), &[], None);
+        assert!(matches!(status, DriverStatus::Unknown));
+    }
+}
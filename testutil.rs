@@ -0,0 +1,42 @@
+//! Scratch directory trees for fixture-based tests
+
+use std::path::{Path, PathBuf};
+
+pub struct TempTree {
+    root: PathBuf,
+}
+
+impl TempTree {
+    pub fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("ardenthat-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        Self { root }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Writes `contents` to `rel`, creating parent directories as needed.
+    pub fn write(&self, rel: &str, contents: &str) {
+        let path = self.root.join(rel);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Creates a directory at `target` and points the symlink `rel` at it.
+    pub fn symlink(&self, rel: &str, target: &str) {
+        let link = self.root.join(rel);
+        let target = self.root.join(target);
+        std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        std::os::unix::fs::symlink(target, link).unwrap();
+    }
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,391 @@
+//! USB device detection from sysfs with an `lsusb` fallback
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+const USB_CLASS_HUB: u8 = 0x09;
+const LINUX_FOUNDATION_VENDOR: u16 = 0x1d6b;
+/// Linux Foundation root hubs: USB 1.1, 2.0 and 3.x. Its other product IDs are
+/// USB gadgets such as the multifunction composite gadget (0104).
+const ROOT_HUB_PRODUCTS: [u16; 3] = [0x0001, 0x0002, 0x0003];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsbDevice {
+    pub bus: u8,
+    pub devnum: u16,
+    /// Bus/port path as used by sysfs, e.g. `1-4.2`; root hubs use `<bus>-0`
+    pub port_path: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// Negotiated speed in Mbit/s
+    pub speed: Option<String>,
+    pub device_class: u8,
+    pub root_hub: bool,
+    pub interfaces: Vec<UsbInterface>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsbInterface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub driver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modalias: Option<String>,
+}
+
+impl UsbDevice {
+    pub fn is_hub(&self) -> bool {
+        self.root_hub
+            || self.device_class == USB_CLASS_HUB
+            || self.interfaces.iter().any(|i| i.class == USB_CLASS_HUB)
+    }
+
+    /// First driver bound to any of the device's interfaces.
+    pub fn driver(&self) -> Option<&str> {
+        self.interfaces.iter().find_map(|i| i.driver.as_deref())
+    }
+}
+
+/// Enumerates USB devices and their interfaces from `<root>/bus/usb/devices`.
+pub struct SysfsUsbDetector {
+    root: PathBuf,
+}
+
+impl Default for SysfsUsbDetector {
+    fn default() -> Self {
+        Self::new("/sys")
+    }
+}
+
+impl SysfsUsbDetector {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub async fn scan(&self) -> Result<Vec<UsbDevice>> {
+        let dir = self.root.join("bus/usb/devices");
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read {}", dir.display()))?;
+
+        // Device entries look like `usb1` or `1-4.2`, interfaces like `1-4.2:1.0`
+        let mut device_names = Vec::new();
+        let mut interface_names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.contains(':') {
+                interface_names.push(name);
+            } else {
+                device_names.push(name);
+            }
+        }
+        device_names.sort();
+        interface_names.sort();
+
+        let mut devices = Vec::new();
+        for name in device_names {
+            let mut device = read_sysfs_device(&dir.join(&name), &name)
+                .await
+                .with_context(|| format!("Failed to read USB device {}", name))?;
+
+            let prefix = format!("{}:", device.port_path.as_deref().unwrap_or(&name));
+            for interface in interface_names.iter().filter(|i| i.starts_with(&prefix)) {
+                device.interfaces.push(
+                    read_sysfs_interface(&dir.join(interface))
+                        .await
+                        .with_context(|| format!("Failed to read USB interface {}", interface))?,
+                );
+            }
+            devices.push(device);
+        }
+
+        Ok(devices)
+    }
+}
+
+async fn read_sysfs_device(path: &Path, name: &str) -> Result<UsbDevice> {
+    let bus: u8 = read_attr(path, "busnum")
+        .await
+        .context("Missing busnum")?
+        .parse()
+        .context("Invalid busnum")?;
+    let root_hub = name.starts_with("usb");
+    let port_path = if root_hub {
+        format!("{}-0", bus)
+    } else {
+        name.to_string()
+    };
+
+    Ok(UsbDevice {
+        bus,
+        devnum: read_attr(path, "devnum")
+            .await
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default(),
+        port_path: Some(port_path),
+        vendor_id: read_hex(path, "idVendor").await? as u16,
+        product_id: read_hex(path, "idProduct").await? as u16,
+        manufacturer: read_attr(path, "manufacturer").await,
+        product: read_attr(path, "product").await,
+        speed: read_attr(path, "speed").await,
+        device_class: read_hex(path, "bDeviceClass").await.unwrap_or_default() as u8,
+        root_hub,
+        interfaces: Vec::new(),
+    })
+}
+
+async fn read_sysfs_interface(path: &Path) -> Result<UsbInterface> {
+    let driver = fs::read_link(path.join("driver"))
+        .await
+        .ok()
+        .and_then(|target| target.file_name().map(|n| n.to_string_lossy().into_owned()));
+
+    Ok(UsbInterface {
+        number: read_hex(path, "bInterfaceNumber").await? as u8,
+        class: read_hex(path, "bInterfaceClass").await? as u8,
+        subclass: read_hex(path, "bInterfaceSubClass").await? as u8,
+        protocol: read_hex(path, "bInterfaceProtocol").await? as u8,
+        driver,
+        modalias: read_attr(path, "modalias").await,
+    })
+}
+
+async fn read_attr(path: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(path.join(attr))
+        .await
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+async fn read_hex(path: &Path, attr: &str) -> Result<u32> {
+    let value = read_attr(path, attr)
+        .await
+        .with_context(|| format!("Missing sysfs attribute {}", attr))?;
+    u32::from_str_radix(&value, 16)
+        .with_context(|| format!("Invalid sysfs {} value: {}", attr, value))
+}
+
+/// Parses the one-line-per-device output of plain `lsusb`.
+pub fn parse_lsusb_output(output: &str) -> Result<Vec<UsbDevice>> {
+    let mut devices = Vec::new();
+
+    for line in output.lines().filter(|l| !l.trim().is_empty()) {
+        // Bus 001 Device 002: ID 8087:0a2b Intel Corp. Bluetooth wireless interface
+        let parse = || -> Option<UsbDevice> {
+            let rest = line.strip_prefix("Bus ")?;
+            let (bus, rest) = rest.split_once(" Device ")?;
+            let (devnum, rest) = rest.split_once(": ID ")?;
+            let (ids, description) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (vendor, product) = ids.split_once(':')?;
+            let vendor_id = u16::from_str_radix(vendor, 16).ok()?;
+            let product_id = u16::from_str_radix(product, 16).ok()?;
+            let description = description.trim();
+
+            Some(UsbDevice {
+                bus: bus.parse().ok()?,
+                devnum: devnum.parse().ok()?,
+                vendor_id,
+                product_id,
+                product: (!description.is_empty()).then(|| description.to_string()),
+                device_class: if description.to_lowercase().ends_with(" hub") {
+                    USB_CLASS_HUB
+                } else {
+                    0
+                },
+                root_hub: vendor_id == LINUX_FOUNDATION_VENDOR
+                    && ROOT_HUB_PRODUCTS.contains(&product_id),
+                ..Default::default()
+            })
+        };
+        devices.push(parse().with_context(|| format!("Invalid lsusb line: {}", line))?);
+    }
+
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempTree;
+
+    fn add_device(sysfs: &TempTree, name: &str, attrs: &[(&str, &str)]) {
+        for (attr, value) in attrs {
+            sysfs.write(
+                &format!("bus/usb/devices/{}/{}", name, attr),
+                &format!("{}\n", value),
+            );
+        }
+    }
+
+    fn add_interface(sysfs: &TempTree, name: &str, class: &str, driver: Option<&str>) {
+        let number = name.rsplit('.').next().unwrap();
+        add_device(
+            sysfs,
+            name,
+            &[
+                ("bInterfaceNumber", &format!("{:0>2}", number)),
+                ("bInterfaceClass", class),
+                ("bInterfaceSubClass", "01"),
+                ("bInterfaceProtocol", "01"),
+            ],
+        );
+        if let Some(driver) = driver {
+            sysfs.symlink(
+                &format!("bus/usb/devices/{}/driver", name),
+                &format!("bus/usb/drivers/{}", driver),
+            );
+        }
+    }
+
+    fn fixture_tree(name: &str) -> TempTree {
+        let sysfs = TempTree::new(name);
+        add_device(
+            &sysfs,
+            "usb1",
+            &[
+                ("busnum", "1"),
+                ("devnum", "1"),
+                ("idVendor", "1d6b"),
+                ("idProduct", "0002"),
+                ("manufacturer", "Linux 6.10.10-arch1-1 xhci-hcd"),
+                ("product", "xHCI Host Controller"),
+                ("speed", "480"),
+                ("bDeviceClass", "09"),
+            ],
+        );
+        add_interface(&sysfs, "1-0:1.0", "09", Some("hub"));
+        add_device(
+            &sysfs,
+            "1-7",
+            &[
+                ("busnum", "1"),
+                ("devnum", "2"),
+                ("idVendor", "8087"),
+                ("idProduct", "0a2b"),
+                ("speed", "12"),
+                ("bDeviceClass", "e0"),
+            ],
+        );
+        add_interface(&sysfs, "1-7:1.0", "e0", Some("btusb"));
+        add_interface(&sysfs, "1-7:1.1", "e0", Some("btusb"));
+        add_device(
+            &sysfs,
+            "1-8",
+            &[
+                ("busnum", "1"),
+                ("devnum", "3"),
+                ("idVendor", "5986"),
+                ("idProduct", "2113"),
+                ("manufacturer", "SunplusIT Inc"),
+                ("product", "Integrated Camera"),
+                ("speed", "480"),
+                ("bDeviceClass", "ef"),
+            ],
+        );
+        add_interface(&sysfs, "1-8:1.0", "0e", Some("uvcvideo"));
+        add_interface(&sysfs, "1-8:1.1", "0e", Some("uvcvideo"));
+        add_device(
+            &sysfs,
+            "1-9",
+            &[
+                ("busnum", "1"),
+                ("devnum", "4"),
+                ("idVendor", "06cb"),
+                ("idProduct", "009a"),
+                ("speed", "12"),
+                ("bDeviceClass", "ff"),
+            ],
+        );
+        add_interface(&sysfs, "1-9:1.0", "ff", None);
+        sysfs
+    }
+
+    #[tokio::test]
+    async fn scans_fixture_sysfs_tree() {
+        let sysfs = fixture_tree("sysfs-usb-scan");
+        let devices = SysfsUsbDetector::new(sysfs.path()).scan().await.unwrap();
+        assert_eq!(devices.len(), 4);
+
+        let camera = devices.iter().find(|d| d.vendor_id == 0x5986).unwrap();
+        assert_eq!(camera.product_id, 0x2113);
+        assert_eq!(camera.port_path.as_deref(), Some("1-8"));
+        assert_eq!((camera.bus, camera.devnum), (1, 3));
+        assert_eq!(camera.manufacturer.as_deref(), Some("SunplusIT Inc"));
+        assert_eq!(camera.product.as_deref(), Some("Integrated Camera"));
+        assert_eq!(camera.speed.as_deref(), Some("480"));
+        assert_eq!(camera.interfaces.len(), 2);
+        assert_eq!(camera.interfaces[1].number, 1);
+        assert_eq!(camera.interfaces[0].class, 0x0e);
+        assert_eq!(camera.driver(), Some("uvcvideo"));
+        assert!(!camera.is_hub());
+
+        let bluetooth = devices.iter().find(|d| d.vendor_id == 0x8087).unwrap();
+        assert!(bluetooth.manufacturer.is_none());
+        assert_eq!(bluetooth.driver(), Some("btusb"));
+
+        let fingerprint = devices.iter().find(|d| d.vendor_id == 0x06cb).unwrap();
+        assert_eq!(fingerprint.interfaces.len(), 1);
+        assert_eq!(fingerprint.driver(), None);
+    }
+
+    #[tokio::test]
+    async fn root_hubs_are_filterable() {
+        let sysfs = fixture_tree("sysfs-usb-hubs");
+        let devices = SysfsUsbDetector::new(sysfs.path()).scan().await.unwrap();
+
+        let root = devices.iter().find(|d| d.root_hub).unwrap();
+        assert_eq!(root.port_path.as_deref(), Some("1-0"));
+        assert_eq!(root.interfaces.len(), 1);
+        assert_eq!(root.driver(), Some("hub"));
+        assert!(root.is_hub());
+        assert_eq!(devices.iter().filter(|d| !d.is_hub()).count(), 3);
+    }
+
+    #[test]
+    fn parses_lsusb_output() {
+        let devices = parse_lsusb_output(include_str!("fixtures/lsusb/thinkpad-t480.txt")).unwrap();
+        assert_eq!(devices.len(), 6);
+
+        let fingerprint = &devices[1];
+        assert_eq!((fingerprint.bus, fingerprint.devnum), (1, 4));
+        assert_eq!(
+            (fingerprint.vendor_id, fingerprint.product_id),
+            (0x06cb, 0x009a)
+        );
+        assert_eq!(
+            fingerprint.product.as_deref(),
+            Some("Synaptics, Inc. Metallica MIS Touch Fingerprint Reader")
+        );
+        assert!(!fingerprint.is_hub());
+
+        assert!(devices[0].root_hub);
+        assert!(devices[4].is_hub());
+        assert!(!devices[4].root_hub);
+        assert_eq!(devices.iter().filter(|d| !d.is_hub()).count(), 3);
+    }
+
+    #[test]
+    fn linux_foundation_gadgets_are_not_root_hubs() {
+        let devices = parse_lsusb_output(
+            "Bus 003 Device 001: ID 1d6b:0003 Linux Foundation 3.0 root hub\n\
+             Bus 001 Device 005: ID 1d6b:0104 Linux Foundation Multifunction Composite Gadget\n\
+             Bus 001 Device 006: ID 1d6b:0106 Linux Foundation Composite Gadget\n",
+        )
+        .unwrap();
+        let root_hubs: Vec<bool> = devices.iter().map(|d| d.root_hub).collect();
+        assert_eq!(root_hubs, vec![true, false, false]);
+        assert!(!devices[1].is_hub());
+    }
+
+    #[test]
+    fn rejects_garbage_lsusb_lines() {
+        assert!(parse_lsusb_output("not lsusb output").is_err());
+    }
+}